@@ -0,0 +1,315 @@
+use anyhow::{anyhow, bail, Context, Result};
+use flate2::{
+    read::{DeflateDecoder, GzDecoder},
+    write::{DeflateEncoder, GzEncoder},
+    Compression,
+};
+use std::io::{Read, Write};
+
+// Every entry gets the same DOS timestamp (1980-01-01 00:00), so the archive
+// only depends on the names and contents that go in.
+const DOS_TIME: u16 = 0;
+const DOS_DATE: u16 = (1 << 5) | 1;
+// Names are UTF-8.
+const FLAGS: u16 = 1 << 11;
+const DEFLATE: u16 = 8;
+const VERSION: u16 = 20;
+
+// Builds a zip archive of `(name, contents)` entries, deflated, in the order
+// given.
+pub fn zip(entries: &[(String, Vec<u8>)]) -> Result<Vec<u8>> {
+    let mut archive = Vec::new();
+    let mut directory = Vec::new();
+    for (name, contents) in entries {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(contents)?;
+        let compressed = encoder.finish()?;
+        let crc = crc32fast::hash(contents);
+        let offset = archive.len() as u32;
+
+        let mut header = Vec::new();
+        for field in [VERSION, FLAGS, DEFLATE, DOS_TIME, DOS_DATE] {
+            header.extend(field.to_le_bytes());
+        }
+        for field in [crc, compressed.len() as u32, contents.len() as u32] {
+            header.extend(field.to_le_bytes());
+        }
+        header.extend((name.len() as u16).to_le_bytes());
+        // No extra field.
+        header.extend(0u16.to_le_bytes());
+
+        archive.extend(0x04034b50u32.to_le_bytes());
+        archive.extend(&header);
+        archive.extend(name.as_bytes());
+        archive.extend(&compressed);
+
+        directory.extend(0x02014b50u32.to_le_bytes());
+        directory.extend(VERSION.to_le_bytes());
+        directory.extend(&header);
+        // No comment, disk 0, no internal or external attributes.
+        for field in [0u16, 0, 0] {
+            directory.extend(field.to_le_bytes());
+        }
+        directory.extend(0u32.to_le_bytes());
+        directory.extend(offset.to_le_bytes());
+        directory.extend(name.as_bytes());
+    }
+
+    let directory_offset = archive.len() as u32;
+    archive.extend(&directory);
+    archive.extend(0x06054b50u32.to_le_bytes());
+    // Single disk.
+    archive.extend([0u8; 4]);
+    for count in [entries.len() as u16; 2] {
+        archive.extend(count.to_le_bytes());
+    }
+    archive.extend((directory.len() as u32).to_le_bytes());
+    archive.extend(directory_offset.to_le_bytes());
+    // No comment.
+    archive.extend(0u16.to_le_bytes());
+    Ok(archive)
+}
+
+fn u16_at(data: &[u8], at: usize) -> Result<u16> {
+    data.get(at..at + 2)
+        .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+        .ok_or_else(|| anyhow!("The archive is truncated"))
+}
+
+fn u32_at(data: &[u8], at: usize) -> Result<u32> {
+    data.get(at..at + 4)
+        .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        .ok_or_else(|| anyhow!("The archive is truncated"))
+}
+
+// Entry names become paths inside the theme directory, so none may point
+// outside of it.
+pub fn check_name(name: &str) -> Result<()> {
+    let escapes = name.starts_with('/')
+        || name.contains('\\')
+        || name.contains(':')
+        || name.split('/').any(|part| part == "..");
+    if name.is_empty() || escapes {
+        bail!("The archive contains an unsafe path: {}", name);
+    }
+    Ok(())
+}
+
+// Reads the files of a zip archive, stored or deflated. Every entry has to
+// unpack to exactly the size and CRC its central directory entry records, so
+// a truncated or corrupted download fails here. Directory entries are
+// skipped.
+pub fn unzip(archive: &[u8]) -> Result<Vec<(String, Vec<u8>)>> {
+    // The end record sits at the very end, followed by a comment of at most
+    // 64 KiB.
+    let search_from = archive.len().saturating_sub(22 + u16::MAX as usize);
+    let end = (search_from..archive.len().saturating_sub(21))
+        .rev()
+        .find(|&at| u32_at(archive, at).ok() == Some(0x06054b50))
+        .ok_or_else(|| anyhow!("Not a zip archive, or truncated"))?;
+    let count = u16_at(archive, end + 10)? as usize;
+    let mut at = u32_at(archive, end + 16)? as usize;
+
+    let mut entries = Vec::with_capacity(count);
+    for _ in 0..count {
+        if u32_at(archive, at)? != 0x02014b50 {
+            bail!("The archive's central directory is damaged");
+        }
+        let method = u16_at(archive, at + 10)?;
+        let crc = u32_at(archive, at + 16)?;
+        let compressed_size = u32_at(archive, at + 20)? as usize;
+        let size = u32_at(archive, at + 24)? as usize;
+        let name_length = u16_at(archive, at + 28)? as usize;
+        let extra_length = u16_at(archive, at + 30)? as usize;
+        let comment_length = u16_at(archive, at + 32)? as usize;
+        let offset = u32_at(archive, at + 42)? as usize;
+        let name = archive
+            .get(at + 46..at + 46 + name_length)
+            .ok_or_else(|| anyhow!("The archive is truncated"))?;
+        let name = String::from_utf8_lossy(name).into_owned();
+        at += 46 + name_length + extra_length + comment_length;
+        if name.ends_with('/') {
+            continue;
+        }
+        check_name(&name)?;
+
+        if u32_at(archive, offset)? != 0x04034b50 {
+            bail!("{}: local header missing", name);
+        }
+        let start = offset
+            + 30
+            + u16_at(archive, offset + 26)? as usize
+            + u16_at(archive, offset + 28)? as usize;
+        let compressed = archive
+            .get(start..start + compressed_size)
+            .ok_or_else(|| anyhow!("{} is cut off, the archive is truncated", name))?;
+        let contents = match method {
+            0 => compressed.to_vec(),
+            DEFLATE => {
+                let mut contents = Vec::with_capacity(size);
+                DeflateDecoder::new(compressed)
+                    .take(size as u64 + 1)
+                    .read_to_end(&mut contents)
+                    .with_context(|| format!("{} does not inflate", name))?;
+                contents
+            }
+            method => bail!("{} uses unsupported compression method {}", name, method),
+        };
+        if contents.len() != size {
+            bail!(
+                "{} unpacks to {} bytes, the archive says {}",
+                name,
+                contents.len(),
+                size
+            );
+        }
+        if crc32fast::hash(&contents) != crc {
+            bail!("{} is corrupted, its CRC does not match", name);
+        }
+        entries.push((name, contents));
+    }
+    Ok(entries)
+}
+
+const BLOCK: usize = 512;
+
+// A NUL-terminated field of a tar header.
+fn tar_field(header: &[u8], at: usize, length: usize) -> String {
+    let bytes = &header[at..at + length];
+    let end = bytes.iter().position(|byte| *byte == 0).unwrap_or(length);
+    String::from_utf8_lossy(&bytes[..end]).trim().to_string()
+}
+
+fn tar_header(name: &str, size: usize) -> Result<[u8; BLOCK]> {
+    // Names past 100 bytes go in the ustar prefix, split at a `/`.
+    let (prefix, name) = match name.len() {
+        0..=100 => ("", name),
+        _ => name
+            .char_indices()
+            .filter(|(at, c)| *c == '/' && *at <= 155 && name.len() - at - 1 <= 100)
+            .map(|(at, _)| (&name[..at], &name[at + 1..]))
+            .next()
+            .ok_or_else(|| anyhow!("{} is too long for a tar archive", name))?,
+    };
+    let mut header = [0u8; BLOCK];
+    let mut put = |at: usize, value: &[u8]| header[at..at + value.len()].copy_from_slice(value);
+    put(0, name.as_bytes());
+    put(100, b"0000644\0");
+    put(108, b"0000000\0");
+    put(116, b"0000000\0");
+    put(124, format!("{:011o}\0", size).as_bytes());
+    // The same timestamp for every entry, as in zip archives.
+    put(136, b"00000000000\0");
+    put(156, b"0");
+    put(257, b"ustar\0");
+    put(263, b"00");
+    put(345, prefix.as_bytes());
+    // The checksum is taken with its own field as spaces.
+    header[148..156].fill(b' ');
+    let checksum: u32 = header.iter().map(|byte| u32::from(*byte)).sum();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+    Ok(header)
+}
+
+// Builds a gzipped tar archive of `(name, contents)` entries, in the order
+// given.
+pub fn tar(entries: &[(String, Vec<u8>)]) -> Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    for (name, contents) in entries {
+        encoder.write_all(&tar_header(name, contents.len())?)?;
+        encoder.write_all(contents)?;
+        encoder.write_all(&vec![
+            0;
+            contents.len().next_multiple_of(BLOCK) - contents.len()
+        ])?;
+    }
+    // Two empty blocks end the archive.
+    encoder.write_all(&[0; 2 * BLOCK])?;
+    Ok(encoder.finish()?)
+}
+
+// Reads the regular files of a gzipped tar archive; directories, links and
+// the like are skipped.
+pub fn untar(archive: &[u8]) -> Result<Vec<(String, Vec<u8>)>> {
+    let mut tar = Vec::new();
+    GzDecoder::new(archive)
+        .read_to_end(&mut tar)
+        .context("Not a gzipped tar archive")?;
+    let mut entries = Vec::new();
+    let mut at = 0;
+    while at + BLOCK <= tar.len() {
+        let header = &tar[at..at + BLOCK];
+        if header.iter().all(|byte| *byte == 0) {
+            break;
+        }
+        let mut name = tar_field(header, 0, 100);
+        let prefix = tar_field(header, 345, 155);
+        if tar_field(header, 257, 6) == "ustar" && !prefix.is_empty() {
+            name = format!("{}/{}", prefix, name);
+        }
+        let size = usize::from_str_radix(&tar_field(header, 124, 12), 8)
+            .with_context(|| format!("{}: invalid size", name))?;
+        let start = at + BLOCK;
+        let contents = tar
+            .get(start..start + size)
+            .ok_or_else(|| anyhow!("{} is cut off, the archive is truncated", name))?;
+        if matches!(header[156], b'0' | 0) && !name.ends_with('/') {
+            let name = name.trim_start_matches("./").to_string();
+            check_name(&name)?;
+            entries.push((name, contents.to_vec()));
+        }
+        at = start + size.next_multiple_of(BLOCK);
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries() -> Vec<(String, Vec<u8>)> {
+        vec![
+            ("config.json".to_string(), b"{\"id\": \"test\"}".to_vec()),
+            (
+                "press/GENERIC_R0.wav".to_string(),
+                (0..=255).cycle().take(3000).collect(),
+            ),
+            ("empty".to_string(), Vec::new()),
+            // Past 100 bytes, so tar splits it into the ustar prefix.
+            (
+                format!("themes/{}/press.wav", "x".repeat(110)),
+                b"long".to_vec(),
+            ),
+        ]
+    }
+
+    #[test]
+    fn zip_and_tar_round_trip() {
+        assert_eq!(unzip(&zip(&entries()).unwrap()).unwrap(), entries());
+        assert_eq!(untar(&tar(&entries()).unwrap()).unwrap(), entries());
+    }
+
+    #[test]
+    fn archives_only_depend_on_their_entries() {
+        assert_eq!(zip(&entries()).unwrap(), zip(&entries()).unwrap());
+        assert_eq!(tar(&entries()).unwrap(), tar(&entries()).unwrap());
+    }
+
+    #[test]
+    fn names_that_leave_the_directory_are_rejected() {
+        for name in [
+            "",
+            "../x",
+            "press/../../x",
+            "/etc/passwd",
+            "press\\x",
+            "C:x",
+        ] {
+            assert!(check_name(name).is_err(), "{:?}", name);
+        }
+        assert!(check_name("press/GENERIC_R0.wav").is_ok());
+        let evil = vec![("../evil".to_string(), b"x".to_vec())];
+        assert!(unzip(&zip(&evil).unwrap()).is_err());
+        assert!(untar(&tar(&evil).unwrap()).is_err());
+    }
+}
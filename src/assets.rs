@@ -0,0 +1,112 @@
+use crate::{musical, ASSETS};
+use anyhow::{bail, Result};
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+// Where `keydio install` puts themes, and the only place `keydio uninstall`
+// removes them from.
+pub fn user_dir() -> Option<PathBuf> {
+    Some(dirs::data_dir()?.join("keydio").join("themes"))
+}
+
+// Where themes are looked for, in order: the working directory (a source
+// checkout), the user's installed themes, next to the executable (an
+// unpacked release or a .desktop launch from anywhere), then the platform's
+// install locations.
+pub fn search_path() -> Vec<PathBuf> {
+    let working = env::current_dir().unwrap_or_default();
+    let mut dirs = vec![working.join(ASSETS)];
+    dirs.extend(user_dir());
+    if let Some(exe_dir) = env::current_exe()
+        .ok()
+        .and_then(|exe| exe.parent().map(Path::to_path_buf))
+    {
+        dirs.push(exe_dir.join(ASSETS));
+    }
+    #[cfg(target_os = "linux")]
+    dirs.extend(["/usr/local/share/keydio", "/usr/share/keydio"].map(PathBuf::from));
+    #[cfg(target_os = "macos")]
+    dirs.extend(["/opt/homebrew/share/keydio", "/usr/local/share/keydio"].map(PathBuf::from));
+    dirs.dedup();
+    dirs
+}
+
+fn installed(dir: &Path) -> Vec<String> {
+    fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter(|entry| entry.path().is_dir())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        // Themes being installed are staged in hidden directories.
+        .filter(|name| !name.starts_with('.'))
+        .collect()
+}
+
+// The directory of a theme: the first one on the search path, or the theme
+// itself when it is given as a path. A theme found nowhere resolves into the
+// first directory, so errors name a sensible place.
+pub fn theme_dir(theme: &str) -> PathBuf {
+    let dirs = search_path();
+    dirs.iter()
+        .map(|dir| dir.join(theme))
+        .find(|dir| dir.is_dir())
+        .unwrap_or_else(|| dirs[0].join(theme))
+}
+
+// Every theme on the search path; a theme in an earlier directory hides one
+// of the same name further down.
+pub fn themes() -> Vec<String> {
+    let mut themes: Vec<String> = search_path()
+        .iter()
+        .flat_map(|dir| installed(dir))
+        .collect();
+    themes.sort();
+    themes.dedup();
+    themes
+}
+
+// Where `keydio install` puts themes: the user's theme directory, or the
+// first directory on the search path that exists on a system without one.
+pub fn install_dir() -> PathBuf {
+    user_dir()
+        .or_else(|| search_path().into_iter().find(|dir| dir.is_dir()))
+        .unwrap_or_else(|| PathBuf::from(ASSETS))
+}
+
+fn searched(dirs: &[PathBuf]) -> String {
+    dirs.iter()
+        .map(|dir| format!("  {}", dir.display()))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// Refuses to start without any theme, or without the configured ones, rather
+// than running silently. With `verbose` the search path is printed first, so
+// packagers can check their layout.
+pub fn check(themes: &[String], verbose: bool) -> Result<()> {
+    let dirs = search_path();
+    if verbose {
+        eprintln!("Theme search path:");
+        for (index, dir) in dirs.iter().enumerate() {
+            let found = if dir.is_dir() {
+                format!("themes: {}", installed(dir).len())
+            } else {
+                "missing".to_string()
+            };
+            eprintln!("  {}. {} ({})", index + 1, dir.display(), found);
+        }
+    }
+    for theme in themes.iter().filter(|theme| *theme != musical::THEME) {
+        if theme_dir(theme).is_dir() {
+            continue;
+        }
+        if dirs.iter().all(|dir| installed(dir).is_empty()) {
+            bail!("No themes found; searched:\n{}", searched(&dirs));
+        }
+        bail!("Theme {} not found; searched:\n{}", theme, searched(&dirs));
+    }
+    Ok(())
+}
@@ -0,0 +1,752 @@
+use crate::{
+    config::{AudioBackend, Config, DEFAULT_OUTPUT},
+    hold::{Held, Presses},
+    metrics::{Metrics, OutputCounters, Voice},
+    mixer::{Bus, Mixer},
+    sample::{Format, PcmSource},
+    state::SharedState,
+    stats::{Chord, Streak},
+    themes::{Pick, ThemeSet},
+    velocity::{Tracker, Velocity},
+    AppState, KeyEvent,
+};
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use rodio::{
+    cpal::{
+        self,
+        traits::{DeviceTrait, HostTrait},
+        Device,
+    },
+    source::Source,
+    OutputStream,
+};
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+const PLAYBACK_TAIL: Duration = Duration::from_millis(500);
+// The audio thread beats at least this often while it waits for events.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum OutputKind {
+    #[default]
+    Default,
+    Null,
+}
+
+// Where prepared sources end up. The null output still pulls every sample
+// through the decoder, so the whole pipeline runs without a sound card.
+pub enum Output {
+    Device(Bus),
+    Mixer(Mixer),
+    Null,
+}
+
+// Keeps an output playing; the stream of either backend stops when dropped.
+// Only ever held, never read.
+#[allow(dead_code)]
+pub enum Stream {
+    Rodio(OutputStream),
+    Cpal(cpal::Stream),
+}
+
+// The system's default output device, or the one named `name`.
+fn find_device(name: Option<&str>) -> Result<Device> {
+    let host = rodio::cpal::default_host();
+    match name {
+        None => host
+            .default_output_device()
+            .ok_or_else(|| anyhow!("No default output device")),
+        Some(name) => host
+            .output_devices()?
+            .find(|device| device.name().is_ok_and(|device_name| device_name == name))
+            .ok_or_else(|| anyhow!("No output device named {}", name)),
+    }
+}
+
+impl Output {
+    // The returned stream has to stay alive (on the opening thread) for as
+    // long as the handle is used.
+    pub fn open(kind: OutputKind, format: Format) -> Result<(Option<Stream>, Self)> {
+        match kind {
+            OutputKind::Default => {
+                let (stream, handle) = OutputStream::try_default()?;
+                let bus = Bus::rodio(&handle, format)?;
+                Ok((Some(Stream::Rodio(stream)), Output::Device(bus)))
+            }
+            OutputKind::Null => Ok((None, Output::Null)),
+        }
+    }
+
+    // With the cpal backend, a device that refuses its stream is played
+    // through rodio instead.
+    fn open_device(
+        device: Option<&str>,
+        backend: Backend,
+        metrics: &Arc<Metrics>,
+    ) -> Result<(Stream, Self)> {
+        let found = find_device(device)?;
+        if backend.kind == AudioBackend::Cpal {
+            match Mixer::open(&found, backend.format, backend.frames, metrics) {
+                Ok((stream, mixer)) => return Ok((Stream::Cpal(stream), Output::Mixer(mixer))),
+                Err(err) => eprintln!(
+                    "Cannot open a cpal stream on {}, using rodio: {:?}",
+                    device.unwrap_or(DEFAULT_OUTPUT),
+                    err
+                ),
+            }
+        }
+        if device.is_none() {
+            if let (Some(stream), output) = Output::open(OutputKind::Default, backend.format)? {
+                return Ok((stream, output));
+            }
+        }
+        let (stream, handle) = OutputStream::try_from_device(&found)?;
+        let bus = Bus::rodio(&handle, backend.format)?;
+        Ok((Stream::Rodio(stream), Output::Device(bus)))
+    }
+
+    pub fn play<S>(&self, source: S) -> Result<()>
+    where
+        S: Source<Item = f32> + Send + 'static,
+    {
+        match self {
+            Output::Device(bus) => bus.play(source),
+            Output::Mixer(mixer) => mixer.play(source),
+            Output::Null => source.for_each(drop),
+        }
+        Ok(())
+    }
+}
+
+// One output to play to. `master` outputs follow the main volume; the
+// legacy virtual output has a volume of its own. A `standby` output only
+// plays while no other one is open.
+#[derive(Clone, Debug, PartialEq)]
+struct Target {
+    // `None` for the system's default output.
+    device: Option<String>,
+    volume: f32,
+    master: bool,
+    standby: bool,
+}
+
+impl Target {
+    fn label(&self) -> &str {
+        self.device.as_deref().unwrap_or(DEFAULT_OUTPUT)
+    }
+}
+
+// What the config asks to play to: the `outputs` list, or the default output
+// plus the virtual one when the list is empty.
+fn targets(config: &Config) -> Vec<Target> {
+    if !config.outputs.is_empty() {
+        return config
+            .outputs
+            .iter()
+            .map(|output| Target {
+                device: (output.device != DEFAULT_OUTPUT).then(|| output.device.clone()),
+                volume: output.volume,
+                master: true,
+                standby: false,
+            })
+            .collect();
+    }
+    let virtual_output = &config.virtual_output;
+    let mut targets = vec![Target {
+        device: None,
+        volume: 1.0,
+        master: true,
+        standby: virtual_output.device.is_some() && !virtual_output.monitor,
+    }];
+    if let Some(device) = &virtual_output.device {
+        targets.push(Target {
+            device: Some(device.clone()),
+            volume: virtual_output.volume,
+            master: false,
+            standby: false,
+        });
+    }
+    targets
+}
+
+// How outputs play: the backend, and for cpal the buffer and the format the
+// stream is opened in. Changing any of them reopens the outputs.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Backend {
+    kind: AudioBackend,
+    frames: Option<u32>,
+    format: Format,
+}
+
+impl Backend {
+    fn new(config: &Config) -> Self {
+        Self {
+            kind: config.audio_backend,
+            frames: config.buffer_frames,
+            format: config.engine.format(),
+        }
+    }
+}
+
+// How often open outputs are checked for having gone away and closed ones
+// are retried.
+const HOTPLUG_INTERVAL: Duration = Duration::from_secs(2);
+
+// One output and its stream, while it is open. Opening records the device
+// name, so a switch of the default output is noticed.
+struct Leg {
+    target: Target,
+    open: Option<(Option<Stream>, Output, String)>,
+    counters: Arc<OutputCounters>,
+}
+
+impl Leg {
+    fn open(&mut self, kind: OutputKind, backend: Backend, metrics: &Arc<Metrics>) -> Result<()> {
+        let opened = match (kind, &self.target.device) {
+            (OutputKind::Null, _) => (None, Output::Null, "null".to_string()),
+            (OutputKind::Default, device) => {
+                let (stream, output) = Output::open_device(device.as_deref(), backend, metrics)?;
+                let name = match device {
+                    Some(device) => device.clone(),
+                    None => default_device().unwrap_or_default(),
+                };
+                (Some(stream), output, name)
+            }
+        };
+        self.open = Some(opened);
+        self.counters.set_open(true);
+        Ok(())
+    }
+
+    fn close(&mut self) {
+        self.open = None;
+        self.counters.set_open(false);
+    }
+
+    fn name(&self) -> String {
+        let name = match (&self.open, &self.target.device) {
+            (Some((None, _, _)), _) => "null".to_string(),
+            (Some((_, _, device)), None) => format!("default ({})", device),
+            (_, device) => device.clone().unwrap_or_else(|| DEFAULT_OUTPUT.to_string()),
+        };
+        match &self.open {
+            Some((_, Output::Mixer(_), _)) => format!("{} via cpal", name),
+            _ => name,
+        }
+    }
+}
+
+fn default_device() -> Option<String> {
+    rodio::cpal::default_host()
+        .default_output_device()
+        .and_then(|device| device.name().ok())
+}
+
+// Every output an event plays to, each with its own stream. An output whose
+// device is missing stays closed without affecting the others and is
+// retried, and one whose device goes away is closed until it is back. The
+// streams stop when this is dropped.
+pub struct Outputs {
+    kind: OutputKind,
+    backend: Backend,
+    metrics: Arc<Metrics>,
+    legs: Vec<Leg>,
+    checked: Instant,
+}
+
+impl Outputs {
+    // Fails only when none of the outputs opens. The null kind replaces
+    // them all with a single null output.
+    pub fn open(kind: OutputKind, config: &Config, metrics: &Arc<Metrics>) -> Result<Self> {
+        let backend = Backend::new(config);
+        let targets = match kind {
+            OutputKind::Default => targets(config),
+            OutputKind::Null => vec![Target {
+                device: None,
+                volume: 1.0,
+                master: true,
+                standby: false,
+            }],
+        };
+        let mut legs = Vec::new();
+        let mut last_err = None;
+        for target in targets {
+            let mut leg = Leg {
+                counters: metrics.output(target.label()),
+                target,
+                open: None,
+            };
+            if let Err(err) = leg.open(kind, backend, metrics) {
+                eprintln!("Output {} unavailable: {:?}", leg.target.label(), err);
+                leg.counters.error();
+                leg.counters.set_open(false);
+                last_err = Some(err);
+            }
+            legs.push(leg);
+        }
+        if let (false, Some(err)) = (legs.iter().any(|leg| leg.open.is_some()), last_err) {
+            return Err(err);
+        }
+        Ok(Self {
+            kind,
+            backend,
+            metrics: Arc::clone(metrics),
+            legs,
+            checked: Instant::now(),
+        })
+    }
+
+    // For the stats, e.g. "default (pipewire) + keydio-sink".
+    pub fn name(&self) -> String {
+        self.legs
+            .iter()
+            .filter(|leg| leg.open.is_some())
+            .map(Leg::name)
+            .collect::<Vec<_>>()
+            .join(" + ")
+    }
+
+    pub fn is_device(&self) -> bool {
+        self.legs
+            .iter()
+            .any(|leg| matches!(leg.open, Some((_, Output::Device(_) | Output::Mixer(_), _))))
+    }
+
+    // Whether `config` still plays to these outputs the same way.
+    fn matches(&self, config: &Config) -> bool {
+        let targets: Vec<Target> = self.legs.iter().map(|leg| leg.target.clone()).collect();
+        targets == self::targets(config) && self.backend == Backend::new(config)
+    }
+
+    // At most every `HOTPLUG_INTERVAL`: closes outputs whose device is gone
+    // (or, for the default one, is no longer the default) and reopens the
+    // ones that are back. Returns whether any output opened or closed.
+    pub fn check(&mut self) -> bool {
+        if self.kind == OutputKind::Null || self.checked.elapsed() < HOTPLUG_INTERVAL {
+            return false;
+        }
+        self.checked = Instant::now();
+        let devices: Vec<String> = match rodio::cpal::default_host().output_devices() {
+            Ok(devices) => devices.filter_map(|device| device.name().ok()).collect(),
+            Err(_) => return false,
+        };
+        let default = default_device();
+        let mut changed = false;
+        for leg in &mut self.legs {
+            let present = match &leg.target.device {
+                Some(device) => devices.contains(device),
+                None => default.is_some(),
+            };
+            if let Some((_, _, device)) = &leg.open {
+                let moved = leg.target.device.is_none() && default.as_ref() != Some(device);
+                if !present || moved {
+                    eprintln!("Output {} disconnected", leg.name());
+                    leg.counters.error();
+                    leg.close();
+                    changed = true;
+                }
+            }
+            if leg.open.is_none() && present {
+                match leg.open(self.kind, self.backend, &self.metrics) {
+                    Ok(()) => {
+                        eprintln!("Output {} connected", leg.name());
+                        self.metrics.reconnected();
+                        changed = true;
+                    }
+                    Err(_) => leg.counters.error(),
+                }
+            }
+        }
+        changed
+    }
+}
+
+// How long voices of a replaced theme take to fade out.
+const FADE_OUT: Duration = Duration::from_millis(100);
+
+// Plays its source until `retired` is set, then fades it out over
+// `FADE_OUT` and ends.
+pub struct FadeOut<S> {
+    source: S,
+    retired: Arc<AtomicBool>,
+    length: u32,
+    left: Option<u32>,
+}
+
+impl<S: Source<Item = f32>> FadeOut<S> {
+    pub fn new(source: S, retired: Arc<AtomicBool>) -> Self {
+        let per_second = source.sample_rate() * u32::from(source.channels());
+        Self {
+            source,
+            retired,
+            length: ((per_second as f32 * FADE_OUT.as_secs_f32()) as u32).max(1),
+            left: None,
+        }
+    }
+}
+
+impl<S: Source<Item = f32>> Iterator for FadeOut<S> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.left.is_none() && self.retired.load(Ordering::Relaxed) {
+            self.left = Some(self.length);
+        }
+        match &mut self.left {
+            None => self.source.next(),
+            Some(0) => None,
+            Some(left) => {
+                let gain = *left as f32 / self.length as f32;
+                *left -= 1;
+                self.source.next().map(|sample| sample * gain)
+            }
+        }
+    }
+}
+
+impl<S: Source<Item = f32>> Source for FadeOut<S> {
+    fn current_frame_len(&self) -> Option<usize> {
+        self.source.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.source.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.source.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.source.total_duration()
+    }
+}
+
+// How one event plays, as the audio thread makes it out from the events
+// before it: the velocity and hold variants to pick, and a gain for keys
+// that are part of a chord.
+#[derive(Clone, Copy, Debug)]
+pub struct Shaping {
+    pub velocity: Option<Velocity>,
+    pub held: Option<Held>,
+    pub gain: f32,
+}
+
+impl Default for Shaping {
+    fn default() -> Self {
+        Self {
+            velocity: None,
+            held: None,
+            gain: 1.0,
+        }
+    }
+}
+
+// Picks, decodes and plays the sample for one event from every theme layer
+// to every open output; layers without a sound for the event skip it.
+// Everything after the mute and mode checks happens here. Returns whether
+// anything played.
+pub fn play_event(
+    themes: &ThemeSet,
+    config: &Config,
+    outputs: &Outputs,
+    metrics: &Arc<Metrics>,
+    event: KeyEvent,
+    shaping: Shaping,
+) -> bool {
+    let mut played = vec![false; outputs.legs.len()];
+    let capped = metrics.voices_dropped();
+    let sound_volume = config.sound_volumes.get(&event.sound_type) * shaping.gain;
+    for pick in themes.picks(config, &event, shaping.velocity, shaping.held) {
+        play_sample(
+            pick,
+            config,
+            outputs,
+            metrics,
+            sound_volume,
+            event.at,
+            &mut played,
+        );
+    }
+    for (leg, played) in outputs.legs.iter().zip(&played) {
+        if *played {
+            leg.counters.played();
+        }
+    }
+    let played = played.contains(&true);
+    if played {
+        metrics.played();
+    } else if metrics.voices_dropped() != capped {
+        metrics.capped();
+    }
+    played
+}
+
+fn play_sample(
+    pick: Pick,
+    config: &Config,
+    outputs: &Outputs,
+    metrics: &Arc<Metrics>,
+    sound_volume: f32,
+    at: Instant,
+    played: &mut [bool],
+) {
+    let sound_volume = sound_volume * pick.gain;
+    // Hot sounds were decoded at load, anything else the first time it
+    // plays; every output shares the samples.
+    let (pcm, decoded) = pick.clip.decode(config.engine.format());
+    metrics.decoded(decoded);
+    let Some(pcm) = pcm else {
+        return;
+    };
+    let others_open = outputs
+        .legs
+        .iter()
+        .any(|leg| leg.open.is_some() && !leg.target.standby);
+    for (leg, played) in outputs.legs.iter().zip(played) {
+        let Some((_, output, _)) = &leg.open else {
+            continue;
+        };
+        if leg.target.standby && others_open {
+            continue;
+        }
+        // The cap counts the voices of every output and every layer, so a
+        // layered theme reaches it twice as fast.
+        if metrics.voices_active() >= config.max_voices as u64 {
+            metrics.voice_dropped();
+            continue;
+        }
+        let master = if leg.target.master {
+            config.volume
+        } else {
+            1.0
+        };
+        let source = PcmSource::new(pcm.clone()).amplify(master * leg.target.volume * sound_volume);
+        let source = FadeOut::new(source, Arc::clone(&pick.retired));
+        let voice = Voice::new(source, metrics).timed(at);
+        match output.play(voice) {
+            Ok(()) => *played = true,
+            Err(_) => leg.counters.error(),
+        }
+    }
+}
+
+// Mixes a combo or chord sound in next to whatever keystrokes are still
+// playing, at its own volume, without cutting them off. Returns whether it
+// played.
+fn play_extra(
+    pick: Option<Pick>,
+    config: &Config,
+    outputs: &Outputs,
+    metrics: &Arc<Metrics>,
+    at: Instant,
+) -> bool {
+    if let Some(pick) = pick {
+        let mut played = vec![false; outputs.legs.len()];
+        play_sample(pick, config, outputs, metrics, 1.0, at, &mut played);
+        return played.contains(&true);
+    }
+    false
+}
+
+// Starts the audio thread. Output streams cannot move between threads, so
+// they are opened on the audio thread itself; an error opening them is
+// returned from here.
+pub fn spawn(
+    state: Arc<SharedState>,
+    kind: OutputKind,
+    rx: Receiver<KeyEvent>,
+) -> Result<JoinHandle<()>> {
+    let (ready_tx, ready_rx) = mpsc::channel();
+    let handle = thread::Builder::new()
+        .name("audio".to_string())
+        .spawn(move || {
+            state.set_audio_thread_priority(state.profile().apply_thread_priority());
+            let outputs = match Outputs::open(kind, &state.config(), state.metrics()) {
+                Ok(outputs) => outputs,
+                Err(err) => {
+                    let _ = ready_tx.send(Err(err));
+                    return;
+                }
+            };
+            // Loaded before signalling ready, so a theme that cannot load
+            // fails startup instead of the running thread.
+            let config = state.config();
+            let mut app = AppState::new(&config.theme);
+            if let Err(err) = app.load(&config) {
+                let _ = ready_tx.send(Err(
+                    err.context(format!("Failed to load theme {}", config.theme))
+                ));
+                return;
+            }
+            let _ = ready_tx.send(Ok(()));
+            state.metrics().set_device(outputs.name());
+            handle_audio(&state, kind, outputs, app, rx);
+        })?;
+    ready_rx
+        .recv()
+        .map_err(|_| anyhow!("Audio thread stopped during startup"))??;
+    Ok(handle)
+}
+
+fn handle_audio(
+    state: &SharedState,
+    kind: OutputKind,
+    outputs: Outputs,
+    app: AppState,
+    rx: Receiver<KeyEvent>,
+) {
+    let metrics = state.metrics();
+    let config = state.config();
+    state.set_theme_silent(app.silent.clone());
+    let mut themes = ThemeSet::new(vec![app], &config);
+    themes.sync(state, &config);
+    metrics.theme_loaded(themes.themes());
+    let idle_suspend = state.profile().idle_suspend();
+    let mut tracker = Tracker::default();
+    let mut presses = Presses::default();
+    let mut streak = Streak::default();
+    let mut chord = Chord::default();
+    // Whether the chord sound of the chord being played started, which the
+    // keys after its first count on.
+    let mut chord_sounded = false;
+    let mut outputs = Some(outputs);
+    // Set while the output could not be reopened, so getting it back counts
+    // as a reconnect.
+    let mut lost = false;
+
+    let mut last_event = Instant::now();
+
+    loop {
+        state.audio_heartbeat().beat();
+        let event = match rx.recv_timeout(HEARTBEAT_INTERVAL) {
+            Ok(event) => event,
+            Err(RecvTimeoutError::Timeout) => {
+                let idle = idle_suspend.is_some_and(|idle| last_event.elapsed() >= idle);
+                if idle && outputs.is_some() {
+                    outputs = None;
+                    metrics.set_device("suspended while idle".to_string());
+                }
+                continue;
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        last_event = Instant::now();
+        metrics.received();
+        let config = state.config();
+        let mut shaping = Shaping {
+            velocity: tracker.velocity(&config.velocity, &event),
+            held: presses.held(&config.release, &event),
+            gain: 1.0,
+        };
+        let chorded = if config.chord.enabled {
+            chord.press(&config.chord, &event)
+        } else {
+            0
+        };
+        // Loading happens right here, so events behind this one wait in the
+        // channel until the new theme is ready.
+        if themes.sync(state, &config) {
+            state.set_theme_silent(themes.main().silent.clone());
+            metrics.theme_loaded(themes.themes());
+        }
+        let reloaded = state.take_reloaded();
+        if reloaded.iter().fold(false, |any, (path, clip)| {
+            themes.replace(&config, path, clip) | any
+        }) {
+            metrics.theme_loaded(themes.themes());
+        }
+        if state.is_muted() {
+            metrics.muted();
+            continue;
+        }
+        let combo = config.combo.enabled && streak.press(&config.combo, &event);
+        if !config.mode.plays(&event.keypress) {
+            metrics.filtered();
+            continue;
+        }
+        if let Some(open) = &mut outputs {
+            if !open.matches(&config) && kind == OutputKind::Default {
+                // The list of outputs or the backend was edited.
+                outputs = None;
+            } else if open.check() {
+                metrics.set_device(open.name());
+            }
+        }
+        if outputs.is_none() {
+            match Outputs::open(kind, &config, metrics) {
+                Ok(reopened) => {
+                    metrics.set_device(reopened.name());
+                    if std::mem::take(&mut lost) {
+                        metrics.reconnected();
+                    }
+                    outputs = Some(reopened);
+                }
+                Err(err) => {
+                    eprintln!("Failed to reopen audio output: {:?}", err);
+                    lost = true;
+                    continue;
+                }
+            }
+        }
+        if let Some(outputs) = &outputs {
+            let at = event.at;
+            match (chorded, themes.chord()) {
+                (0, _) => {
+                    play_event(&themes, &config, outputs, metrics, event, shaping);
+                }
+                // The chord sound stands in for every key after the first.
+                (keys, Some(pick)) => {
+                    if keys == 1 {
+                        chord_sounded = play_extra(Some(pick), &config, outputs, metrics, at);
+                    }
+                    if chord_sounded {
+                        metrics.played();
+                    }
+                }
+                (_, None) => {
+                    shaping.gain = config.chord.gain;
+                    play_event(&themes, &config, outputs, metrics, event, shaping);
+                }
+            }
+            if combo {
+                play_extra(themes.combo(&config), &config, outputs, metrics, at);
+            }
+        }
+    }
+
+    if outputs.as_ref().is_some_and(Outputs::is_device) {
+        // Give the last sample time to ring out before the streams are dropped.
+        thread::sleep(PLAYBACK_TAIL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rodio::buffer::SamplesBuffer;
+
+    #[test]
+    fn retired_voices_fade_out_and_end() {
+        let retired = Arc::new(AtomicBool::new(false));
+        // One channel at 1 kHz: the fade takes 100 samples.
+        let source = SamplesBuffer::new(1, 1000, vec![1.0; 1000]);
+        let mut voice = FadeOut::new(source, Arc::clone(&retired));
+        assert!(voice.by_ref().take(10).all(|sample| sample == 1.0));
+
+        retired.store(true, Ordering::Relaxed);
+        let tail: Vec<f32> = voice.collect();
+        assert_eq!(tail.len(), 100);
+        assert!(tail.windows(2).all(|pair| pair[1] < pair[0]));
+        assert!(tail[0] <= 1.0 && *tail.last().unwrap() < 0.05);
+    }
+}
@@ -0,0 +1,110 @@
+use crate::{
+    config::Config,
+    state::{MuteReason, SharedState},
+};
+use anyhow::Result;
+use std::{
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+pub const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+// Something outside keydio that should keep it quiet while it lasts.
+pub struct Condition {
+    pub name: &'static str,
+    pub reason: MuteReason,
+    pub enabled: fn(&Config) -> bool,
+    pub detect: fn(&Config) -> Result<bool>,
+    // How often to check; the profile can stretch it.
+    pub interval: Duration,
+    // How long the condition has to hold, or be gone, before keydio reacts.
+    pub settle_on: Duration,
+    pub settle_off: Duration,
+}
+
+// A condition and where its detection stands.
+struct Watch {
+    condition: Condition,
+    interval: Duration,
+    next: Instant,
+    reported: bool,
+    applied: bool,
+    changed_at: Option<Instant>,
+}
+
+impl Watch {
+    fn poll(&mut self, state: &SharedState, config: &Config) {
+        let condition = &self.condition;
+        let active = (condition.enabled)(config)
+            && match (condition.detect)(config) {
+                Ok(active) => active,
+                Err(err) => {
+                    if !self.reported {
+                        eprintln!("Cannot detect {}: {:?}", condition.name, err);
+                        self.reported = true;
+                    }
+                    false
+                }
+            };
+        if active == self.applied {
+            self.changed_at = None;
+            return;
+        }
+        let settle = if active {
+            condition.settle_on
+        } else {
+            condition.settle_off
+        };
+        let since = *self.changed_at.get_or_insert_with(Instant::now);
+        if since.elapsed() >= settle {
+            eprintln!(
+                "{} {}, {}",
+                condition.name,
+                if active { "started" } else { "ended" },
+                if active { "muting" } else { "unmuting" }
+            );
+            state.set_mute_reason(condition.reason, active);
+            self.applied = active;
+            self.changed_at = None;
+        }
+    }
+}
+
+// Watches every condition on one thread, each at its own interval. A
+// condition mutes when it starts and lifts its own reason when it ends, so
+// keydio stays muted while another one holds. Like quiet hours, only the
+// transitions touch the mute state. Detection errors are reported once
+// and treated as the condition being absent.
+pub fn spawn(state: Arc<SharedState>, conditions: Vec<Condition>) {
+    let started = Instant::now();
+    let mut watches: Vec<Watch> = conditions
+        .into_iter()
+        .map(|condition| {
+            let interval = state.poll_interval(condition.name, condition.interval);
+            Watch {
+                condition,
+                interval,
+                next: started + interval,
+                reported: false,
+                applied: false,
+                changed_at: None,
+            }
+        })
+        .collect();
+    thread::spawn(move || loop {
+        let Some(next) = watches.iter().map(|watch| watch.next).min() else {
+            return;
+        };
+        thread::sleep(next.saturating_duration_since(Instant::now()));
+        let config = state.config();
+        let now = Instant::now();
+        for watch in &mut watches {
+            if watch.next <= now {
+                watch.next += watch.interval;
+                watch.poll(&state, &config);
+            }
+        }
+    });
+}
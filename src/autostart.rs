@@ -0,0 +1,239 @@
+use anyhow::{Context, Result};
+use std::path::PathBuf;
+
+// Everything keydio itself needs to launch at login: the binary and the flags
+// the user asked for.
+fn command_line(args: &[String]) -> Result<Vec<String>> {
+    let exe = std::env::current_exe().context("Cannot determine the keydio binary path")?;
+    let mut command = vec![exe.to_string_lossy().into_owned()];
+    command.extend(args.iter().cloned());
+    Ok(command)
+}
+
+#[cfg(target_os = "windows")]
+fn quote(arg: &str) -> String {
+    if !arg.is_empty() && !arg.contains(|c: char| c.is_whitespace() || c == '"' || c == '\\') {
+        return arg.to_string();
+    }
+    format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use super::*;
+    use anyhow::anyhow;
+    use std::fs;
+
+    fn entry_path() -> Result<PathBuf> {
+        let dir = dirs::config_dir().ok_or_else(|| anyhow!("No config directory found"))?;
+        Ok(dir.join("autostart").join("keydio.desktop"))
+    }
+
+    // An argument as the Desktop Entry spec wants it in `Exec=`: quoted when
+    // it holds a reserved character, with `"`, `` ` ``, `$` and `\` escaped
+    // inside the quotes, `%` doubled so it is not taken for a field code, and
+    // every backslash escaped once more since the value is a string.
+    pub fn exec_arg(arg: &str) -> String {
+        const RESERVED: &str = " \t\n\"'\\><~|&;$*?#()`";
+        let arg = if !arg.is_empty() && !arg.contains(|c| RESERVED.contains(c)) {
+            arg.to_string()
+        } else {
+            let mut quoted = String::from("\"");
+            for c in arg.chars() {
+                if matches!(c, '"' | '`' | '$' | '\\') {
+                    quoted.push('\\');
+                }
+                quoted.push(c);
+            }
+            quoted.push('"');
+            quoted
+        };
+        arg.replace('%', "%%").replace('\\', "\\\\")
+    }
+
+    pub fn enable(command: &[String]) -> Result<PathBuf> {
+        let path = entry_path()?;
+        let exec: Vec<_> = command.iter().map(|arg| exec_arg(arg)).collect();
+        let entry = format!(
+            "[Desktop Entry]\nType=Application\nName=keydio\nComment=Mechanical keyboard sounds\nExec={}\nTerminal=false\nX-GNOME-Autostart-enabled=true\n",
+            exec.join(" ")
+        );
+        fs::create_dir_all(path.parent().unwrap())?;
+        fs::write(&path, entry).with_context(|| format!("Failed to write {}", path.display()))?;
+        Ok(path)
+    }
+
+    pub fn disable() -> Result<Option<PathBuf>> {
+        let path = entry_path()?;
+        if !path.exists() {
+            return Ok(None);
+        }
+        fs::remove_file(&path).with_context(|| format!("Failed to remove {}", path.display()))?;
+        Ok(Some(path))
+    }
+
+    pub fn status() -> Result<Option<String>> {
+        let path = entry_path()?;
+        let Ok(entry) = fs::read_to_string(&path) else {
+            return Ok(None);
+        };
+        let exec = entry
+            .lines()
+            .find_map(|line| line.strip_prefix("Exec="))
+            .unwrap_or_default();
+        Ok(Some(format!("{} ({})", exec, path.display())))
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use super::*;
+    use anyhow::anyhow;
+    use std::fs;
+
+    const LABEL: &str = "io.keydio.keydio";
+
+    fn agent_path() -> Result<PathBuf> {
+        let home = dirs::home_dir().ok_or_else(|| anyhow!("No home directory found"))?;
+        Ok(home
+            .join("Library/LaunchAgents")
+            .join(format!("{}.plist", LABEL)))
+    }
+
+    fn escape(value: &str) -> String {
+        value
+            .replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+    }
+
+    pub fn enable(command: &[String]) -> Result<PathBuf> {
+        let path = agent_path()?;
+        let arguments: String = command
+            .iter()
+            .map(|arg| format!("        <string>{}</string>\n", escape(arg)))
+            .collect();
+        let plist = format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>Label</key>
+    <string>{}</string>
+    <key>ProgramArguments</key>
+    <array>
+{}    </array>
+    <key>RunAtLoad</key>
+    <true/>
+</dict>
+</plist>
+"#,
+            LABEL, arguments
+        );
+        fs::create_dir_all(path.parent().unwrap())?;
+        fs::write(&path, plist).with_context(|| format!("Failed to write {}", path.display()))?;
+        Ok(path)
+    }
+
+    pub fn disable() -> Result<Option<PathBuf>> {
+        let path = agent_path()?;
+        if !path.exists() {
+            return Ok(None);
+        }
+        fs::remove_file(&path).with_context(|| format!("Failed to remove {}", path.display()))?;
+        Ok(Some(path))
+    }
+
+    pub fn status() -> Result<Option<String>> {
+        let path = agent_path()?;
+        Ok(path.exists().then(|| path.display().to_string()))
+    }
+}
+
+#[cfg(target_os = "windows")]
+mod platform {
+    use super::*;
+    use winreg::{enums::HKEY_CURRENT_USER, RegKey};
+
+    const RUN_KEY: &str = r"Software\Microsoft\Windows\CurrentVersion\Run";
+    const VALUE: &str = "keydio";
+
+    pub fn enable(command: &[String]) -> Result<PathBuf> {
+        let (run, _) = RegKey::predef(HKEY_CURRENT_USER).create_subkey(RUN_KEY)?;
+        let line: Vec<_> = command.iter().map(|arg| quote(arg)).collect();
+        run.set_value(VALUE, &line.join(" "))?;
+        Ok(PathBuf::from(format!(r"HKCU\{}\{}", RUN_KEY, VALUE)))
+    }
+
+    pub fn disable() -> Result<Option<PathBuf>> {
+        let run = RegKey::predef(HKEY_CURRENT_USER)
+            .open_subkey_with_flags(RUN_KEY, winreg::enums::KEY_ALL_ACCESS)?;
+        match run.delete_value(VALUE) {
+            Ok(()) => Ok(Some(PathBuf::from(format!(r"HKCU\{}\{}", RUN_KEY, VALUE)))),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub fn status() -> Result<Option<String>> {
+        let run = RegKey::predef(HKEY_CURRENT_USER).open_subkey(RUN_KEY)?;
+        Ok(run.get_value::<String, _>(VALUE).ok())
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+mod platform {
+    use super::*;
+    use anyhow::anyhow;
+
+    pub fn enable(_command: &[String]) -> Result<PathBuf> {
+        Err(anyhow!("Autostart is not supported on this platform"))
+    }
+
+    pub fn disable() -> Result<Option<PathBuf>> {
+        Err(anyhow!("Autostart is not supported on this platform"))
+    }
+
+    pub fn status() -> Result<Option<String>> {
+        Err(anyhow!("Autostart is not supported on this platform"))
+    }
+}
+
+// Enabling again rewrites the same entry, so it updates rather than duplicates.
+pub fn enable(args: &[String]) -> Result<()> {
+    let command = command_line(args)?;
+    let location = platform::enable(&command)?;
+    println!("Autostart enabled: {}", location.display());
+    Ok(())
+}
+
+pub fn disable() -> Result<()> {
+    match platform::disable()? {
+        Some(location) => println!("Autostart disabled: removed {}", location.display()),
+        None => println!("Autostart was not enabled"),
+    }
+    Ok(())
+}
+
+pub fn status() -> Result<()> {
+    match platform::status()? {
+        Some(entry) => println!("Autostart is enabled: {}", entry),
+        None => println!("Autostart is disabled"),
+    }
+    Ok(())
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::platform::exec_arg;
+
+    #[test]
+    fn exec_arguments_follow_the_desktop_entry_spec() {
+        assert_eq!(exec_arg("--volume"), "--volume");
+        assert_eq!(exec_arg("50%"), "50%%");
+        assert_eq!(exec_arg("my theme"), "\"my theme\"");
+        assert_eq!(exec_arg("a$b"), "\"a\\\\$b\"");
+        assert_eq!(exec_arg(r"C:\dir"), r#""C:\\\\dir""#);
+        assert_eq!(exec_arg(""), "\"\"");
+    }
+}
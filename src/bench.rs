@@ -0,0 +1,195 @@
+use crate::{
+    assets,
+    audio::{self, OutputKind, Outputs, Shaping},
+    config::Config,
+    manifest::Manifest,
+    metrics::Metrics,
+    themes::ThemeSet,
+    AppState, KeyEvent, KeyPressType, SoundType,
+};
+use anyhow::{bail, Context, Result};
+use rodio::{Decoder, Source};
+use serde::Serialize;
+use std::{
+    fs,
+    io::Cursor,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+#[derive(Serialize)]
+struct FileTiming {
+    file: String,
+    raw_bytes: u64,
+    decoded_bytes: u64,
+    read_us: f64,
+    decode_us: f64,
+}
+
+#[derive(Serialize)]
+struct EventTimings {
+    events: usize,
+    p50_us: f64,
+    p95_us: f64,
+    max_us: f64,
+}
+
+#[derive(Serialize)]
+struct Report {
+    theme: String,
+    load_us: f64,
+    raw_bytes: u64,
+    decoded_bytes: u64,
+    files: Vec<FileTiming>,
+    pipeline: EventTimings,
+}
+
+fn micros(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1e6
+}
+
+fn time_files(theme: &str) -> Result<Vec<FileTiming>> {
+    let dir = assets::theme_dir(theme);
+    let manifest = Manifest::load(&dir)?;
+    let mut files = Vec::new();
+    for keypress in [
+        KeyPressType::Press,
+        KeyPressType::Release,
+        KeyPressType::Hold,
+    ] {
+        for sample in manifest.samples(&dir, &keypress) {
+            let started = Instant::now();
+            let data = match fs::read(&sample.path) {
+                Ok(data) => data,
+                Err(_) if !sample.required => continue,
+                Err(err) => {
+                    return Err(err)
+                        .with_context(|| format!("Failed to read {}", sample.path.display()))
+                }
+            };
+            let read = started.elapsed();
+            let raw_bytes = data.len() as u64;
+            let started = Instant::now();
+            let decoded: Vec<f32> = Decoder::new(Cursor::new(data))
+                .with_context(|| format!("Failed to decode {}", sample.path.display()))?
+                .convert_samples()
+                .collect();
+            let decode = started.elapsed();
+            files.push(FileTiming {
+                file: sample.path.display().to_string(),
+                raw_bytes,
+                decoded_bytes: (decoded.len() * std::mem::size_of::<f32>()) as u64,
+                read_us: micros(read),
+                decode_us: micros(decode),
+            });
+        }
+    }
+    Ok(files)
+}
+
+// Runs synthetic events straight through the playback path into the null
+// output, which pulls every sample, so each timing covers the whole job.
+fn time_pipeline(theme: &str, events: usize) -> Result<(Duration, EventTimings)> {
+    let config = Config {
+        theme: theme.to_string(),
+        ..Config::default()
+    };
+    let started = Instant::now();
+    let mut app = AppState::new(theme);
+    app.load_audio_samples(&config)?;
+    let themes = ThemeSet::new(vec![app], &config);
+    let load = started.elapsed();
+    let metrics = Arc::new(Metrics::new());
+    let outputs = Outputs::open(OutputKind::Null, &config, &metrics)?;
+    let kinds = [
+        SoundType::Generic,
+        SoundType::Generic,
+        SoundType::Generic,
+        SoundType::Space,
+        SoundType::Enter,
+        SoundType::Backspace,
+    ];
+    let mut timings: Vec<Duration> = (0..events)
+        .map(|index| {
+            let event = KeyEvent {
+                key: None,
+                keypress: if index % 2 == 0 {
+                    KeyPressType::Press
+                } else {
+                    KeyPressType::Release
+                },
+                sound_type: kinds[(index / 2) % kinds.len()].clone(),
+                at: Instant::now(),
+            };
+            let started = Instant::now();
+            audio::play_event(
+                &themes,
+                &config,
+                &outputs,
+                &metrics,
+                event,
+                Shaping::default(),
+            );
+            started.elapsed()
+        })
+        .collect();
+    timings.sort();
+    let percentile = |p: f64| {
+        timings
+            .get(((timings.len() as f64 - 1.0) * p).round() as usize)
+            .map_or(0.0, |timing| micros(*timing))
+    };
+    Ok((
+        load,
+        EventTimings {
+            events,
+            p50_us: percentile(0.5),
+            p95_us: percentile(0.95),
+            max_us: percentile(1.0),
+        },
+    ))
+}
+
+pub fn run(theme: &str, events: usize, json: bool) -> Result<()> {
+    if !assets::theme_dir(theme).is_dir() {
+        bail!("Unknown theme: {}", theme);
+    }
+    let files = time_files(theme)?;
+    let (load, pipeline) = time_pipeline(theme, events)?;
+    let report = Report {
+        theme: theme.to_string(),
+        load_us: micros(load),
+        raw_bytes: files.iter().map(|file| file.raw_bytes).sum(),
+        decoded_bytes: files.iter().map(|file| file.decoded_bytes).sum(),
+        files,
+        pipeline,
+    };
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
+
+    println!("theme {} loaded in {:.0} us", report.theme, report.load_us);
+    println!(
+        "{:<48} {:>10} {:>12} {:>10} {:>10}",
+        "file", "raw", "decoded", "read us", "decode us"
+    );
+    for file in &report.files {
+        println!(
+            "{:<48} {:>10} {:>12} {:>10.0} {:>10.0}",
+            file.file, file.raw_bytes, file.decoded_bytes, file.read_us, file.decode_us
+        );
+    }
+    println!(
+        "{:<48} {:>10} {:>12}",
+        "total", report.raw_bytes, report.decoded_bytes
+    );
+    println!(
+        "{} events: p50 {:.0} us, p95 {:.0} us, max {:.0} us",
+        report.pipeline.events,
+        report.pipeline.p50_us,
+        report.pipeline.p95_us,
+        report.pipeline.max_us
+    );
+    Ok(())
+}
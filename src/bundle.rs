@@ -0,0 +1,411 @@
+use crate::{archive, assets, clean, config::Config, fsutil, manifest, musical, pack, version};
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Component, Path, PathBuf},
+};
+
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+// What a bundle holds besides its themes and sounds.
+const MANIFEST: &str = "keydio-profile.json";
+const CONFIG: &str = "config.toml";
+const THEMES: &str = "themes";
+const SOUNDS: &str = "key_sounds";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+    // The keydio that wrote the bundle, so an older one can tell which
+    // settings it may not know.
+    keydio_version: String,
+    themes: Vec<String>,
+    // The bundled file of every `key_sounds` entry.
+    key_sounds: BTreeMap<String, String>,
+}
+
+impl Manifest {
+    // The manifest comes from whoever made the bundle, and its theme names
+    // pick directories to replace, so each has to be a plain name: no path
+    // that leads elsewhere, nothing `archive::check_name` refuses.
+    fn check(&self) -> Result<()> {
+        for name in &self.themes {
+            archive::check_name(name)?;
+            let mut components = Path::new(name).components();
+            if !matches!(
+                (components.next(), components.next()),
+                (Some(Component::Normal(_)), None)
+            ) {
+                bail!("The bundle names an invalid theme: {}", name);
+            }
+        }
+        for entry in self.key_sounds.values() {
+            archive::check_name(entry)?;
+            if !entry.starts_with(&format!("{}/", SOUNDS)) {
+                bail!("The bundle has a key sound outside {}: {}", SOUNDS, entry);
+            }
+        }
+        Ok(())
+    }
+}
+
+// Themes the config plays: the main one, its layers and the scheduled ones.
+fn referenced(config: &Config) -> Vec<String> {
+    let mut themes: Vec<String> = config
+        .themes()
+        .into_iter()
+        .map(|(theme, _)| theme)
+        .chain(
+            config
+                .theme_schedule
+                .ranges
+                .iter()
+                .map(|range| range.theme.clone()),
+        )
+        .collect();
+    themes.sort();
+    themes.dedup();
+    themes
+}
+
+// A theme the user installed or made: one in the user's theme directory, or
+// one given as a path. Themes that ship with keydio are on the new machine
+// already.
+fn custom_dir(theme: &str) -> Option<PathBuf> {
+    if theme == musical::THEME {
+        return None;
+    }
+    let dir = assets::theme_dir(theme);
+    let custom = Path::new(theme).is_absolute()
+        || assets::user_dir().is_some_and(|user| dir.starts_with(user));
+    (custom && dir.is_dir()).then_some(dir)
+}
+
+// `theme` and `theme:0.3` both refer to the theme `theme`.
+fn renamed(spec: &str, from: &str, to: &str) -> Option<String> {
+    let rest = spec.strip_prefix(from)?;
+    (rest.is_empty() || rest.starts_with(':')).then(|| format!("{}{}", to, rest))
+}
+
+// Points every reference to the theme `from` in a config file at `to`.
+fn rename_theme(table: &mut toml::Table, from: &str, to: &str) {
+    let rename = |value: &mut toml::Value| {
+        if let Some(spec) = value.as_str().and_then(|spec| renamed(spec, from, to)) {
+            *value = toml::Value::String(spec);
+        }
+    };
+    match table.get_mut("theme") {
+        Some(toml::Value::Array(specs)) => specs.iter_mut().for_each(rename),
+        Some(spec) => rename(spec),
+        None => {}
+    }
+    if let Some(toml::Value::Array(ranges)) = table
+        .get_mut("theme_schedule")
+        .and_then(|schedule| schedule.get_mut("ranges"))
+    {
+        for range in ranges.iter_mut().filter_map(toml::Value::as_table_mut) {
+            if let Some(theme) = range.get_mut("theme") {
+                rename(theme);
+            }
+        }
+    }
+}
+
+// `keydio export-profile`: the config file, with every sound file it plays
+// from `key_sounds` and every custom theme it uses, in one archive.
+pub fn export(out: &Path) -> Result<()> {
+    let path = Config::path()?;
+    let mut text = fs::read_to_string(&path)
+        .with_context(|| format!("No config to export at {}", path.display()))?;
+    let config =
+        Config::parse(&text).with_context(|| format!("Invalid config file {}", path.display()))?;
+    let mut table: toml::Table = toml::from_str(&text)?;
+    let mut manifest = Manifest {
+        keydio_version: VERSION.to_string(),
+        themes: Vec::new(),
+        key_sounds: BTreeMap::new(),
+    };
+    let mut files = Vec::new();
+    let mut renames = false;
+    for theme in referenced(&config) {
+        let Some(dir) = custom_dir(&theme) else {
+            continue;
+        };
+        let name = dir
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .ok_or_else(|| anyhow!("Cannot tell the theme name from {}", theme))?;
+        if manifest.themes.contains(&name) {
+            anyhow::bail!("Two of the themes are called {}; rename one", name);
+        }
+        // A theme given as a path is imported by name.
+        if name != theme {
+            rename_theme(&mut table, &theme, &name);
+            renames = true;
+        }
+        for file in pack::walk(&dir)? {
+            let entry = pack::entry_name(file.strip_prefix(&dir)?);
+            if !pack::is_junk(entry.rsplit('/').next().unwrap_or(&entry)) {
+                files.push((format!("{}/{}/{}", THEMES, name, entry), fs::read(&file)?));
+            }
+        }
+        manifest.themes.push(name);
+    }
+    let mut sounds: BTreeMap<PathBuf, String> = BTreeMap::new();
+    for (key, sound) in &config.key_sounds {
+        let file = fsutil::expand_home(sound);
+        if !sounds.contains_key(&file) {
+            let data =
+                fs::read(&file).with_context(|| format!("Failed to read {}", file.display()))?;
+            let base = file
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            // Sounds from different directories can share a name.
+            let entry = format!("{}/{}-{}", SOUNDS, sounds.len() + 1, base);
+            files.push((entry.clone(), data));
+            sounds.insert(file.clone(), entry);
+        }
+        manifest
+            .key_sounds
+            .insert(key.clone(), sounds[&file].clone());
+    }
+    // The file as written keeps its comments, unless themes were renamed.
+    if renames {
+        text = toml::to_string_pretty(&table)?;
+    }
+    let mut entries = vec![
+        (MANIFEST.to_string(), serde_json::to_vec_pretty(&manifest)?),
+        (CONFIG.to_string(), text.into_bytes()),
+    ];
+    entries.extend(files);
+    fsutil::write_atomic(out, &archive::tar(&entries)?)?;
+    println!(
+        "Exported the config, {} themes and {} key sounds to {}",
+        manifest.themes.len(),
+        sounds.len(),
+        out.display()
+    );
+    Ok(())
+}
+
+// Settings of `bundled` that did not survive a round trip through this
+// keydio's config, as dotted paths.
+fn dropped(bundled: &toml::Table, kept: &toml::Table, prefix: &str) -> Vec<String> {
+    bundled
+        .iter()
+        .flat_map(|(key, value)| {
+            let path = format!("{}{}", prefix, key);
+            match (value, kept.get(key)) {
+                (_, None) => vec![path],
+                (toml::Value::Table(bundled), Some(toml::Value::Table(kept))) => {
+                    dropped(bundled, kept, &format!("{}.", path))
+                }
+                _ => Vec::new(),
+            }
+        })
+        .collect()
+}
+
+fn warn_unknown(table: &toml::Table, config: &Config, bundled_by: &str) -> Result<()> {
+    let mut kept = toml::Table::try_from(config)?;
+    // Written apart from the rest, see `Config::save`.
+    kept.insert("theme".to_string(), toml::Value::Boolean(true));
+    let unknown = dropped(table, &kept, "");
+    if unknown.is_empty() {
+        return Ok(());
+    }
+    let origin = if version(bundled_by) > version(VERSION) {
+        format!(", the bundle is from keydio {}", bundled_by)
+    } else {
+        String::new()
+    };
+    eprintln!(
+        "Warning: keydio {} does not know these settings{}; they are kept in the config but do nothing:",
+        VERSION, origin
+    );
+    for key in unknown {
+        eprintln!("  {}", key);
+    }
+    Ok(())
+}
+
+// Whether to write over `path`; what does not exist yet is always written.
+fn overwrite(path: &Path, yes: bool) -> Result<bool> {
+    if yes || !path.exists() {
+        return Ok(true);
+    }
+    clean::confirm(&format!("Overwrite {}?", path.display()))
+}
+
+// Unpacks and validates one bundled theme next to where it goes.
+fn stage(user: &Path, name: &str, entries: &BTreeMap<String, Vec<u8>>) -> Result<PathBuf> {
+    let staging = user.join(format!(".{}.importing", name));
+    if staging.exists() {
+        fs::remove_dir_all(&staging)?;
+    }
+    let prefix = format!("{}/{}/", THEMES, name);
+    let unpacked = (|| -> Result<()> {
+        for (entry, contents) in entries {
+            if let Some(relative) = entry.strip_prefix(&prefix) {
+                fsutil::write_atomic(&staging.join(relative), contents)?;
+            }
+        }
+        manifest::validate(&staging)?;
+        Ok(())
+    })();
+    match unpacked {
+        Ok(()) => Ok(staging),
+        Err(err) => {
+            let _ = fs::remove_dir_all(&staging);
+            Err(err.context(format!("Theme {} failed validation", name)))
+        }
+    }
+}
+
+// `keydio import-profile`: puts a bundle's config, themes and key sounds
+// where this platform keeps them. Every theme has to validate before any is
+// moved in, and nothing that exists is replaced without asking.
+pub fn import(file: &Path, yes: bool) -> Result<()> {
+    let data = fs::read(file).with_context(|| format!("Failed to read {}", file.display()))?;
+    let mut entries: BTreeMap<String, Vec<u8>> = archive::untar(&data)
+        .with_context(|| format!("{} is not a profile bundle", file.display()))?
+        .into_iter()
+        .collect();
+    let manifest: Manifest = entries
+        .remove(MANIFEST)
+        .ok_or_else(|| anyhow!("{} is not a keydio profile bundle", file.display()))
+        .and_then(|data| Ok(serde_json::from_slice::<Manifest>(&data)?))
+        .and_then(|manifest| manifest.check().map(|()| manifest))
+        .context("Invalid profile bundle")?;
+    let text = entries
+        .remove(CONFIG)
+        .ok_or_else(|| anyhow!("The bundle has no config"))?;
+    let mut table: toml::Table =
+        toml::from_str(&String::from_utf8(text)?).context("The bundled config is invalid")?;
+
+    let data_dir = dirs::data_dir().ok_or_else(|| anyhow!("No data directory found"))?;
+    let sounds_dir = data_dir.join("keydio").join("sounds");
+    let mut sounds = Vec::new();
+    if let Some(toml::Value::Table(key_sounds)) = table.get_mut("key_sounds") {
+        for (key, entry) in &manifest.key_sounds {
+            let contents = entries
+                .get(entry)
+                .ok_or_else(|| anyhow!("The bundle is missing {}", entry))?;
+            let path = sounds_dir.join(entry.trim_start_matches(&format!("{}/", SOUNDS)));
+            key_sounds.insert(key.clone(), toml::Value::String(path.display().to_string()));
+            sounds.push((path, contents));
+        }
+    }
+    let text = toml::to_string_pretty(&table)?;
+    let config = Config::parse(&text).context("The bundled config is invalid")?;
+    warn_unknown(&table, &config, &manifest.keydio_version)?;
+
+    // Every question comes before anything is written, so an import refused
+    // halfway leaves the machine as it was.
+    let user = assets::user_dir().ok_or_else(|| anyhow!("No data directory found"))?;
+    let mut replace_themes = Vec::new();
+    for name in &manifest.themes {
+        replace_themes.push(overwrite(&user.join(name), yes)?);
+    }
+    let mut replace_sounds = Vec::new();
+    for (path, _) in &sounds {
+        replace_sounds.push(overwrite(path, yes)?);
+    }
+    let path = Config::path()?;
+    let replace_config = overwrite(&path, yes)?;
+
+    let mut staged = Vec::new();
+    for (name, _) in manifest
+        .themes
+        .iter()
+        .zip(&replace_themes)
+        .filter(|(_, yes)| **yes)
+    {
+        match stage(&user, name, &entries) {
+            Ok(staging) => staged.push((name, staging)),
+            Err(err) => {
+                for (_, staging) in staged {
+                    let _ = fs::remove_dir_all(staging);
+                }
+                return Err(err);
+            }
+        }
+    }
+    let themes = staged.len();
+    for (name, staging) in staged {
+        let target = user.join(name);
+        if target.exists() {
+            fs::remove_dir_all(&target)?;
+        }
+        fs::rename(&staging, &target)
+            .with_context(|| format!("Failed to move the theme to {}", target.display()))?;
+    }
+    let mut written = 0;
+    for ((path, contents), _) in sounds.iter().zip(&replace_sounds).filter(|(_, yes)| **yes) {
+        fsutil::write_atomic(path, contents)?;
+        written += 1;
+    }
+    if replace_config {
+        fsutil::write_atomic(&path, text.as_bytes())?;
+        println!("Imported the config to {}", path.display());
+    }
+    println!(
+        "Imported {} themes to {} and {} key sounds to {}",
+        themes,
+        user.display(),
+        written,
+        sounds_dir.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn theme_names_that_are_paths_are_rejected() {
+        for name in ["..", ".", "a/b", "/tmp", "..\\x", "C:x", ""] {
+            let manifest = Manifest {
+                keydio_version: VERSION.to_string(),
+                themes: vec![name.to_string()],
+                key_sounds: BTreeMap::new(),
+            };
+            assert!(manifest.check().is_err(), "{:?}", name);
+        }
+        let manifest = Manifest {
+            keydio_version: VERSION.to_string(),
+            themes: vec!["typewriter".to_string()],
+            key_sounds: BTreeMap::from([("Enter".to_string(), "config.toml".to_string())]),
+        };
+        assert!(manifest.check().is_err());
+    }
+
+    #[test]
+    fn themes_given_as_paths_are_bundled_by_name() {
+        let mut table: toml::Table = toml::from_str(
+            r#"
+            theme = ["/home/ada/themes/typewriter", "/home/ada/themes/typewriter2:0.3"]
+            [[theme_schedule.ranges]]
+            start = "22:00"
+            end = "07:00"
+            theme = "/home/ada/themes/typewriter"
+            "#,
+        )
+        .unwrap();
+        rename_theme(&mut table, "/home/ada/themes/typewriter", "typewriter");
+        assert_eq!(
+            table["theme"].as_array().unwrap()[0].as_str(),
+            Some("typewriter")
+        );
+        assert_eq!(
+            table["theme"].as_array().unwrap()[1].as_str(),
+            Some("/home/ada/themes/typewriter2:0.3")
+        );
+        assert_eq!(
+            table["theme_schedule"]["ranges"][0]["theme"].as_str(),
+            Some("typewriter")
+        );
+    }
+}
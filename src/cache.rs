@@ -0,0 +1,171 @@
+use crate::{
+    fsutil,
+    sample::{Bytes, Format, Pcm},
+    sha256,
+};
+use anyhow::{anyhow, Result};
+use std::{fs, io::ErrorKind, path::PathBuf};
+
+// Decoded samples of the hot sound types, kept on disk so a large theme
+// starts without decoding it all again. An entry is named after a hash of
+// the encoded sample, the engine format and the keydio version, so any
+// change to them misses and rebuilds it.
+const MAGIC: &[u8; 8] = b"KEYDPCM1";
+// Magic, channels, sample rate, sample count and CRC-32 of the samples.
+const HEADER: usize = 8 + 2 + 4 + 8 + 4;
+
+// Hits and misses while loading one theme.
+#[derive(Clone, Debug, Default)]
+pub struct Counts {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+pub fn dir() -> Result<PathBuf> {
+    let dir = dirs::cache_dir().ok_or_else(|| anyhow!("No cache directory found"))?;
+    Ok(dir.join("keydio").join("pcm"))
+}
+
+fn key(bytes: &Bytes, format: Format) -> String {
+    let mut input = bytes.as_ref().to_vec();
+    input.extend_from_slice(
+        format!(
+            "\n{} {} {}",
+            format.channels,
+            format.sample_rate,
+            env!("CARGO_PKG_VERSION")
+        )
+        .as_bytes(),
+    );
+    sha256::hex(&input)
+}
+
+fn encode(pcm: &Pcm) -> Vec<u8> {
+    let samples: Vec<u8> = pcm
+        .samples
+        .iter()
+        .flat_map(|sample| sample.to_le_bytes())
+        .collect();
+    let mut data = Vec::with_capacity(HEADER + samples.len());
+    data.extend_from_slice(MAGIC);
+    data.extend_from_slice(&pcm.channels.to_le_bytes());
+    data.extend_from_slice(&pcm.sample_rate.to_le_bytes());
+    data.extend_from_slice(&(pcm.samples.len() as u64).to_le_bytes());
+    data.extend_from_slice(&crc32fast::hash(&samples).to_le_bytes());
+    data.extend_from_slice(&samples);
+    data
+}
+
+// `None` unless the entry is whole: the header matches `format`, the length
+// matches the header and the samples match their CRC.
+fn parse(data: &[u8], format: Format) -> Option<Pcm> {
+    let header = data.get(..HEADER)?;
+    if &header[..8] != MAGIC {
+        return None;
+    }
+    let channels = u16::from_le_bytes(header[8..10].try_into().ok()?);
+    let sample_rate = u32::from_le_bytes(header[10..14].try_into().ok()?);
+    let count = u64::from_le_bytes(header[14..22].try_into().ok()?);
+    let crc = u32::from_le_bytes(header[22..26].try_into().ok()?);
+    let samples = &data[HEADER..];
+    if channels != format.channels
+        || sample_rate != format.sample_rate
+        || samples.len() as u64 != count.checked_mul(4)?
+        || crc32fast::hash(samples) != crc
+    {
+        return None;
+    }
+    Some(Pcm {
+        channels,
+        sample_rate,
+        samples: samples
+            .chunks_exact(4)
+            .map(|sample| f32::from_le_bytes([sample[0], sample[1], sample[2], sample[3]]))
+            .collect(),
+    })
+}
+
+// Like `Pcm::decode`, through the cache. A missing or damaged entry is
+// decoded again and rewritten; failing to write it only costs the next
+// start the same decode.
+pub fn decode(bytes: &Bytes, format: Format, counts: &mut Counts) -> Result<Pcm> {
+    let Ok(dir) = dir() else {
+        return Pcm::decode(bytes, format);
+    };
+    let path = dir.join(format!("{}.pcm", key(bytes, format)));
+    if let Some(pcm) = fs::read(&path).ok().and_then(|data| parse(&data, format)) {
+        counts.hits += 1;
+        return Ok(pcm);
+    }
+    counts.misses += 1;
+    let pcm = Pcm::decode(bytes, format)?;
+    if let Err(err) = fsutil::write_atomic(&path, &encode(&pcm)) {
+        eprintln!("Failed to cache decoded samples: {:?}", err);
+    }
+    Ok(pcm)
+}
+
+// Bytes the cache takes on disk.
+pub fn size() -> u64 {
+    let Ok(entries) = dir().and_then(|dir| Ok(fs::read_dir(dir)?)) else {
+        return 0;
+    };
+    entries
+        .filter_map(|entry| entry.ok()?.metadata().ok())
+        .map(|meta| meta.len())
+        .sum()
+}
+
+// `keydio cache clear`.
+pub fn clear() -> Result<()> {
+    let dir = dir()?;
+    let size = size();
+    match fs::remove_dir_all(&dir) {
+        Ok(()) => println!("Removed {} ({} bytes)", dir.display(), size),
+        Err(err) if err.kind() == ErrorKind::NotFound => println!("The cache is empty"),
+        Err(err) => return Err(err.into()),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FORMAT: Format = Format {
+        channels: 2,
+        sample_rate: 48_000,
+    };
+
+    fn entry() -> Vec<u8> {
+        encode(&Pcm {
+            channels: 2,
+            sample_rate: 48_000,
+            samples: vec![0.25, -0.5, 1.0, 0.0].into(),
+        })
+    }
+
+    #[test]
+    fn entries_round_trip() {
+        let pcm = parse(&entry(), FORMAT).unwrap();
+        assert_eq!(&*pcm.samples, [0.25, -0.5, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn damaged_entries_are_never_played() {
+        let entry = entry();
+        // Truncated, padded, flipped sample bits, a different engine format.
+        let mut flipped = entry.clone();
+        *flipped.last_mut().unwrap() ^= 1;
+        let mut padded = entry.clone();
+        padded.extend_from_slice(&[0; 4]);
+        for data in [&entry[..entry.len() - 4], &entry[..10], &padded, &flipped] {
+            assert!(parse(data, FORMAT).is_none());
+        }
+        let mono = Format {
+            channels: 1,
+            ..FORMAT
+        };
+        assert!(parse(&entry, mono).is_none());
+    }
+}
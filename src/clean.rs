@@ -0,0 +1,100 @@
+use crate::{assets, crash, fsutil};
+use anyhow::{anyhow, bail, Context, Result};
+use std::{
+    fs,
+    io::{self, BufRead, IsTerminal, Write},
+    path::PathBuf,
+};
+
+// What `keydio clean` can remove. The cache is rebuilt and the registry index
+// fetched again as needed; installed themes are gone for good.
+struct Target {
+    label: &'static str,
+    path: PathBuf,
+}
+
+fn targets(cache: bool, state: bool, themes: bool) -> Result<Vec<Target>> {
+    let mut targets = Vec::new();
+    if cache {
+        let dir = dirs::cache_dir().ok_or_else(|| anyhow!("No cache directory found"))?;
+        targets.push(Target {
+            label: "decoded samples and registry index",
+            path: dir.join("keydio"),
+        });
+    }
+    if state {
+        // Only the log itself: on some platforms the state directory is
+        // also where themes are installed.
+        if let Some(path) = crash::log_path() {
+            targets.push(Target {
+                label: "crash log",
+                path,
+            });
+        }
+    }
+    if themes {
+        if let Some(path) = assets::user_dir() {
+            targets.push(Target {
+                label: "installed themes",
+                path,
+            });
+        }
+    }
+    Ok(targets)
+}
+
+// Asks on the terminal; without one there is nobody to ask, so only `--yes`
+// goes ahead.
+pub fn confirm(question: &str) -> Result<bool> {
+    if !io::stdin().is_terminal() {
+        bail!("No terminal to confirm on; pass --yes");
+    }
+    eprint!("{} [y/N] ", question);
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+// `keydio clean`: the cache and the crash log unless told which, everything
+// including installed themes with `all`. Lists what goes and asks first.
+pub fn run(cache: bool, state: bool, all: bool, yes: bool) -> Result<()> {
+    let neither = !cache && !state;
+    let (cache, state) = (cache || all || neither, state || all || neither);
+    let targets: Vec<(Target, u64)> = targets(cache, state, all)?
+        .into_iter()
+        .filter(|target| target.path.exists())
+        .map(|target| {
+            let size = fsutil::disk_size(&target.path);
+            (target, size)
+        })
+        .collect();
+    if targets.is_empty() {
+        println!("Nothing to clean");
+        return Ok(());
+    }
+    for (target, size) in &targets {
+        println!(
+            "{:>9}  {} ({})",
+            fsutil::human_size(*size),
+            target.path.display(),
+            target.label
+        );
+    }
+    let total: u64 = targets.iter().map(|(_, size)| size).sum();
+    println!("{:>9}  in total", fsutil::human_size(total));
+    if !yes && !confirm("Remove them?")? {
+        println!("Nothing removed");
+        return Ok(());
+    }
+    for (target, _) in &targets {
+        let removed = if target.path.is_dir() {
+            fs::remove_dir_all(&target.path)
+        } else {
+            fs::remove_file(&target.path)
+        };
+        removed.with_context(|| format!("Failed to remove {}", target.path.display()))?;
+    }
+    println!("Removed {}", fsutil::human_size(total));
+    Ok(())
+}
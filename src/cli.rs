@@ -0,0 +1,291 @@
+use crate::{audio::OutputKind, convert::PackFormat, profile::Profile};
+use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+
+#[derive(Parser)]
+#[command(version, about = "Mechanical keyboard sounds for every keystroke")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Keep typing statistics (counts only) and log them every minute
+    #[arg(long)]
+    pub stats: bool,
+
+    /// Write a JSON stats snapshot to this file every minute and on exit
+    #[arg(long, value_name = "PATH")]
+    pub stats_file: Option<PathBuf>,
+
+    /// Count presses per key into this JSON (or .csv) file, merged across sessions
+    #[arg(long, value_name = "PATH")]
+    pub heatmap: Option<PathBuf>,
+
+    /// Minutes between heatmap writes
+    #[arg(long, value_name = "MINUTES", default_value_t = 5)]
+    pub heatmap_interval: u64,
+
+    /// Log runtime counters (samples, memory, events, voices) every minute
+    #[arg(short, long, global = true)]
+    pub verbose: bool,
+
+    /// Audio output to play through; `null` plays nothing, for tests and CI
+    #[arg(long, value_enum, default_value_t = OutputKind::Default, global = true)]
+    pub output: OutputKind,
+
+    /// Play nothing; together with --osc-target keydio only forwards events
+    #[arg(long, global = true)]
+    pub no_audio: bool,
+
+    /// Send key events as OSC messages to this UDP address
+    #[cfg(feature = "osc")]
+    #[arg(long, value_name = "HOST:PORT")]
+    pub osc_target: Option<String>,
+
+    /// Start even if another keydio instance is already running
+    #[arg(long, global = true)]
+    pub force: bool,
+
+    /// Save power: poll less often, lower the audio thread priority and close
+    /// the audio stream while idle
+    #[arg(long, global = true, conflicts_with = "low_latency")]
+    pub low_power: bool,
+
+    /// Raise the audio thread priority for the lowest possible lag
+    #[arg(long, global = true)]
+    pub low_latency: bool,
+
+    /// Log how long after each keystroke its sound reaches the output
+    #[arg(long, global = true)]
+    pub debug_latency: bool,
+}
+
+impl Cli {
+    // The profile picked on the command line, which overrides the config file.
+    pub fn profile(&self) -> Option<Profile> {
+        if self.low_power {
+            Some(Profile::LowPower)
+        } else if self.low_latency {
+            Some(Profile::LowLatency)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Open the settings window
+    Settings,
+    /// Record the rhythm of a typing session (sound types and timing only)
+    Record {
+        /// File to write the session to
+        #[arg(long, value_name = "PATH")]
+        out: PathBuf,
+        /// Seconds to record, counted from the first keystroke
+        #[arg(long, value_name = "SECONDS", default_value_t = 60)]
+        duration: u64,
+        /// Also store which key was pressed
+        #[arg(long)]
+        with_keys: bool,
+    },
+    /// Play a recorded session through the audio engine without hooking the keyboard
+    Replay {
+        /// Session file written by `keydio record`
+        file: PathBuf,
+        /// Theme to replay with instead of the configured one
+        #[arg(long)]
+        theme: Option<String>,
+        /// Timing multiplier, e.g. 1.5 plays the session faster
+        #[arg(long, default_value_t = 1.0)]
+        speed: f64,
+        /// Start over at the end of the session until interrupted
+        #[arg(long = "loop")]
+        looped: bool,
+    },
+    /// Play scripted key events through the audio engine without hooking the
+    /// keyboard. Reads lines like `press A`, `release A` and `sleep 120`
+    /// (milliseconds); end with a sleep to let the last sound ring out
+    Simulate {
+        /// Script to read instead of stdin
+        #[arg(long)]
+        script: Option<PathBuf>,
+        /// Theme to play with instead of the configured one
+        #[arg(long)]
+        theme: Option<String>,
+    },
+    /// Flood the audio engine with random key events and check that it keeps up;
+    /// exits non-zero if any invariant breaks
+    Stress {
+        /// Events per second
+        #[arg(long, default_value_t = 500)]
+        rate: u32,
+        /// How long to generate events for, in seconds
+        #[arg(long, default_value_t = 30)]
+        duration: u64,
+        /// Fail if more voices than this ever play at once [default: max_voices from
+        /// the config]
+        #[arg(long)]
+        max_voices: Option<u64>,
+        /// Theme to play with instead of the configured one
+        #[arg(long)]
+        theme: Option<String>,
+    },
+    /// Control a running keydio: mute, unmute, toggle-mute, set-theme <name>,
+    /// volume <0-100>, status, stats
+    Ctl {
+        #[arg(required = true, trailing_var_arg = true)]
+        command: Vec<String>,
+    },
+    /// List the installed themes and the gain each plays at
+    Themes,
+    /// Check that themes load and every sample decodes
+    Validate {
+        /// Themes to check; all installed themes by default
+        themes: Vec<String>,
+    },
+    /// Print key events and whether each would be silenced, without playing anything
+    Keys,
+    /// Play one sound of a theme, with any theme layers under it, and exit
+    #[command(alias = "preview")]
+    Play {
+        /// Sound to play: generic, enter, space, backspace or hold
+        sound: String,
+        /// Theme to play from instead of the configured main theme
+        #[arg(long)]
+        theme: Option<String>,
+        /// Play the release sound instead of the press sound
+        #[arg(long)]
+        release: bool,
+        /// Which member of the sound's pool to play, counting from 1; random by default
+        #[arg(long, value_name = "N")]
+        variant: Option<usize>,
+        /// Volume (0-100) instead of the configured one
+        #[arg(long, value_parser = clap::value_parser!(u8).range(0..=100))]
+        volume: Option<u8>,
+    },
+    /// Time theme loading, decoding and the playback path; needs no audio device
+    Bench {
+        /// Theme to measure instead of the default one
+        #[arg(long)]
+        theme: Option<String>,
+        /// Synthetic events to run through the playback path
+        #[arg(long, default_value_t = 1000)]
+        events: usize,
+        /// Print the report as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Turn a sound pack from another app into a keydio theme
+    Convert {
+        /// Format of the pack
+        #[arg(long, value_enum)]
+        from: PackFormat,
+        /// Directory of the pack, e.g. the one holding a Mechvibes config.json
+        src: PathBuf,
+        /// Directory to write the theme to; must not exist yet
+        #[arg(long, value_name = "DIR")]
+        out: PathBuf,
+    },
+    /// Validate a theme and pack it into a .keydio archive for sharing
+    Pack {
+        /// Installed theme name or path to a theme directory
+        theme: String,
+        /// Name to record in the theme manifest
+        #[arg(long)]
+        name: Option<String>,
+        /// Author to record in the theme manifest
+        #[arg(long)]
+        author: Option<String>,
+        /// Archive to write; <theme>.keydio in the current directory by default
+        #[arg(long, value_name = "PATH")]
+        out: Option<PathBuf>,
+    },
+    /// Install a .keydio theme archive from a URL, a file or the registry
+    Install {
+        /// URL or path of the archive, or the name of a theme in the registry
+        source: String,
+        /// Expected SHA-256 of the archive; without it a .sha256 file next to
+        /// the archive is used when there is one
+        #[arg(long, value_name = "HEX")]
+        sha256: Option<String>,
+    },
+    /// Search the theme registry by name, author or description
+    Search {
+        /// Text to look for; every theme is listed without it
+        query: Option<String>,
+    },
+    /// Show a theme's registry entry, with its download URL and checksum
+    Info {
+        /// Theme name
+        name: String,
+    },
+    /// Remove a theme installed with `keydio install`
+    Uninstall {
+        /// Theme name
+        theme: String,
+    },
+    /// Remove keydio's cache and crash log, listing them and their sizes first
+    Clean {
+        /// Only the cache of decoded samples and the registry index
+        #[arg(long)]
+        cache: bool,
+        /// Only the crash log
+        #[arg(long)]
+        state: bool,
+        /// The cache, the crash log and every installed theme
+        #[arg(long)]
+        all: bool,
+        /// Do not ask for confirmation
+        #[arg(short, long)]
+        yes: bool,
+    },
+    /// Bundle the config with its custom themes and key sounds, to move to
+    /// another machine
+    ExportProfile {
+        /// The .tar.gz file to write
+        file: PathBuf,
+    },
+    /// Unpack a bundle from `keydio export-profile` into this machine's
+    /// directories
+    ImportProfile {
+        file: PathBuf,
+        /// Overwrite existing files without asking
+        #[arg(short, long)]
+        yes: bool,
+    },
+    /// Replace this keydio with the latest release from GitHub
+    SelfUpdate {
+        /// Only say whether a newer release exists
+        #[arg(long)]
+        check: bool,
+    },
+    /// Start keydio automatically when you log in
+    Autostart {
+        #[command(subcommand)]
+        action: AutostartAction,
+    },
+    /// Manage the on-disk cache of decoded samples
+    Cache {
+        #[command(subcommand)]
+        action: CacheAction,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum CacheAction {
+    /// Delete every cached sample; they are decoded again on the next start
+    Clear,
+}
+
+#[derive(Subcommand)]
+pub enum AutostartAction {
+    /// Register keydio to start at login, with any flags given after `--`
+    Enable {
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        args: Vec<String>,
+    },
+    /// Remove the login entry
+    Disable,
+    /// Show whether keydio starts at login
+    Status,
+}
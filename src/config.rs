@@ -0,0 +1,679 @@
+use crate::{
+    fsutil, layout::Layout, profile::Profile, registry, sample::Format, state::SharedState,
+    KeyPressType, SoundType, DEFAULT_THEME,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::{Duration, SystemTime},
+};
+
+const CONFIG_FILE: &str = "config.toml";
+pub const DEFAULT_OUTPUT: &str = "default";
+const WATCH_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PlaybackMode {
+    #[default]
+    Both,
+    Press,
+    Release,
+}
+
+impl PlaybackMode {
+    pub fn plays(&self, keypress: &KeyPressType) -> bool {
+        match self {
+            PlaybackMode::Both => true,
+            PlaybackMode::Press => *keypress != KeyPressType::Release,
+            PlaybackMode::Release => *keypress == KeyPressType::Release,
+        }
+    }
+}
+
+// What plays the samples: rodio's mixer, or keydio's own on a cpal stream
+// with a buffer of `buffer_frames`, for less lag.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AudioBackend {
+    #[default]
+    Rodio,
+    Cpal,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SoundVolumes {
+    pub backspace: f32,
+    pub enter: f32,
+    pub generic: f32,
+    pub space: f32,
+}
+
+impl Default for SoundVolumes {
+    fn default() -> Self {
+        Self {
+            backspace: 1.0,
+            enter: 1.0,
+            generic: 1.0,
+            space: 1.0,
+        }
+    }
+}
+
+impl SoundVolumes {
+    pub fn get(&self, sound_type: &SoundType) -> f32 {
+        match sound_type {
+            SoundType::Backspace => self.backspace,
+            SoundType::Enter => self.enter,
+            SoundType::Generic => self.generic,
+            SoundType::Space => self.space,
+        }
+    }
+}
+
+// Times are local "HH:MM"; a range whose end is before its start runs past
+// midnight. `days` limits the range to the weekdays it starts on.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TimeRange {
+    pub start: String,
+    pub end: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub days: Vec<String>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct QuietHours {
+    pub ranges: Vec<TimeRange>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ThemeRange {
+    #[serde(flatten)]
+    pub range: TimeRange,
+    pub theme: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ThemeSchedule {
+    // When set, a theme picked by hand stays until the next boundary instead
+    // of being switched back to the scheduled one.
+    pub manual_override: bool,
+    pub ranges: Vec<ThemeRange>,
+}
+
+impl Default for ThemeSchedule {
+    fn default() -> Self {
+        Self {
+            manual_override: true,
+            ranges: Vec::new(),
+        }
+    }
+}
+
+// A bonus sound after every `every` keystrokes in a row, for themes with a
+// COMBO.mp3. A pause longer than `pause_ms`, or a Backspace unless that is
+// turned off, starts the count over. It plays at `volume` under the
+// keystrokes.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ComboConfig {
+    pub enabled: bool,
+    pub every: u64,
+    // Play again at every multiple of `every`, not only the first time.
+    pub repeat: bool,
+    pub pause_ms: u64,
+    pub reset_on_backspace: bool,
+    pub volume: f32,
+}
+
+impl Default for ComboConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            every: 50,
+            repeat: true,
+            pause_ms: 2000,
+            reset_on_backspace: true,
+            volume: 0.4,
+        }
+    }
+}
+
+// Keys pressed within `window_ms` of each other form a chord. Its first key
+// plays as usual; the theme's CHORD.mp3 then plays once for the rest, or,
+// without one, they play at `gain`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChordConfig {
+    pub enabled: bool,
+    pub window_ms: u64,
+    pub gain: f32,
+}
+
+impl Default for ChordConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            window_ms: 10,
+            gain: 0.5,
+        }
+    }
+}
+
+// Repeated sound while a key stays down, for themes with a HOLD.mp3.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HoldConfig {
+    pub enabled: bool,
+    // How long a key has to be down before the first hold sound.
+    pub delay_ms: u64,
+    pub interval_ms: u64,
+}
+
+impl Default for HoldConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            delay_ms: 500,
+            interval_ms: 150,
+        }
+    }
+}
+
+// Picks a theme's short or long release variants by how long the key was
+// down: shorter than `threshold_ms` is a tap.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ReleaseConfig {
+    pub threshold_ms: u64,
+}
+
+impl Default for ReleaseConfig {
+    fn default() -> Self {
+        Self { threshold_ms: 150 }
+    }
+}
+
+// Picks a theme's soft or hard sample variants by the time since the previous
+// press: faster than `threshold_ms` sounds hard.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct VelocityConfig {
+    pub enabled: bool,
+    pub threshold_ms: u64,
+    // Width of the band around the threshold where the choice is random.
+    pub crossover_ms: u64,
+}
+
+impl Default for VelocityConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            threshold_ms: 150,
+            crossover_ms: 60,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scale {
+    #[default]
+    Major,
+    Minor,
+    Pentatonic,
+    Chromatic,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Waveform {
+    #[default]
+    Sine,
+    Square,
+}
+
+// The built-in `musical` theme: every key plays a note of `scale`, the home
+// row starting at `root_hz` and the rows above and below it an octave apart.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MusicalConfig {
+    pub scale: Scale,
+    pub waveform: Waveform,
+    pub root_hz: f32,
+    pub note_ms: u64,
+    // Releases play a short, damped version of the note.
+    pub release: bool,
+    // A sample to pitch instead of generating a tone; it plays the root.
+    pub sample: Option<PathBuf>,
+    // Semitones from the root by key name, replacing the row layout.
+    pub keys: BTreeMap<String, i32>,
+}
+
+impl Default for MusicalConfig {
+    fn default() -> Self {
+        Self {
+            scale: Scale::default(),
+            waveform: Waveform::default(),
+            root_hz: 261.63,
+            note_ms: 300,
+            release: true,
+            sample: None,
+            keys: BTreeMap::new(),
+        }
+    }
+}
+
+// How theme samples are held. Mapped files are paged in by the OS as they
+// play instead of being read onto the heap; samples of the hot sound types
+// are decoded once at load, so they play without decoding.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MemoryConfig {
+    pub mmap: bool,
+    pub hot: Vec<SoundType>,
+}
+
+// The format every sample is converted to at load, whatever the theme
+// stored, so mixed themes play at the right pitch on every channel.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EngineConfig {
+    pub channels: u16,
+    pub sample_rate: u32,
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        Self {
+            channels: 2,
+            sample_rate: 48_000,
+        }
+    }
+}
+
+impl EngineConfig {
+    pub fn format(&self) -> Format {
+        Format {
+            channels: self.channels,
+            sample_rate: self.sample_rate,
+        }
+    }
+}
+
+// One entry of `outputs`: a device by name, or "default" for the system's
+// default output, played at its own gain under the main volume.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OutputConfig {
+    pub device: String,
+    pub volume: f32,
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        Self {
+            device: DEFAULT_OUTPUT.to_string(),
+            volume: 1.0,
+        }
+    }
+}
+
+// A second output for streaming, opened by device name at startup. Ignored
+// once `outputs` lists any.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct VirtualOutput {
+    pub device: Option<String>,
+    pub volume: f32,
+    // Keep playing to the normal output as well.
+    pub monitor: bool,
+}
+
+impl Default for VirtualOutput {
+    fn default() -> Self {
+        Self {
+            device: None,
+            volume: 1.0,
+            monitor: true,
+        }
+    }
+}
+
+// A theme played underneath the main one, at its own gain. Written as
+// "name" or "name:gain" in the `theme` list.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Layer {
+    pub theme: String,
+    pub gain: f32,
+}
+
+impl Layer {
+    fn parse(spec: &str) -> Result<Self> {
+        let (theme, gain) = match spec.rsplit_once(':') {
+            Some((theme, gain)) => {
+                let gain: f32 = gain
+                    .trim()
+                    .parse()
+                    .with_context(|| format!("Invalid gain in theme layer {}", spec))?;
+                if !gain.is_finite() || gain < 0.0 {
+                    bail!("Invalid gain in theme layer {}", spec);
+                }
+                (theme, gain)
+            }
+            None => (spec, 1.0),
+        };
+        Ok(Self {
+            theme: theme.trim().to_string(),
+            gain,
+        })
+    }
+
+    fn spec(&self) -> String {
+        if self.gain == 1.0 {
+            self.theme.clone()
+        } else {
+            format!("{}:{}", self.theme, self.gain)
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum ThemeField<'a> {
+    Single(&'a str),
+    Layers(Vec<String>),
+}
+
+// How `Config` is written: `theme` first, as a name or a list of layers.
+#[derive(Serialize)]
+struct ConfigFile<'a> {
+    theme: ThemeField<'a>,
+    #[serde(flatten)]
+    config: &'a Config,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    // The main theme. Tray, schedule and `set-theme` switch this one. Written
+    // by `save`, together with the layers.
+    #[serde(skip_serializing)]
+    pub theme: String,
+    // Further themes from `theme = ["main", "under:0.3"]`, each playing its
+    // own sample for every event.
+    #[serde(skip)]
+    pub layers: Vec<Layer>,
+    // Reload sample files of the main theme as they change on disk, for
+    // theme authors.
+    pub watch_theme: bool,
+    pub volume: f32,
+    // Brings every theme to about the same loudness, under `volume`.
+    pub auto_gain: bool,
+    pub sound_volumes: SoundVolumes,
+    pub mode: PlaybackMode,
+    pub ignore: Vec<String>,
+    // Sound files by key name, played instead of the theme's sound for those
+    // keys; `Key:press` or `Key:release` replaces just one direction.
+    pub key_sounds: BTreeMap<String, String>,
+    // How `physical:` and `char:` key names translate; `auto` asks the
+    // system.
+    pub layout: Layout,
+    pub notifications: bool,
+    pub respect_dnd: bool,
+    pub mute_on_mic: bool,
+    pub mute_on_fullscreen: bool,
+    // Fullscreen apps that still get sounds: X11 window classes, executable
+    // names on Windows, application names on macOS.
+    pub fullscreen_allow: Vec<String>,
+    pub mute_on_screenshare: bool,
+    pub profile: Profile,
+    pub hold: HoldConfig,
+    pub combo: ComboConfig,
+    pub chord: ChordConfig,
+    pub velocity: VelocityConfig,
+    pub release: ReleaseConfig,
+    pub musical: MusicalConfig,
+    pub memory: MemoryConfig,
+    pub engine: EngineConfig,
+    // Most voices playing at once, counting one for every layer on every
+    // output. Sounds past it are not played.
+    pub max_voices: usize,
+    pub audio_backend: AudioBackend,
+    // Frames per buffer of the cpal backend; unset leaves it to the device.
+    pub buffer_frames: Option<u32>,
+    pub outputs: Vec<OutputConfig>,
+    pub virtual_output: VirtualOutput,
+    pub quiet_hours: QuietHours,
+    pub theme_schedule: ThemeSchedule,
+    // The theme index `search`, `info` and `install <name>` use.
+    pub registry: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            theme: DEFAULT_THEME.to_string(),
+            layers: Vec::new(),
+            watch_theme: false,
+            volume: 1.0,
+            auto_gain: true,
+            sound_volumes: SoundVolumes::default(),
+            mode: PlaybackMode::default(),
+            ignore: Vec::new(),
+            key_sounds: BTreeMap::new(),
+            layout: Layout::default(),
+            notifications: true,
+            respect_dnd: false,
+            mute_on_mic: false,
+            mute_on_fullscreen: false,
+            fullscreen_allow: Vec::new(),
+            mute_on_screenshare: false,
+            profile: Profile::default(),
+            hold: HoldConfig::default(),
+            combo: ComboConfig::default(),
+            chord: ChordConfig::default(),
+            velocity: VelocityConfig::default(),
+            release: ReleaseConfig::default(),
+            musical: MusicalConfig::default(),
+            memory: MemoryConfig::default(),
+            engine: EngineConfig::default(),
+            max_voices: 48,
+            audio_backend: AudioBackend::default(),
+            buffer_frames: None,
+            outputs: Vec::new(),
+            virtual_output: VirtualOutput::default(),
+            quiet_hours: QuietHours::default(),
+            theme_schedule: ThemeSchedule::default(),
+            registry: registry::DEFAULT_REGISTRY.to_string(),
+        }
+    }
+}
+
+impl Config {
+    pub fn path() -> Result<PathBuf> {
+        let dir = dirs::config_dir().ok_or_else(|| anyhow!("No config directory found"))?;
+        Ok(dir.join("keydio").join(CONFIG_FILE))
+    }
+
+    // A missing file is not an error, it just means nothing was customized yet.
+    pub fn load(path: &Path) -> Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(contents) => Self::parse(&contents)
+                .with_context(|| format!("Invalid config file {}", path.display())),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err).with_context(|| format!("Failed to read {}", path.display())),
+        }
+    }
+
+    // `theme` is a name or a list of layers; the first layer becomes the
+    // main theme, which plays at the regular volume.
+    pub fn parse(contents: &str) -> Result<Self> {
+        let mut table: toml::Table = toml::from_str(contents)?;
+        let mut layers = Vec::new();
+        if let Some(toml::Value::Array(specs)) = table.get("theme") {
+            let mut specs = specs.iter().map(|spec| match spec.as_str() {
+                Some(spec) => Layer::parse(spec),
+                None => bail!("theme layers must be strings"),
+            });
+            let main = specs
+                .next()
+                .ok_or_else(|| anyhow!("theme lists no themes"))??;
+            if main.gain != 1.0 {
+                bail!(
+                    "The first theme plays at the main volume; give a gain to the layers below it"
+                );
+            }
+            layers = specs.collect::<Result<_>>()?;
+            table.insert("theme".to_string(), toml::Value::String(main.theme));
+        }
+        let mut config: Self = table.try_into()?;
+        config.layers = layers;
+        Ok(config)
+    }
+
+    // Writes only the settings that differ from what the file holds, into
+    // the file as it is, so settings the user left out keep following the
+    // defaults.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(err) => {
+                return Err(err).with_context(|| format!("Failed to read {}", path.display()))
+            }
+        };
+        let contents = self
+            .edit(&contents)
+            .with_context(|| format!("Invalid config file {}", path.display()))?;
+        fsutil::write_atomic(path, contents.as_bytes())
+    }
+
+    // `contents` with the keys this config changes from the one it holds.
+    fn edit(&self, contents: &str) -> Result<String> {
+        let mut document: toml::Table = toml::from_str(contents)?;
+        let old = Self::parse(contents)?.to_table()?;
+        merge(&mut document, &old, &self.to_table()?);
+        Ok(toml::to_string_pretty(&document)?)
+    }
+
+    fn to_table(&self) -> Result<toml::Table> {
+        let theme = if self.layers.is_empty() {
+            ThemeField::Single(&self.theme)
+        } else {
+            ThemeField::Layers(
+                std::iter::once(self.theme.clone())
+                    .chain(self.layers.iter().map(Layer::spec))
+                    .collect(),
+            )
+        };
+        let file = ConfigFile {
+            theme,
+            config: self,
+        };
+        Ok(toml::Table::try_from(&file)?)
+    }
+
+    // The main theme and the layers under it, with their gains.
+    pub fn themes(&self) -> Vec<(String, f32)> {
+        std::iter::once((self.theme.clone(), 1.0))
+            .chain(
+                self.layers
+                    .iter()
+                    .map(|layer| (layer.theme.clone(), layer.gain)),
+            )
+            .collect()
+    }
+}
+
+// Brings `document` from `old` to `new` one key at a time. Keys that did not
+// change stay as the document has them, or missing; tables are merged key by
+// key, anything else is replaced whole.
+fn merge(document: &mut toml::Table, old: &toml::Table, new: &toml::Table) {
+    for key in old.keys().filter(|key| !new.contains_key(*key)) {
+        document.remove(key);
+    }
+    for (key, value) in new {
+        if old.get(key) == Some(value) {
+            continue;
+        }
+        if let (Some(toml::Value::Table(old)), toml::Value::Table(new)) = (old.get(key), value) {
+            let entry = document
+                .entry(key.clone())
+                .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+            if let toml::Value::Table(table) = entry {
+                merge(table, old, new);
+                continue;
+            }
+        }
+        document.insert(key.clone(), value.clone());
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+// Keeps the config file and the running engine in sync: edits made to the
+// file (by hand or from the settings window) are applied live, and changes
+// made at runtime (tray, hotkeys) are written back so they survive a restart.
+pub fn watch(state: Arc<SharedState>, path: PathBuf) {
+    let interval = state.poll_interval("config", WATCH_INTERVAL);
+    thread::spawn(move || {
+        let mut applied = state.stored_config();
+        let mut mtime = modified(&path);
+        loop {
+            thread::sleep(interval);
+            let current = modified(&path);
+            if current != mtime {
+                mtime = current;
+                match Config::load(&path) {
+                    Ok(config) => {
+                        state.update(config.clone());
+                        applied = config;
+                    }
+                    Err(err) => eprintln!("Ignoring config change: {:?}", err),
+                }
+                continue;
+            }
+            let config = state.stored_config();
+            if config != applied {
+                match config.save(&path) {
+                    Ok(()) => mtime = modified(&path),
+                    Err(err) => eprintln!("Failed to save config: {:?}", err),
+                }
+                applied = config;
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saving_writes_only_the_changed_keys() {
+        let contents =
+            "theme = \"cherry\"\nnotifications = false\n\n[engine]\nsample_rate = 48000\n";
+        let mut config = Config::parse(contents).unwrap();
+        config.volume = 0.5;
+        config.engine.channels = 1;
+        let saved: toml::Table = toml::from_str(&config.edit(contents).unwrap()).unwrap();
+        let expected: toml::Table = toml::from_str(
+            "theme = \"cherry\"\nnotifications = false\nvolume = 0.5\n\n[engine]\nsample_rate = 48000\nchannels = 1\n",
+        )
+        .unwrap();
+        assert_eq!(saved, expected);
+        // Nothing to write for a config that is all defaults.
+        assert_eq!(Config::default().edit("").unwrap(), "");
+    }
+}
@@ -0,0 +1,120 @@
+use crate::{
+    available_themes,
+    metrics::Runtime,
+    state::SharedState,
+    stats::{Snapshot, Stats},
+};
+use anyhow::{bail, Result};
+use serde::Serialize;
+use std::{fmt, str::FromStr, sync::Arc};
+
+// Runtime commands understood by every remote control front end.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Request {
+    Mute,
+    Unmute,
+    ToggleMute,
+    SetTheme(String),
+    Volume(u8),
+    Status,
+    Stats,
+}
+
+impl FromStr for Request {
+    type Err = anyhow::Error;
+
+    fn from_str(line: &str) -> Result<Self> {
+        let words: Vec<_> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["mute"] => Ok(Request::Mute),
+            ["unmute"] => Ok(Request::Unmute),
+            ["toggle-mute"] => Ok(Request::ToggleMute),
+            ["set-theme", theme] => Ok(Request::SetTheme(theme.to_string())),
+            ["volume", percent] => match percent.parse::<u8>() {
+                Ok(percent) if percent <= 100 => Ok(Request::Volume(percent)),
+                _ => bail!("volume must be a number from 0 to 100"),
+            },
+            ["status"] => Ok(Request::Status),
+            ["stats"] => Ok(Request::Stats),
+            [] => bail!("empty command"),
+            _ => bail!("unknown command: {}", line.trim()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Status {
+    pub theme: String,
+    pub volume: u8,
+    pub muted: bool,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "theme={} volume={} muted={}",
+            self.theme, self.volume, self.muted
+        )
+    }
+}
+
+// Reply to `stats`: the runtime counters, plus typing statistics when they
+// are kept.
+#[derive(Serialize)]
+struct StatsReply {
+    runtime: Runtime,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    typing: Option<Snapshot>,
+}
+
+pub struct Controller {
+    state: Arc<SharedState>,
+    stats: Option<Arc<Stats>>,
+}
+
+impl Controller {
+    pub fn new(state: Arc<SharedState>, stats: Option<Arc<Stats>>) -> Self {
+        Self { state, stats }
+    }
+
+    pub fn status(&self) -> Status {
+        let config = self.state.config();
+        Status {
+            theme: config.theme,
+            volume: (config.volume * 100.0).round() as u8,
+            muted: self.state.is_muted(),
+        }
+    }
+
+    pub fn execute(&self, request: Request) -> Result<String> {
+        match request {
+            Request::Mute => self.state.set_muted(true),
+            Request::Unmute => self.state.set_muted(false),
+            Request::ToggleMute => {
+                self.state.toggle_mute();
+            }
+            Request::SetTheme(theme) => {
+                let themes = available_themes();
+                if !themes.contains(&theme) {
+                    bail!(
+                        "unknown theme {}, installed themes: {}",
+                        theme,
+                        themes.join(", ")
+                    );
+                }
+                self.state.set_theme(&theme);
+            }
+            Request::Volume(percent) => self.state.set_volume(f32::from(percent) / 100.0),
+            Request::Status => {}
+            Request::Stats => {
+                let reply = StatsReply {
+                    runtime: self.state.metrics().snapshot(),
+                    typing: self.stats.as_ref().map(|stats| stats.snapshot()),
+                };
+                return Ok(serde_json::to_string(&reply)?);
+            }
+        }
+        Ok(self.status().to_string())
+    }
+}
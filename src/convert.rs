@@ -0,0 +1,320 @@
+use crate::{
+    manifest::{self, Manifest, PoolEntry, MANIFEST_FILE},
+    map_key_to_sound, SoundType,
+};
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use device_query::Keycode;
+use rodio::{Decoder, Source};
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs,
+    io::BufReader,
+    path::Path,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum PackFormat {
+    Mechvibes,
+}
+
+// Where a Mechvibes pack keeps the sound of one key: a slice of the shared
+// sprite (`[start_ms, duration_ms]`) or a file of its own. Unused keys are
+// often listed as `null`.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Define {
+    Slice([f64; 2]),
+    File(String),
+}
+
+// A Mechvibes pack's `config.json`.
+#[derive(Deserialize)]
+struct MechvibesPack {
+    name: String,
+    #[serde(default)]
+    author: Option<String>,
+    #[serde(default)]
+    sound: String,
+    defines: BTreeMap<String, Option<Define>>,
+}
+
+// Mechvibes names keys by their scancode (as iohook reports it).
+fn keycode(code: u32) -> Option<Keycode> {
+    use Keycode::*;
+    Some(match code {
+        1 => Escape,
+        2 => Key1,
+        3 => Key2,
+        4 => Key3,
+        5 => Key4,
+        6 => Key5,
+        7 => Key6,
+        8 => Key7,
+        9 => Key8,
+        10 => Key9,
+        11 => Key0,
+        12 => Minus,
+        13 => Equal,
+        14 => Backspace,
+        15 => Tab,
+        16 => Q,
+        17 => W,
+        18 => E,
+        19 => R,
+        20 => T,
+        21 => Y,
+        22 => U,
+        23 => I,
+        24 => O,
+        25 => P,
+        26 => LeftBracket,
+        27 => RightBracket,
+        28 => Enter,
+        29 => LControl,
+        30 => A,
+        31 => S,
+        32 => D,
+        33 => F,
+        34 => G,
+        35 => H,
+        36 => J,
+        37 => K,
+        38 => L,
+        39 => Semicolon,
+        40 => Apostrophe,
+        41 => Grave,
+        42 => LShift,
+        43 => BackSlash,
+        44 => Z,
+        45 => X,
+        46 => C,
+        47 => V,
+        48 => B,
+        49 => N,
+        50 => M,
+        51 => Comma,
+        52 => Dot,
+        53 => Slash,
+        54 => RShift,
+        55 => NumpadMultiply,
+        56 => LAlt,
+        57 => Space,
+        58 => CapsLock,
+        59 => F1,
+        60 => F2,
+        61 => F3,
+        62 => F4,
+        63 => F5,
+        64 => F6,
+        65 => F7,
+        66 => F8,
+        67 => F9,
+        68 => F10,
+        71 => Numpad7,
+        72 => Numpad8,
+        73 => Numpad9,
+        74 => NumpadSubtract,
+        75 => Numpad4,
+        76 => Numpad5,
+        77 => Numpad6,
+        78 => NumpadAdd,
+        79 => Numpad1,
+        80 => Numpad2,
+        81 => Numpad3,
+        82 => Numpad0,
+        83 => NumpadDecimal,
+        87 => F11,
+        88 => F12,
+        91 => F13,
+        92 => F14,
+        93 => F15,
+        99 => F16,
+        100 => F17,
+        101 => F18,
+        102 => F19,
+        103 => F20,
+        3597 => NumpadEquals,
+        3612 => NumpadEnter,
+        3613 => RControl,
+        3637 => NumpadDivide,
+        3640 => RAlt,
+        3655 | 60999 => Home,
+        3657 | 61001 => PageUp,
+        3663 | 61007 => End,
+        3665 | 61009 => PageDown,
+        3666 | 61010 => Insert,
+        3667 | 61011 => Delete,
+        3675 => LMeta,
+        3676 => RMeta,
+        57416 | 61000 => Up,
+        57419 | 61003 => Left,
+        57421 | 61005 => Right,
+        57424 | 61008 => Down,
+        _ => return None,
+    })
+}
+
+struct Audio {
+    channels: u16,
+    sample_rate: u32,
+    samples: Vec<i16>,
+}
+
+fn decode(path: &Path) -> Result<Audio> {
+    let file =
+        fs::File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let decoder = Decoder::new(BufReader::new(file))
+        .with_context(|| format!("Failed to decode {}", path.display()))?;
+    Ok(Audio {
+        channels: decoder.channels(),
+        sample_rate: decoder.sample_rate(),
+        samples: decoder.collect(),
+    })
+}
+
+fn write_wav(path: &Path, channels: u16, sample_rate: u32, samples: &[i16]) -> Result<()> {
+    let spec = hound::WavSpec {
+        channels,
+        sample_rate,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+    let mut writer = hound::WavWriter::create(path, spec)
+        .with_context(|| format!("Failed to create {}", path.display()))?;
+    for sample in samples {
+        writer.write_sample(*sample)?;
+    }
+    writer.finalize()?;
+    Ok(())
+}
+
+// The sprite samples from `start_ms` on, for `duration_ms`, clamped to the
+// end of the sprite.
+fn slice(audio: &Audio, start_ms: f64, duration_ms: f64) -> Option<&[i16]> {
+    let frame = |ms: f64| (ms.max(0.0) / 1000.0 * audio.sample_rate as f64) as usize;
+    let channels = audio.channels as usize;
+    let start = (frame(start_ms) * channels).min(audio.samples.len());
+    let end = (frame(start_ms + duration_ms) * channels).min(audio.samples.len());
+    (end > start).then(|| &audio.samples[start..end])
+}
+
+// Converts a Mechvibes pack into a keydio theme: every distinct sound becomes
+// a WAV file in `press`, pooled under the sound type of the keys using it
+// and weighted by how many keys do.
+fn convert_mechvibes(src: &Path, out: &Path) -> Result<()> {
+    let config_path = src.join("config.json");
+    let contents = fs::read_to_string(&config_path)
+        .with_context(|| format!("Failed to read {}", config_path.display()))?;
+    let pack: MechvibesPack = serde_json::from_str(&contents)
+        .with_context(|| format!("Invalid Mechvibes pack {}", config_path.display()))?;
+    let sprite = match pack.sound.as_str() {
+        "" => None,
+        sound => Some(decode(&src.join(sound))?),
+    };
+
+    let press = out.join("press");
+    fs::create_dir_all(&press).with_context(|| format!("Failed to create {}", press.display()))?;
+    let mut manifest = Manifest {
+        name: Some(pack.name.clone()),
+        author: pack.author.clone(),
+        ..Manifest::default()
+    };
+    // Output file per distinct sound, and how often each sound type uses it.
+    let mut files: HashMap<String, String> = HashMap::new();
+    let mut names = HashSet::new();
+    let mut uses: Vec<(SoundType, String, u32)> = Vec::new();
+    let mut unknown = Vec::new();
+    for (code, define) in &pack.defines {
+        let Some(define) = define else {
+            continue;
+        };
+        let Some(key) = code.parse().ok().and_then(keycode) else {
+            unknown.push(code.clone());
+            continue;
+        };
+        let source = match define {
+            Define::Slice([start, duration]) => format!("{}+{}", start, duration),
+            Define::File(file) => file.clone(),
+        };
+        if !files.contains_key(&source) {
+            // Two scancodes can stand for the same key.
+            let name = (1..)
+                .map(|n| match n {
+                    1 => format!("{}.wav", key),
+                    n => format!("{}_{}.wav", key, n),
+                })
+                .find(|name| !names.contains(name))
+                .unwrap();
+            let path = press.join(&name);
+            match define {
+                Define::Slice([start, duration]) => {
+                    let Some(audio) = &sprite else {
+                        bail!("Key {} uses a slice, but the pack has no sound file", code);
+                    };
+                    let Some(samples) = slice(audio, *start, *duration) else {
+                        bail!("Key {} slices past the end of {}", code, pack.sound);
+                    };
+                    write_wav(&path, audio.channels, audio.sample_rate, samples)?;
+                }
+                Define::File(file) => {
+                    let audio = decode(&src.join(file))?;
+                    write_wav(&path, audio.channels, audio.sample_rate, &audio.samples)?;
+                }
+            }
+            names.insert(name.clone());
+            files.insert(source.clone(), name);
+        }
+        let sound_type = map_key_to_sound(&key);
+        let file = &files[&source];
+        match uses
+            .iter_mut()
+            .find(|(used_by, used, _)| *used_by == sound_type && used == file)
+        {
+            Some((_, _, count)) => *count += 1,
+            None => uses.push((sound_type, file.clone(), 1)),
+        }
+    }
+    if uses.is_empty() {
+        bail!("{} defines no sounds keydio can use", pack.name);
+    }
+    for (sound_type, file, count) in uses {
+        let entry = match count {
+            1 => PoolEntry::File(file),
+            count => PoolEntry::Weighted {
+                file,
+                weight: count as f32,
+            },
+        };
+        manifest
+            .press
+            .get_mut(&sound_type)
+            .get_or_insert_with(Vec::new)
+            .push(entry);
+    }
+    fs::write(out.join(MANIFEST_FILE), toml::to_string(&manifest)?)
+        .with_context(|| format!("Failed to write {}", out.join(MANIFEST_FILE).display()))?;
+
+    if !unknown.is_empty() {
+        eprintln!(
+            "Skipped {} keys keydio does not know: {}",
+            unknown.len(),
+            unknown.join(", ")
+        );
+    }
+    Ok(())
+}
+
+pub fn run(from: PackFormat, src: &Path, out: &Path) -> Result<()> {
+    if out.exists() {
+        bail!("{} already exists", out.display());
+    }
+    match from {
+        PackFormat::Mechvibes => convert_mechvibes(src, out)?,
+    }
+    let samples = manifest::validate(out)
+        .with_context(|| format!("The converted theme in {} is invalid", out.display()))?;
+    println!("Wrote {} ({} samples)", out.display(), samples.len());
+    Ok(())
+}
@@ -0,0 +1,135 @@
+use crate::state::SharedState;
+use anyhow::{bail, Result};
+use std::{
+    any::Any,
+    backtrace::Backtrace,
+    cell::Cell,
+    fs::{self, OpenOptions},
+    io::Write,
+    panic::{self, PanicHookInfo},
+    path::PathBuf,
+    process,
+    sync::{mpsc, Arc, OnceLock},
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime},
+};
+
+// Distinct from both errors (1) and Rust's own panic exit code (101).
+pub const EXIT_CRASHED: i32 = 70;
+// How long a crash waits for the shutdown hooks to save stats and heatmaps.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2);
+
+static STATE: OnceLock<Arc<SharedState>> = OnceLock::new();
+
+thread_local! {
+    static SUPERVISED: Cell<bool> = const { Cell::new(false) };
+}
+
+pub fn log_path() -> Option<PathBuf> {
+    let dir = dirs::state_dir().or_else(dirs::data_local_dir)?;
+    Some(dir.join("keydio").join("keydio.log"))
+}
+
+fn message(payload: &(dyn Any + Send)) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_string())
+}
+
+fn reason(info: &PanicHookInfo) -> String {
+    let message = message(info.payload());
+    match info.location() {
+        Some(location) => format!("{} at {}", message, location),
+        None => message,
+    }
+}
+
+fn append(path: &PathBuf, report: &str) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?
+        .write_all(report.as_bytes())
+}
+
+// For a thread that is restarted when it dies: its panics are reported like
+// any other but leave keydio running.
+pub fn supervised() {
+    SUPERVISED.with(|supervised| supervised.set(true));
+}
+
+// Makes the theme show up in crash reports.
+pub fn set_state(state: &Arc<SharedState>) {
+    let _ = STATE.set(Arc::clone(state));
+}
+
+// A panic on any thread but a supervised one ends keydio: a dead audio
+// thread would otherwise leave a process that runs on but never makes a
+// sound. The report goes to stderr and, with a backtrace, to the log file.
+pub fn install_hook() {
+    panic::set_hook(Box::new(|info| {
+        let thread = thread::current();
+        let name = thread.name().unwrap_or("worker");
+        let reason = reason(info);
+        let theme = STATE
+            .get()
+            .and_then(|state| state.try_theme())
+            .unwrap_or_else(|| "unknown".to_string());
+        let summary = format!(
+            "keydio {} (theme {}): {} thread crashed: {}",
+            env!("CARGO_PKG_VERSION"),
+            theme,
+            name,
+            reason
+        );
+        eprintln!("{}", summary);
+        if let Some(path) = log_path() {
+            let at = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs());
+            let report = format!("[{}] {}\n{}\n", at, summary, Backtrace::force_capture());
+            match append(&path, &report) {
+                Ok(()) => eprintln!("Crash report written to {}", path.display()),
+                Err(err) => eprintln!("Failed to write {}: {}", path.display(), err),
+            }
+        }
+        if !SUPERVISED.with(Cell::get) {
+            shutdown();
+            process::exit(EXIT_CRASHED);
+        }
+    }));
+}
+
+// Runs the shutdown hooks as far as it can before a crash exits. They run on
+// a thread of their own, so a hook that needs a lock the crashed thread
+// still holds cannot keep keydio from exiting.
+fn shutdown() {
+    let Some(state) = STATE.get() else {
+        return;
+    };
+    let state = Arc::clone(state);
+    let (done_tx, done_rx) = mpsc::channel();
+    let spawned = thread::Builder::new()
+        .name("shutdown".to_string())
+        .spawn(move || {
+            state.try_shutdown();
+            let _ = done_tx.send(());
+        });
+    if spawned.is_ok() {
+        let _ = done_rx.recv_timeout(SHUTDOWN_TIMEOUT);
+    }
+}
+
+// Waits for a thread to end. One that panicked is reported as an error
+// rather than panicking the waiting thread too.
+pub fn join(name: &str, handle: JoinHandle<()>) -> Result<()> {
+    if let Err(payload) = handle.join() {
+        bail!("{} thread crashed: {}", name, message(payload.as_ref()));
+    }
+    Ok(())
+}
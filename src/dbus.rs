@@ -0,0 +1,111 @@
+use crate::{
+    control::{Controller, Request, Status},
+    state::SharedState,
+};
+use anyhow::Result;
+use std::{collections::HashMap, sync::Arc, thread, time::Duration};
+use zbus::{
+    blocking::connection,
+    fdo,
+    object_server::SignalEmitter,
+    zvariant::{OwnedValue, Str},
+};
+
+const BUS_NAME: &str = "io.keydio.Control";
+const OBJECT_PATH: &str = "/io/keydio/Control";
+const WATCH_INTERVAL: Duration = Duration::from_millis(250);
+
+struct ControlInterface {
+    controller: Arc<Controller>,
+}
+
+impl ControlInterface {
+    fn run(&self, request: Request) -> fdo::Result<()> {
+        self.controller
+            .execute(request)
+            .map(drop)
+            .map_err(|err| fdo::Error::Failed(err.to_string()))
+    }
+}
+
+fn status_map(status: &Status) -> HashMap<String, OwnedValue> {
+    HashMap::from([
+        (
+            "theme".to_string(),
+            OwnedValue::from(Str::from(status.theme.clone())),
+        ),
+        (
+            "volume".to_string(),
+            OwnedValue::from(f64::from(status.volume)),
+        ),
+        ("muted".to_string(), OwnedValue::from(status.muted)),
+    ])
+}
+
+#[zbus::interface(name = "io.keydio.Control")]
+impl ControlInterface {
+    fn mute(&self) -> fdo::Result<()> {
+        self.run(Request::Mute)
+    }
+
+    fn unmute(&self) -> fdo::Result<()> {
+        self.run(Request::Unmute)
+    }
+
+    fn set_theme(&self, theme: String) -> fdo::Result<()> {
+        self.run(Request::SetTheme(theme))
+    }
+
+    // Percent, like `keydio ctl volume`.
+    fn set_volume(&self, volume: f64) -> fdo::Result<()> {
+        self.run(Request::Volume(volume.clamp(0.0, 100.0).round() as u8))
+    }
+
+    fn get_status(&self) -> HashMap<String, OwnedValue> {
+        status_map(&self.controller.status())
+    }
+
+    #[zbus(signal)]
+    async fn state_changed(
+        emitter: &SignalEmitter<'_>,
+        status: HashMap<String, OwnedValue>,
+    ) -> zbus::Result<()>;
+}
+
+fn run(controller: Arc<Controller>, interval: Duration) -> Result<()> {
+    let interface = ControlInterface {
+        controller: Arc::clone(&controller),
+    };
+    let connection = connection::Builder::session()?
+        .name(BUS_NAME)?
+        .serve_at(OBJECT_PATH, interface)?
+        .build()?;
+    let interface = connection
+        .object_server()
+        .interface::<_, ControlInterface>(OBJECT_PATH)?;
+    // State can change from any front end, so watch it rather than only
+    // signalling for calls that came in over the bus.
+    let mut last = controller.status();
+    loop {
+        thread::sleep(interval);
+        let status = controller.status();
+        if status != last {
+            zbus::block_on(ControlInterface::state_changed(
+                interface.signal_emitter(),
+                status_map(&status),
+            ))?;
+            last = status;
+        }
+    }
+}
+
+// Registers the session bus service on its own thread. A missing session bus
+// or a taken name only costs the D-Bus interface, never playback.
+pub fn serve(state: &SharedState, controller: Arc<Controller>) {
+    let interval = state.poll_interval("dbus", WATCH_INTERVAL);
+    thread::spawn(move || {
+        if let Err(err) = run(controller, interval) {
+            eprintln!("D-Bus service unavailable: {:?}", err);
+        }
+    });
+}
@@ -0,0 +1,89 @@
+use crate::{
+    automute::{Condition, POLL_INTERVAL},
+    state::MuteReason,
+};
+use std::time::Duration;
+
+const SETTLE: Duration = Duration::from_secs(5);
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use anyhow::{bail, Context, Result};
+    use std::process::Command;
+
+    // GNOME implements Do Not Disturb by turning notification banners off.
+    pub fn is_active() -> Result<bool> {
+        let output = Command::new("gsettings")
+            .args(["get", "org.gnome.desktop.notifications", "show-banners"])
+            .output()
+            .context("Failed to run gsettings")?;
+        if !output.status.success() {
+            bail!("{}", String::from_utf8_lossy(&output.stderr).trim());
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).trim() == "false")
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use anyhow::{anyhow, Context, Result};
+    use std::fs;
+
+    // Focus keeps its active assertions in this file; any record means a
+    // Focus mode is on.
+    pub fn is_active() -> Result<bool> {
+        let path = dirs::home_dir()
+            .ok_or_else(|| anyhow!("No home directory found"))?
+            .join("Library/DoNotDisturb/DB/Assertions.json");
+        let contents =
+            fs::read(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+        let assertions: serde_json::Value = serde_json::from_slice(&contents)?;
+        Ok(assertions["data"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| entry["storeAssertionRecords"].as_array())
+            .any(|records| !records.is_empty()))
+    }
+}
+
+#[cfg(target_os = "windows")]
+mod platform {
+    use anyhow::{bail, Result};
+    use windows_sys::Win32::UI::Shell::{SHQueryUserNotificationState, QUNS_QUIET_TIME};
+
+    // Focus Assist shows up as the shell's quiet time. The busy, fullscreen
+    // and presentation states only mean some app is fullscreen, which is
+    // `mute_on_fullscreen`'s business.
+    pub fn is_active() -> Result<bool> {
+        let mut state = 0;
+        let result = unsafe { SHQueryUserNotificationState(&mut state) };
+        if result != 0 {
+            bail!("SHQueryUserNotificationState failed: {:#x}", result);
+        }
+        Ok(state == QUNS_QUIET_TIME)
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+mod platform {
+    use anyhow::{bail, Result};
+
+    pub fn is_active() -> Result<bool> {
+        bail!("Do Not Disturb detection is not supported on this platform")
+    }
+}
+
+// Mutes while the system Do Not Disturb mode is on, when `respect_dnd` is set.
+// Flicking it on and straight back off never cuts out a word.
+pub fn condition() -> Condition {
+    Condition {
+        name: "Do Not Disturb",
+        reason: MuteReason::Dnd,
+        enabled: |config| config.respect_dnd,
+        detect: |_| platform::is_active(),
+        interval: POLL_INTERVAL,
+        settle_on: SETTLE,
+        settle_off: SETTLE,
+    }
+}
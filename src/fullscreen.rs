@@ -0,0 +1,191 @@
+use crate::{
+    automute::{Condition, POLL_INTERVAL},
+    config::Config,
+    state::MuteReason,
+};
+use anyhow::Result;
+use std::time::Duration;
+
+// Switching in and out of a game for a moment should not flip the mute.
+const SETTLE: Duration = Duration::from_secs(2);
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use anyhow::{bail, Context, Result};
+    use std::process::Command;
+
+    fn xprop(args: &[&str]) -> Result<String> {
+        let output = Command::new("xprop")
+            .args(args)
+            .output()
+            .context("Failed to run xprop")?;
+        if !output.status.success() {
+            bail!("{}", String::from_utf8_lossy(&output.stderr).trim());
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    // The class of the active window when the window manager has it in the
+    // `_NET_WM_STATE_FULLSCREEN` state.
+    pub fn fullscreen_app() -> Result<Option<String>> {
+        let active = xprop(&["-root", "_NET_ACTIVE_WINDOW"])?;
+        let Some(window) = active.rsplit("# ").next().map(str::trim) else {
+            return Ok(None);
+        };
+        if !window.starts_with("0x") || window == "0x0" {
+            return Ok(None);
+        }
+        let properties = xprop(&["-id", window, "_NET_WM_STATE", "WM_CLASS"])?;
+        if !properties.contains("_NET_WM_STATE_FULLSCREEN") {
+            return Ok(None);
+        }
+        // `WM_CLASS(STRING) = "code", "Code"`: instance, then class.
+        let class = properties
+            .lines()
+            .find(|line| line.starts_with("WM_CLASS"))
+            .and_then(|line| line.rsplit('"').nth(1))
+            .unwrap_or("");
+        Ok(Some(class.to_string()))
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use anyhow::{bail, Context, Result};
+    use std::process::Command;
+
+    const SCRIPT: &str = r#"
+tell application "System Events"
+    set frontApp to first application process whose frontmost is true
+    set isFullscreen to false
+    try
+        set isFullscreen to value of attribute "AXFullScreen" of front window of frontApp
+    end try
+    return (name of frontApp) & linefeed & isFullscreen
+end tell"#;
+
+    // The frontmost application when its front window is in full screen. Needs
+    // the accessibility permission keydio already has for reading keys.
+    pub fn fullscreen_app() -> Result<Option<String>> {
+        let output = Command::new("osascript")
+            .args(["-e", SCRIPT])
+            .output()
+            .context("Failed to run osascript")?;
+        if !output.status.success() {
+            bail!("{}", String::from_utf8_lossy(&output.stderr).trim());
+        }
+        let output = String::from_utf8_lossy(&output.stdout);
+        let mut lines = output.lines();
+        let name = lines.next().unwrap_or("").trim();
+        Ok((lines.next().map(str::trim) == Some("true")).then(|| name.to_string()))
+    }
+}
+
+#[cfg(target_os = "windows")]
+mod platform {
+    use anyhow::Result;
+    use std::{mem, path::Path};
+    use windows_sys::Win32::{
+        Foundation::{CloseHandle, HWND, RECT},
+        Graphics::Gdi::{
+            GetMonitorInfoW, MonitorFromWindow, MONITORINFO, MONITOR_DEFAULTTONEAREST,
+        },
+        System::Threading::{
+            OpenProcess, QueryFullProcessImageNameW, PROCESS_NAME_WIN32,
+            PROCESS_QUERY_LIMITED_INFORMATION,
+        },
+        UI::WindowsAndMessaging::{
+            GetDesktopWindow, GetForegroundWindow, GetShellWindow, GetWindowRect,
+            GetWindowThreadProcessId,
+        },
+    };
+
+    // The executable name of the window's process, e.g. `game`.
+    fn process_name(window: HWND) -> String {
+        unsafe {
+            let mut pid = 0;
+            GetWindowThreadProcessId(window, &mut pid);
+            let process = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, pid);
+            if process.is_null() {
+                return String::new();
+            }
+            let mut path = [0u16; 1024];
+            let mut length = path.len() as u32;
+            let ok = QueryFullProcessImageNameW(
+                process,
+                PROCESS_NAME_WIN32,
+                path.as_mut_ptr(),
+                &mut length,
+            );
+            CloseHandle(process);
+            if ok == 0 {
+                return String::new();
+            }
+            let path = String::from_utf16_lossy(&path[..length as usize]);
+            Path::new(&path)
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_default()
+        }
+    }
+
+    // The foreground window's process when the window covers its whole
+    // monitor. The desktop and the shell cover it too, but do not count.
+    pub fn fullscreen_app() -> Result<Option<String>> {
+        unsafe {
+            let window = GetForegroundWindow();
+            if window.is_null() || window == GetDesktopWindow() || window == GetShellWindow() {
+                return Ok(None);
+            }
+            let mut bounds: RECT = mem::zeroed();
+            if GetWindowRect(window, &mut bounds) == 0 {
+                return Ok(None);
+            }
+            let monitor = MonitorFromWindow(window, MONITOR_DEFAULTTONEAREST);
+            let mut info: MONITORINFO = mem::zeroed();
+            info.cbSize = mem::size_of::<MONITORINFO>() as u32;
+            if monitor.is_null() || GetMonitorInfoW(monitor, &mut info) == 0 {
+                return Ok(None);
+            }
+            let screen = info.rcMonitor;
+            let covers = bounds.left <= screen.left
+                && bounds.top <= screen.top
+                && bounds.right >= screen.right
+                && bounds.bottom >= screen.bottom;
+            Ok(covers.then(|| process_name(window)))
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+mod platform {
+    use anyhow::{bail, Result};
+
+    pub fn fullscreen_app() -> Result<Option<String>> {
+        bail!("Fullscreen detection is not supported on this platform")
+    }
+}
+
+// A fullscreen app mutes unless `fullscreen_allow` names it.
+fn detect(config: &Config) -> Result<bool> {
+    Ok(platform::fullscreen_app()?.is_some_and(|app| {
+        !config
+            .fullscreen_allow
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(&app))
+    }))
+}
+
+// Game mode: mutes while a fullscreen application is in front, when
+// `mute_on_fullscreen` is set.
+pub fn condition() -> Condition {
+    Condition {
+        name: "Fullscreen app",
+        reason: MuteReason::Fullscreen,
+        enabled: |config| config.mute_on_fullscreen,
+        detect,
+        interval: POLL_INTERVAL,
+        settle_on: SETTLE,
+        settle_off: SETTLE,
+    }
+}
@@ -0,0 +1,226 @@
+use crate::{
+    available_themes,
+    config::{Config, PlaybackMode},
+    keys,
+    sample::{Pcm, PcmSource},
+    AppState, KeyEvent, KeyPressType,
+};
+use anyhow::{anyhow, bail, Result};
+use device_query::Keycode;
+use eframe::egui;
+use rodio::{OutputStream, OutputStreamHandle, Source};
+use std::{
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+// Sliders report a change on every frame while dragged, so batch the writes.
+const SAVE_INTERVAL: Duration = Duration::from_millis(200);
+
+struct SettingsApp {
+    path: PathBuf,
+    config: Config,
+    // The file as last read or written, to tell which fields the window
+    // changed since.
+    saved: Config,
+    themes: Vec<String>,
+    new_ignore: String,
+    output: Option<(OutputStream, OutputStreamHandle)>,
+    dirty: bool,
+    saved_at: Instant,
+    error: Option<String>,
+}
+
+impl SettingsApp {
+    fn preview(&mut self) -> Result<()> {
+        if self.output.is_none() {
+            self.output = Some(OutputStream::try_default()?);
+        }
+        // Every layer plays its own sample, like a keystroke would.
+        for (index, (name, gain)) in self.config.themes().into_iter().enumerate() {
+            let mut theme = AppState::new(&name);
+            theme.load(&self.config)?;
+            let Some(audio) =
+                theme.sound(&KeyEvent::new(Keycode::A, KeyPressType::Press), None, None)
+            else {
+                if index == 0 {
+                    bail!("Theme {} has no generic sound", name);
+                }
+                continue;
+            };
+            let volume = self.config.volume * self.config.sound_volumes.generic * gain;
+            let pcm = match audio.pcm {
+                Some(pcm) => pcm,
+                None => Pcm::decode(&audio.bytes, self.config.engine.format())?,
+            };
+            let source = PcmSource::new(pcm).amplify(volume);
+            if let Some((_, handle)) = &self.output {
+                handle.play_raw(source)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Writes just the fields the window changed over what the file holds
+    // now, so changes the engine saved meanwhile (tray, hotkeys, `keydio
+    // ctl`) stay.
+    fn save(&mut self) {
+        let saved = Config::load(&self.path).and_then(|mut config| {
+            let (edited, saved) = (&self.config, &self.saved);
+            if edited.theme != saved.theme {
+                config.theme = edited.theme.clone();
+            }
+            if edited.volume != saved.volume {
+                config.volume = edited.volume;
+            }
+            if edited.sound_volumes != saved.sound_volumes {
+                config.sound_volumes = edited.sound_volumes.clone();
+            }
+            if edited.mode != saved.mode {
+                config.mode = edited.mode;
+            }
+            if edited.ignore != saved.ignore {
+                config.ignore = edited.ignore.clone();
+            }
+            config.save(&self.path)?;
+            Ok(config)
+        });
+        match saved {
+            Ok(config) => {
+                self.config = config.clone();
+                self.saved = config;
+                self.dirty = false;
+            }
+            Err(err) => self.error = Some(format!("{:?}", err)),
+        }
+        self.saved_at = Instant::now();
+    }
+}
+
+impl eframe::App for SettingsApp {
+    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        let mut changed = false;
+        egui::CentralPanel::default().show(ctx, |ui| {
+            ui.heading("Theme");
+            ui.horizontal(|ui| {
+                egui::ComboBox::from_id_salt("theme")
+                    .selected_text(&self.config.theme)
+                    .show_ui(ui, |ui| {
+                        for theme in &self.themes {
+                            changed |= ui
+                                .selectable_value(&mut self.config.theme, theme.clone(), theme)
+                                .changed();
+                        }
+                    });
+                if ui.button("Preview").clicked() {
+                    if let Err(err) = self.preview() {
+                        self.error = Some(format!("{:?}", err));
+                    }
+                }
+            });
+
+            ui.separator();
+            ui.heading("Volume");
+            changed |= ui
+                .add(egui::Slider::new(&mut self.config.volume, 0.0..=1.0).text("Master"))
+                .changed();
+            let volumes = &mut self.config.sound_volumes;
+            for (label, volume) in [
+                ("Generic", &mut volumes.generic),
+                ("Space", &mut volumes.space),
+                ("Enter", &mut volumes.enter),
+                ("Backspace", &mut volumes.backspace),
+            ] {
+                changed |= ui
+                    .add(egui::Slider::new(volume, 0.0..=1.0).text(label))
+                    .changed();
+            }
+
+            ui.separator();
+            ui.heading("Play on");
+            ui.horizontal(|ui| {
+                for (mode, label) in [
+                    (PlaybackMode::Both, "Press and release"),
+                    (PlaybackMode::Press, "Press"),
+                    (PlaybackMode::Release, "Release"),
+                ] {
+                    changed |= ui.radio_value(&mut self.config.mode, mode, label).changed();
+                }
+            });
+
+            ui.separator();
+            ui.heading("Ignored keys");
+            let mut removed = None;
+            for (index, key) in self.config.ignore.iter().enumerate() {
+                ui.horizontal(|ui| {
+                    ui.label(key);
+                    if ui.small_button("Remove").clicked() {
+                        removed = Some(index);
+                    }
+                });
+            }
+            if let Some(index) = removed {
+                self.config.ignore.remove(index);
+                changed = true;
+            }
+            ui.horizontal(|ui| {
+                ui.text_edit_singleline(&mut self.new_ignore);
+                if ui.button("Add").clicked() {
+                    let key = self.new_ignore.trim().to_string();
+                    match keys::parse_spec(&key) {
+                        Ok(_) if !self.config.ignore.contains(&key) => {
+                            self.config.ignore.push(key);
+                            self.new_ignore.clear();
+                            changed = true;
+                        }
+                        Ok(_) => self.new_ignore.clear(),
+                        Err(err) => self.error = Some(err.to_string()),
+                    }
+                }
+            });
+
+            if let Some(error) = &self.error {
+                ui.separator();
+                ui.colored_label(egui::Color32::RED, error);
+            }
+        });
+
+        self.dirty |= changed;
+        if self.dirty {
+            if self.saved_at.elapsed() >= SAVE_INTERVAL {
+                self.save();
+            } else {
+                ctx.request_repaint_after(SAVE_INTERVAL);
+            }
+        }
+    }
+
+    fn on_exit(&mut self, _gl: Option<&eframe::glow::Context>) {
+        if self.dirty {
+            self.save();
+        }
+    }
+}
+
+// Opens the settings window. Changes are written to the config file, which the
+// running engine picks up on its own, so this never needs to talk to it directly.
+pub fn run(path: PathBuf) -> Result<()> {
+    let config = Config::load(&path)?;
+    let app = SettingsApp {
+        path,
+        saved: config.clone(),
+        config,
+        themes: available_themes(),
+        new_ignore: String::new(),
+        output: None,
+        dirty: false,
+        saved_at: Instant::now(),
+        error: None,
+    };
+    let options = eframe::NativeOptions {
+        viewport: egui::ViewportBuilder::default().with_inner_size([360.0, 520.0]),
+        ..Default::default()
+    };
+    eframe::run_native("keydio settings", options, Box::new(|_| Ok(Box::new(app))))
+        .map_err(|err| anyhow!("Failed to open settings window: {}", err))
+}
@@ -0,0 +1,100 @@
+use crate::{fsutil::write_atomic, KeyEvent, KeyPressType};
+use anyhow::{anyhow, Context, Result};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{Receiver, RecvTimeoutError},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+// Per-keycode press counts, nothing else: no ordering or timing is kept.
+pub struct Heatmap {
+    path: PathBuf,
+    counts: Mutex<BTreeMap<String, u64>>,
+}
+
+fn is_csv(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("csv"))
+}
+
+fn parse_csv(contents: &str) -> Result<BTreeMap<String, u64>> {
+    contents
+        .lines()
+        .skip(1)
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let (key, count) = line
+                .split_once(',')
+                .ok_or_else(|| anyhow!("Malformed heatmap line: {}", line))?;
+            Ok((key.trim().to_string(), count.trim().parse()?))
+        })
+        .collect()
+}
+
+impl Heatmap {
+    // Counts already in the file are carried over so the map accumulates
+    // across sessions.
+    pub fn open(path: PathBuf) -> Result<Self> {
+        let counts = match fs::read_to_string(&path) {
+            Ok(contents) if is_csv(&path) => parse_csv(&contents),
+            Ok(contents) => serde_json::from_str(&contents).map_err(Into::into),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+            Err(err) => Err(err.into()),
+        }
+        .with_context(|| format!("Failed to read heatmap {}", path.display()))?;
+        Ok(Self {
+            path,
+            counts: Mutex::new(counts),
+        })
+    }
+
+    fn record(&self, event: &KeyEvent) {
+        if let (Some(key), KeyPressType::Press) = (event.key, &event.keypress) {
+            *self
+                .counts
+                .lock()
+                .unwrap()
+                .entry(key.to_string())
+                .or_default() += 1;
+        }
+    }
+
+    pub fn write(&self) -> Result<()> {
+        let counts = self.counts.lock().unwrap().clone();
+        let contents = if is_csv(&self.path) {
+            let mut csv = String::from("key,count\n");
+            for (key, count) in &counts {
+                csv.push_str(&format!("{},{}\n", key, count));
+            }
+            csv
+        } else {
+            serde_json::to_string_pretty(&counts)?
+        };
+        write_atomic(&self.path, contents.as_bytes())
+    }
+}
+
+pub fn spawn(heatmap: Arc<Heatmap>, rx: Receiver<KeyEvent>, interval: Duration) {
+    thread::spawn(move || {
+        let mut next_write = Instant::now() + interval;
+        loop {
+            let timeout = next_write.saturating_duration_since(Instant::now());
+            match rx.recv_timeout(timeout) {
+                Ok(event) => heatmap.record(&event),
+                Err(RecvTimeoutError::Timeout) => {
+                    next_write += interval;
+                    if let Err(err) = heatmap.write() {
+                        eprintln!("{:?}", err);
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+    });
+}
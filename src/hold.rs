@@ -0,0 +1,143 @@
+use crate::{
+    config::ReleaseConfig, dispatch, map_key_to_sound, state::SharedState, KeyEvent, KeyPressType,
+    KeySender,
+};
+use device_query::{DeviceQuery, DeviceState, Keycode};
+use std::{
+    collections::HashMap,
+    sync::{
+        mpsc::{self, RecvTimeoutError, Sender},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+// While keys are held, how often to check that they really still are. A
+// release can go missing, e.g. when focus moves to a lock screen mid-press.
+const RECHECK_INTERVAL: Duration = Duration::from_millis(250);
+// A press without a release for this long is taken to have lost its release
+// and forgotten.
+const STALE_PRESS: Duration = Duration::from_secs(10);
+
+// How long a key was down before its release: the upstroke of a quick tap
+// sounds different from that of a long hold.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Held {
+    Short,
+    Long,
+}
+
+// Lives on the audio thread next to the velocity tracker and, like it, sees
+// every event.
+#[derive(Default)]
+pub struct Presses {
+    // When each key that is down was pressed.
+    pressed: HashMap<Keycode, Instant>,
+}
+
+impl Presses {
+    // For a release, whether its key was down for a short or a long time;
+    // `None` for other events and releases whose press was not seen.
+    pub fn held(&mut self, config: &ReleaseConfig, event: &KeyEvent) -> Option<Held> {
+        let key = event.key?;
+        match event.keypress {
+            KeyPressType::Press => {
+                self.pressed
+                    .retain(|_, at| event.at.saturating_duration_since(*at) < STALE_PRESS);
+                // Keyboard repeat sends more presses; the first one counts.
+                self.pressed.entry(key).or_insert(event.at);
+                None
+            }
+            KeyPressType::Release => {
+                let down = event
+                    .at
+                    .saturating_duration_since(self.pressed.remove(&key)?);
+                Some(if down < Duration::from_millis(config.threshold_ms) {
+                    Held::Short
+                } else {
+                    Held::Long
+                })
+            }
+            KeyPressType::Hold => None,
+        }
+    }
+}
+
+pub enum Edge {
+    Down(Keycode),
+    Up(Keycode),
+}
+
+// Emits a `Hold` event for every key held longer than the configured delay,
+// then again at the configured interval until the key is released. Returns
+// the sender the keyboard hook reports presses and releases to.
+pub fn spawn(state: Arc<SharedState>, senders: Arc<Vec<KeySender>>) -> Sender<Edge> {
+    let (tx, rx) = mpsc::channel();
+    thread::Builder::new()
+        .name("hold".to_string())
+        .spawn(move || {
+            let device_state = DeviceState::new();
+            // When each held key next sounds.
+            let mut held: HashMap<Keycode, Instant> = HashMap::new();
+            let mut rechecked = Instant::now();
+            loop {
+                let edge = match held.values().min() {
+                    Some(next) => {
+                        let wake = (*next).min(rechecked + RECHECK_INTERVAL);
+                        match rx.recv_timeout(wake.saturating_duration_since(Instant::now())) {
+                            Ok(edge) => Some(edge),
+                            Err(RecvTimeoutError::Timeout) => None,
+                            Err(RecvTimeoutError::Disconnected) => break,
+                        }
+                    }
+                    None => match rx.recv() {
+                        Ok(edge) => Some(edge),
+                        Err(_) => break,
+                    },
+                };
+                let config = state.config().hold;
+                match edge {
+                    Some(Edge::Down(key)) if config.enabled => {
+                        if held.is_empty() {
+                            rechecked = Instant::now();
+                        }
+                        held.insert(key, Instant::now() + Duration::from_millis(config.delay_ms));
+                    }
+                    Some(Edge::Down(_)) => {}
+                    Some(Edge::Up(key)) => {
+                        held.remove(&key);
+                    }
+                    None => {
+                        let now = Instant::now();
+                        if !config.enabled {
+                            held.clear();
+                            continue;
+                        }
+                        if now >= rechecked + RECHECK_INTERVAL {
+                            let down = device_state.get_keys();
+                            held.retain(|key, _| down.contains(key));
+                            rechecked = now;
+                        }
+                        let interval = Duration::from_millis(config.interval_ms.max(10));
+                        for (key, next) in held.iter_mut() {
+                            if *next <= now {
+                                *next = now + interval;
+                                dispatch(
+                                    &senders,
+                                    KeyEvent {
+                                        key: Some(*key),
+                                        keypress: KeyPressType::Hold,
+                                        sound_type: map_key_to_sound(key),
+                                        at: now,
+                                    },
+                                );
+                            }
+                        }
+                    }
+                }
+            }
+        })
+        .expect("Failed to start the hold thread");
+    tx
+}
@@ -0,0 +1,232 @@
+use crate::{
+    archive, assets, config::Config, fsutil, manifest, musical, pack, registry, sha256,
+    DEFAULT_THEME,
+};
+use anyhow::{bail, Context, Result};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+fn is_url(source: &str) -> bool {
+    source.starts_with("https://") || source.starts_with("http://")
+}
+
+// Downloads with curl, which every platform keydio runs on ships.
+pub fn download(url: &str) -> Result<Vec<u8>> {
+    let output = Command::new("curl")
+        .args(["--fail", "--silent", "--show-error", "--location", url])
+        .output()
+        .context("Failed to run curl")?;
+    if !output.status.success() {
+        bail!(
+            "Failed to download {}: {}",
+            url,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(output.stdout)
+}
+
+fn fetch(source: &str) -> Result<Vec<u8>> {
+    if is_url(source) {
+        download(source)
+    } else {
+        fs::read(source).with_context(|| format!("Failed to read {}", source))
+    }
+}
+
+fn parse_checksum(text: &str) -> Result<String> {
+    // `sha256sum` writes the hash followed by the file name.
+    let hex = text
+        .split_whitespace()
+        .next()
+        .unwrap_or("")
+        .to_ascii_lowercase();
+    if hex.len() != 64 || !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        bail!("{} is not a SHA-256 checksum", text.trim());
+    }
+    Ok(hex)
+}
+
+// The checksum to verify against: `--sha256`, or a `.sha256` file next to the
+// archive when there is one.
+fn expected_checksum(source: &str, flag: Option<&str>) -> Result<Option<String>> {
+    if let Some(flag) = flag {
+        return parse_checksum(flag).map(Some);
+    }
+    let adjacent = format!("{}.sha256", source);
+    let contents = if is_url(&adjacent) {
+        download(&adjacent).ok()
+    } else {
+        fs::read(&adjacent).ok()
+    };
+    match contents {
+        Some(contents) => parse_checksum(&String::from_utf8_lossy(&contents))
+            .with_context(|| format!("Invalid checksum file {}", adjacent))
+            .map(Some),
+        None => Ok(None),
+    }
+}
+
+// `https://example.com/themes/typewriter.keydio?dl=1` installs as `typewriter`.
+fn theme_name(source: &str) -> Result<String> {
+    let path = source.split(['?', '#']).next().unwrap_or(source);
+    let file = path.rsplit(['/', '\\']).next().unwrap_or(path);
+    let name = file
+        .strip_suffix(&format!(".{}", pack::EXTENSION))
+        .unwrap_or(file);
+    if name.is_empty() || name.starts_with('.') {
+        bail!("Cannot tell the theme name from {}", source);
+    }
+    Ok(name.to_string())
+}
+
+// A bare name that is neither a URL nor a file, like `typewriter`, is looked
+// up in the registry, whose checksum is then required to match.
+fn is_registry_name(source: &str) -> bool {
+    !is_url(source) && !Path::new(source).exists() && !source.contains(['/', '\\', '.'])
+}
+
+// Installs a `.keydio` archive from a URL, a local path or the registry into
+// the assets directory. The archive is hashed before anything is unpacked,
+// and the unpacked theme has to validate before it replaces the staging
+// directory.
+pub fn run(source: &str, sha256: Option<&str>) -> Result<()> {
+    if is_registry_name(source) {
+        let entry = registry::find(source)?;
+        return install(
+            &entry.url,
+            &entry.name,
+            Some(sha256.unwrap_or(&entry.sha256)),
+        );
+    }
+    install(source, &theme_name(source)?, sha256)
+}
+
+fn install(source: &str, name: &str, sha256: Option<&str>) -> Result<()> {
+    let target = assets::install_dir().join(name);
+    if target.exists() {
+        bail!(
+            "Theme {} is already installed in {}",
+            name,
+            target.display()
+        );
+    }
+    let expected = expected_checksum(source, sha256)?;
+    let data = fetch(source)?;
+    let actual = sha256::hex(&data);
+    match expected {
+        Some(expected) if expected != actual => bail!(
+            "Checksum mismatch for {}:\n  expected {}\n  actual   {}\nRefusing to install it",
+            source,
+            expected,
+            actual
+        ),
+        Some(_) => eprintln!("Checksum verified: {}", actual),
+        None => eprintln!(
+            "Notice: no checksum given for {}, its integrity was not verified (sha256 {})",
+            source, actual
+        ),
+    }
+
+    let entries = archive::unzip(&data)
+        .with_context(|| format!("{} is not a valid theme archive", source))?;
+    let staging: PathBuf = assets::install_dir().join(format!(".{}.installing", name));
+    if staging.exists() {
+        fs::remove_dir_all(&staging)?;
+    }
+    let unpacked = (|| -> Result<usize> {
+        for (entry, contents) in &entries {
+            fsutil::write_atomic(&staging.join(entry), contents)?;
+        }
+        Ok(manifest::validate(&staging)?.len())
+    })();
+    let samples = match unpacked {
+        Ok(samples) => samples,
+        Err(err) => {
+            let _ = fs::remove_dir_all(&staging);
+            return Err(err.context(format!("{} failed validation", source)));
+        }
+    };
+    fs::rename(&staging, &target)
+        .with_context(|| format!("Failed to move the theme to {}", target.display()))?;
+    println!(
+        "Installed {} ({} samples) to {}",
+        name,
+        samples,
+        target.display()
+    );
+    Ok(())
+}
+
+// A configured theme is swapped for the default one, or dropped from the
+// layers, so the next start still finds every theme it plays.
+fn deactivate(name: &str) -> Result<()> {
+    let path = Config::path()?;
+    let mut config = Config::load(&path)?;
+    let layers = config.layers.len();
+    config.layers.retain(|layer| layer.theme != name);
+    let main = config.theme == name;
+    if main {
+        if name == DEFAULT_THEME {
+            bail!(
+                "{} is the active theme and the default one; switch to another theme first",
+                name
+            );
+        }
+        config.theme = DEFAULT_THEME.to_string();
+    }
+    if !main && config.layers.len() == layers {
+        return Ok(());
+    }
+    config.save(&path)?;
+    if main {
+        println!(
+            "{} was the active theme, switched to {}",
+            name, DEFAULT_THEME
+        );
+    } else {
+        println!("Removed {} from the theme layers", name);
+    }
+    Ok(())
+}
+
+// `keydio uninstall`: removes a theme from the user's theme directory, as
+// unpacked by `keydio install` or as a `.keydio` archive. Themes that ship
+// with keydio, anywhere else on the search path, are never touched.
+pub fn uninstall(name: &str) -> Result<()> {
+    if name == musical::THEME {
+        bail!("{} is built into keydio and cannot be uninstalled", name);
+    }
+    if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+        bail!("{} is not a theme name", name);
+    }
+    let user = assets::user_dir().context("No data directory found")?;
+    let dir = user.join(name);
+    let archive = user.join(format!("{}.{}", name, pack::EXTENSION));
+    if !dir.is_dir() && !archive.is_file() {
+        let found = assets::theme_dir(name);
+        if found.is_dir() {
+            bail!(
+                "{} ships with keydio in {}; only themes installed into {} can be uninstalled",
+                name,
+                found.display(),
+                user.display()
+            );
+        }
+        bail!("Theme {} is not installed", name);
+    }
+    deactivate(name)?;
+    if dir.is_dir() {
+        fs::remove_dir_all(&dir).with_context(|| format!("Failed to remove {}", dir.display()))?;
+        println!("Removed {}", dir.display());
+    }
+    if archive.is_file() {
+        fs::remove_file(&archive)
+            .with_context(|| format!("Failed to remove {}", archive.display()))?;
+        println!("Removed {}", archive.display());
+    }
+    Ok(())
+}
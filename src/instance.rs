@@ -0,0 +1,67 @@
+use anyhow::{bail, Context, Result};
+use std::{
+    fs::{self, File, OpenOptions, TryLockError},
+    io::Write,
+    path::PathBuf,
+};
+
+const LOCK_FILE: &str = "keydio.lock";
+
+// Per-user directory for files that only matter while keydio runs.
+pub fn runtime_dir() -> Result<PathBuf> {
+    let dir = dirs::runtime_dir()
+        .unwrap_or_else(std::env::temp_dir)
+        .join("keydio");
+    fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o700))?;
+    }
+    Ok(dir)
+}
+
+// Held for as long as this process runs. The OS drops the advisory lock when
+// the process dies, so a crash never leaves a stale lock behind; the pid in
+// the file is only there for the error message.
+pub struct InstanceLock {
+    _file: File,
+}
+
+impl InstanceLock {
+    pub fn acquire(force: bool) -> Result<Option<Self>> {
+        let path = runtime_dir()?.join(LOCK_FILE);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        match file.try_lock() {
+            Ok(()) => {
+                file.set_len(0)?;
+                write!(file, "{}", std::process::id())?;
+                Ok(Some(Self { _file: file }))
+            }
+            Err(TryLockError::WouldBlock) => {
+                let pid = fs::read_to_string(&path)
+                    .ok()
+                    .map(|pid| pid.trim().to_string())
+                    .filter(|pid| !pid.is_empty())
+                    .unwrap_or_else(|| "unknown".to_string());
+                if !force {
+                    bail!("keydio is already running (pid {})", pid);
+                }
+                eprintln!(
+                    "keydio is already running (pid {}), starting anyway because of --force",
+                    pid
+                );
+                Ok(None)
+            }
+            Err(TryLockError::Error(err)) => {
+                Err(err).with_context(|| format!("Failed to lock {}", path.display()))
+            }
+        }
+    }
+}
@@ -0,0 +1,167 @@
+use crate::{
+    control::{Controller, Request},
+    instance::runtime_dir,
+    state::SharedState,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use interprocess::local_socket::{prelude::*, ListenerOptions, Name, Stream};
+use std::{
+    fs,
+    io::{BufRead, BufReader, Write},
+    path::PathBuf,
+    sync::Arc,
+    thread,
+};
+
+const SOCKET_FILE: &str = "keydio.sock";
+
+fn socket_path() -> Result<PathBuf> {
+    Ok(runtime_dir()?.join(SOCKET_FILE))
+}
+
+#[cfg(unix)]
+fn socket_name(path: &std::path::Path) -> Result<Name<'_>> {
+    use interprocess::local_socket::GenericFilePath;
+    Ok(path.to_fs_name::<GenericFilePath>()?)
+}
+
+#[cfg(windows)]
+fn socket_name(_path: &std::path::Path) -> Result<Name<'static>> {
+    use interprocess::local_socket::GenericNamespaced;
+    let user = std::env::var("USERNAME").unwrap_or_default();
+    Ok(format!("keydio-{}", user).to_ns_name::<GenericNamespaced>()?)
+}
+
+// A pipe only the user keydio runs as can open: a protected DACL allowing
+// just that user's SID.
+#[cfg(windows)]
+fn current_user_only() -> Result<interprocess::os::windows::security_descriptor::SecurityDescriptor>
+{
+    use interprocess::os::windows::security_descriptor::SecurityDescriptor;
+    use std::{io, ptr};
+    use widestring::{U16CStr, U16CString};
+    use windows_sys::Win32::{
+        Foundation::{CloseHandle, LocalFree},
+        Security::{
+            Authorization::ConvertSidToStringSidW, GetTokenInformation, TokenUser, TOKEN_QUERY,
+            TOKEN_USER,
+        },
+        System::Threading::{GetCurrentProcess, OpenProcessToken},
+    };
+    let sid = unsafe {
+        let mut token = ptr::null_mut();
+        if OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &mut token) == 0 {
+            return Err(io::Error::last_os_error()).context("Cannot open the process token");
+        }
+        let mut len = 0;
+        GetTokenInformation(token, TokenUser, ptr::null_mut(), 0, &mut len);
+        // u64s keep the buffer aligned for the TOKEN_USER it holds.
+        let mut buffer = vec![0u64; (len as usize).div_ceil(8)];
+        let read = GetTokenInformation(token, TokenUser, buffer.as_mut_ptr().cast(), len, &mut len);
+        let err = io::Error::last_os_error();
+        CloseHandle(token);
+        if read == 0 {
+            return Err(err).context("Cannot read the current user");
+        }
+        let user = &*buffer.as_ptr().cast::<TOKEN_USER>();
+        let mut text = ptr::null_mut();
+        if ConvertSidToStringSidW(user.User.Sid, &mut text) == 0 {
+            return Err(io::Error::last_os_error()).context("Cannot format the user's SID");
+        }
+        let sid = U16CStr::from_ptr_str(text).to_string_lossy();
+        LocalFree(text.cast());
+        sid
+    };
+    let sddl = U16CString::from_str(format!("D:P(A;;GA;;;{})", sid))?;
+    Ok(SecurityDescriptor::deserialize(&sddl)?)
+}
+
+// One request per line, one "ok ..." or "error ..." line back.
+fn handle(controller: &Controller, stream: Stream) -> Result<()> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 0 {
+        let response = match line.parse::<Request>() {
+            Ok(request) => controller.execute(request),
+            Err(err) => Err(err),
+        };
+        let reply = match response {
+            Ok(text) => format!("ok {}\n", text),
+            Err(err) => format!("error {}\n", err),
+        };
+        reader.get_mut().write_all(reply.as_bytes())?;
+        line.clear();
+    }
+    Ok(())
+}
+
+// Serves the control socket from its own thread. Only the current user can
+// connect: the socket lives in the per-user runtime directory and is mode
+// 0600, the Windows pipe allows just the user's SID.
+pub fn serve(state: &SharedState, controller: Arc<Controller>) -> Result<()> {
+    let path = socket_path()?;
+    let options = ListenerOptions::new()
+        .name(socket_name(&path)?)
+        .try_overwrite(true);
+    // Linux sets the mode before the socket is bound; elsewhere it is set
+    // right after below.
+    #[cfg(target_os = "linux")]
+    let options = {
+        use interprocess::os::unix::local_socket::ListenerOptionsExt;
+        options.mode(0o600)
+    };
+    #[cfg(windows)]
+    let options = {
+        use interprocess::os::windows::local_socket::ListenerOptionsExt;
+        options.security_descriptor(current_user_only()?)
+    };
+    let listener = options
+        .create_sync()
+        .with_context(|| format!("Failed to listen on {}", path.display()))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600))
+            .with_context(|| format!("Failed to restrict {}", path.display()))?;
+    }
+    state.on_shutdown(move || {
+        let _ = fs::remove_file(&path);
+    });
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let controller = Arc::clone(&controller);
+                    thread::spawn(move || {
+                        if let Err(err) = handle(&controller, stream) {
+                            eprintln!("Control connection failed: {:?}", err);
+                        }
+                    });
+                }
+                Err(err) => eprintln!("Failed to accept control connection: {:?}", err),
+            }
+        }
+    });
+    Ok(())
+}
+
+// Client side of `keydio ctl`: sends one command and prints the answer.
+pub fn send(command: &[String]) -> Result<()> {
+    let path = socket_path()?;
+    let stream = Stream::connect(socket_name(&path)?)
+        .map_err(|err| anyhow!("Cannot reach a running keydio ({})", err))?;
+    let mut reader = BufReader::new(stream);
+    reader
+        .get_mut()
+        .write_all(format!("{}\n", command.join(" ")).as_bytes())?;
+    let mut reply = String::new();
+    reader.read_line(&mut reply)?;
+    match reply.trim_end().split_once(' ') {
+        Some(("ok", text)) => {
+            println!("{}", text);
+            Ok(())
+        }
+        Some(("error", message)) => bail!("{}", message),
+        _ => bail!("Unexpected reply from keydio: {:?}", reply),
+    }
+}
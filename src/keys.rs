@@ -0,0 +1,241 @@
+use crate::layout;
+use anyhow::{anyhow, bail, Result};
+use device_query::Keycode;
+use std::{collections::HashSet, str::FromStr};
+
+const MODIFIERS: [Keycode; 11] = [
+    Keycode::LControl,
+    Keycode::RControl,
+    Keycode::LShift,
+    Keycode::RShift,
+    Keycode::LAlt,
+    Keycode::RAlt,
+    Keycode::Command,
+    Keycode::LOption,
+    Keycode::ROption,
+    Keycode::LMeta,
+    Keycode::RMeta,
+];
+const ARROWS: [Keycode; 4] = [Keycode::Up, Keycode::Down, Keycode::Left, Keycode::Right];
+// Every key name device_query knows, in its order.
+pub const NAMES: [&str; 111] = [
+    "Key0",
+    "Key1",
+    "Key2",
+    "Key3",
+    "Key4",
+    "Key5",
+    "Key6",
+    "Key7",
+    "Key8",
+    "Key9",
+    "A",
+    "B",
+    "C",
+    "D",
+    "E",
+    "F",
+    "G",
+    "H",
+    "I",
+    "J",
+    "K",
+    "L",
+    "M",
+    "N",
+    "O",
+    "P",
+    "Q",
+    "R",
+    "S",
+    "T",
+    "U",
+    "V",
+    "W",
+    "X",
+    "Y",
+    "Z",
+    "F1",
+    "F2",
+    "F3",
+    "F4",
+    "F5",
+    "F6",
+    "F7",
+    "F8",
+    "F9",
+    "F10",
+    "F11",
+    "F12",
+    "F13",
+    "F14",
+    "F15",
+    "F16",
+    "F17",
+    "F18",
+    "F19",
+    "F20",
+    "Escape",
+    "Space",
+    "LControl",
+    "RControl",
+    "LShift",
+    "RShift",
+    "LAlt",
+    "RAlt",
+    "Command",
+    "LOption",
+    "ROption",
+    "LMeta",
+    "RMeta",
+    "Enter",
+    "Up",
+    "Down",
+    "Left",
+    "Right",
+    "Backspace",
+    "CapsLock",
+    "Tab",
+    "Home",
+    "End",
+    "PageUp",
+    "PageDown",
+    "Insert",
+    "Delete",
+    "Numpad0",
+    "Numpad1",
+    "Numpad2",
+    "Numpad3",
+    "Numpad4",
+    "Numpad5",
+    "Numpad6",
+    "Numpad7",
+    "Numpad8",
+    "Numpad9",
+    "NumpadSubtract",
+    "NumpadAdd",
+    "NumpadDivide",
+    "NumpadMultiply",
+    "NumpadEquals",
+    "NumpadEnter",
+    "NumpadDecimal",
+    "Grave",
+    "Minus",
+    "Equal",
+    "LeftBracket",
+    "RightBracket",
+    "BackSlash",
+    "Semicolon",
+    "Apostrophe",
+    "Comma",
+    "Dot",
+    "Slash",
+];
+
+fn keycode(name: &str) -> Result<Keycode> {
+    if let Some(key) = layout::resolve(name)? {
+        return Ok(key);
+    }
+    Keycode::from_str(name).map_err(|_| anyhow!("Unknown key name: {}", name))
+}
+
+// A single key by name; unknown names list the valid ones. Names may pin a
+// position or a character instead: `physical:KeyQ`, `char:a`.
+pub fn named(name: &str) -> Result<Keycode> {
+    if let Some(key) = layout::resolve(name)? {
+        return Ok(key);
+    }
+    Keycode::from_str(name).map_err(|_| {
+        anyhow!(
+            "Unknown key name: {}; valid names: {}",
+            name,
+            NAMES.join(", ")
+        )
+    })
+}
+
+// "F1" -> ("F", 1), "Key0" -> ("Key", 0); None for names without a number.
+fn numbered(name: &str) -> Option<(&str, u32)> {
+    let split = name.trim_end_matches(|c: char| c.is_ascii_digit()).len();
+    let number = name[split..].parse().ok()?;
+    Some((&name[..split], number))
+}
+
+fn range(first: &str, last: &str) -> Result<Vec<Keycode>> {
+    let names: Vec<String> = match (numbered(first), numbered(last)) {
+        (Some((prefix, from)), Some((last_prefix, to))) if prefix == last_prefix => {
+            (from..=to).map(|n| format!("{}{}", prefix, n)).collect()
+        }
+        _ => match (first.as_bytes(), last.as_bytes()) {
+            ([from], [to]) if from.is_ascii_uppercase() && to.is_ascii_uppercase() => {
+                (*from..=*to).map(|c| (c as char).to_string()).collect()
+            }
+            _ => bail!("Cannot make a range from {} to {}", first, last),
+        },
+    };
+    if names.is_empty() {
+        bail!("{}..{} is an empty range", first, last);
+    }
+    names.iter().map(|name| keycode(name)).collect()
+}
+
+// One entry of an ignore or silent list: a key name (`F1`), a range of
+// numbered or lettered keys (`F1..F12`, `A..Z`) or a class (`modifiers`,
+// `function`, `letters`, `digits`, `numpad`, `arrows`).
+pub fn parse_spec(spec: &str) -> Result<Vec<Keycode>> {
+    let spec = spec.trim();
+    Ok(match spec {
+        "modifiers" => MODIFIERS.to_vec(),
+        "arrows" => ARROWS.to_vec(),
+        "function" => range("F1", "F20")?,
+        "letters" => range("A", "Z")?,
+        "digits" => range("Key0", "Key9")?,
+        "numpad" => {
+            let mut keys = range("Numpad0", "Numpad9")?;
+            keys.extend([
+                Keycode::NumpadSubtract,
+                Keycode::NumpadAdd,
+                Keycode::NumpadDivide,
+                Keycode::NumpadMultiply,
+                Keycode::NumpadEquals,
+                Keycode::NumpadEnter,
+                Keycode::NumpadDecimal,
+            ]);
+            keys
+        }
+        _ => match spec.split_once("..") {
+            Some((first, last)) => range(first.trim(), last.trim())?,
+            None => vec![keycode(spec)?],
+        },
+    })
+}
+
+// A resolved key list, built once so per-keystroke checks are a hash lookup.
+#[derive(Clone, Debug, Default)]
+pub struct KeySet(HashSet<Keycode>);
+
+impl KeySet {
+    pub fn parse(specs: &[String]) -> Result<Self> {
+        let mut keys = HashSet::new();
+        for spec in specs {
+            keys.extend(parse_spec(spec)?);
+        }
+        Ok(Self(keys))
+    }
+
+    // Like `parse`, but skips entries that do not parse instead of failing.
+    pub fn parse_lenient(specs: &[String]) -> Self {
+        let mut keys = HashSet::new();
+        for spec in specs {
+            match parse_spec(spec) {
+                Ok(parsed) => keys.extend(parsed),
+                Err(err) => eprintln!("Ignoring ignore list entry: {:?}", err),
+            }
+        }
+        Self(keys)
+    }
+
+    pub fn contains(&self, key: &Keycode) -> bool {
+        self.0.contains(key)
+    }
+}
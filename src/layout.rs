@@ -0,0 +1,400 @@
+use anyhow::{anyhow, bail, Result};
+use device_query::Keycode;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    str::FromStr,
+    sync::{OnceLock, RwLock},
+};
+
+// The keyboard layout key names are translated with. `auto` asks the
+// system once, when a name first needs it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Layout {
+    #[default]
+    Auto,
+    Qwerty,
+    Azerty,
+    Qwertz,
+}
+
+impl fmt::Display for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Layout::Auto => "auto",
+            Layout::Qwerty => "qwerty",
+            Layout::Azerty => "azerty",
+            Layout::Qwertz => "qwertz",
+        };
+        f.write_str(name)
+    }
+}
+
+// What each key position types on a US layout, unshifted. Positions are
+// named after the US key, the way device_query names them.
+const QWERTY: [(Keycode, char); 47] = [
+    (Keycode::Grave, '`'),
+    (Keycode::Key1, '1'),
+    (Keycode::Key2, '2'),
+    (Keycode::Key3, '3'),
+    (Keycode::Key4, '4'),
+    (Keycode::Key5, '5'),
+    (Keycode::Key6, '6'),
+    (Keycode::Key7, '7'),
+    (Keycode::Key8, '8'),
+    (Keycode::Key9, '9'),
+    (Keycode::Key0, '0'),
+    (Keycode::Minus, '-'),
+    (Keycode::Equal, '='),
+    (Keycode::Q, 'q'),
+    (Keycode::W, 'w'),
+    (Keycode::E, 'e'),
+    (Keycode::R, 'r'),
+    (Keycode::T, 't'),
+    (Keycode::Y, 'y'),
+    (Keycode::U, 'u'),
+    (Keycode::I, 'i'),
+    (Keycode::O, 'o'),
+    (Keycode::P, 'p'),
+    (Keycode::LeftBracket, '['),
+    (Keycode::RightBracket, ']'),
+    (Keycode::BackSlash, '\\'),
+    (Keycode::A, 'a'),
+    (Keycode::S, 's'),
+    (Keycode::D, 'd'),
+    (Keycode::F, 'f'),
+    (Keycode::G, 'g'),
+    (Keycode::H, 'h'),
+    (Keycode::J, 'j'),
+    (Keycode::K, 'k'),
+    (Keycode::L, 'l'),
+    (Keycode::Semicolon, ';'),
+    (Keycode::Apostrophe, '\''),
+    (Keycode::Z, 'z'),
+    (Keycode::X, 'x'),
+    (Keycode::C, 'c'),
+    (Keycode::V, 'v'),
+    (Keycode::B, 'b'),
+    (Keycode::N, 'n'),
+    (Keycode::M, 'm'),
+    (Keycode::Comma, ','),
+    (Keycode::Dot, '.'),
+    (Keycode::Slash, '/'),
+];
+
+// Where the other layouts differ from it: French AZERTY and German QWERTZ.
+const AZERTY: [(Keycode, char); 25] = [
+    (Keycode::Grave, '²'),
+    (Keycode::Key1, '&'),
+    (Keycode::Key2, 'é'),
+    (Keycode::Key3, '"'),
+    (Keycode::Key4, '\''),
+    (Keycode::Key5, '('),
+    (Keycode::Key6, '-'),
+    (Keycode::Key7, 'è'),
+    (Keycode::Key8, '_'),
+    (Keycode::Key9, 'ç'),
+    (Keycode::Key0, 'à'),
+    (Keycode::Minus, ')'),
+    (Keycode::Q, 'a'),
+    (Keycode::W, 'z'),
+    (Keycode::LeftBracket, '^'),
+    (Keycode::RightBracket, '$'),
+    (Keycode::BackSlash, '*'),
+    (Keycode::A, 'q'),
+    (Keycode::Semicolon, 'm'),
+    (Keycode::Apostrophe, 'ù'),
+    (Keycode::Z, 'w'),
+    (Keycode::M, ','),
+    (Keycode::Comma, ';'),
+    (Keycode::Dot, ':'),
+    (Keycode::Slash, '!'),
+];
+const QWERTZ: [(Keycode, char); 11] = [
+    (Keycode::Grave, '^'),
+    (Keycode::Minus, 'ß'),
+    (Keycode::Equal, '´'),
+    (Keycode::Y, 'z'),
+    (Keycode::Z, 'y'),
+    (Keycode::LeftBracket, 'ü'),
+    (Keycode::RightBracket, '+'),
+    (Keycode::BackSlash, '#'),
+    (Keycode::Semicolon, 'ö'),
+    (Keycode::Apostrophe, 'ä'),
+    (Keycode::Slash, '-'),
+];
+
+impl Layout {
+    fn overrides(self) -> &'static [(Keycode, char)] {
+        match self {
+            Layout::Azerty => &AZERTY,
+            Layout::Qwertz => &QWERTZ,
+            Layout::Auto | Layout::Qwerty => &[],
+        }
+    }
+
+    // The character the key at `position` types, unshifted.
+    pub fn character(self, position: Keycode) -> Option<char> {
+        let lookup = |table: &[(Keycode, char)]| {
+            table
+                .iter()
+                .find(|(key, _)| *key == position)
+                .map(|(_, c)| *c)
+        };
+        lookup(self.overrides()).or_else(|| lookup(&QWERTY))
+    }
+
+    // The position of the key that types `c`.
+    pub fn position(self, c: char) -> Option<Keycode> {
+        let c = c.to_lowercase().next()?;
+        QWERTY
+            .iter()
+            .map(|(key, _)| *key)
+            .find(|key| self.character(*key) == Some(c))
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use super::Layout;
+    use anyhow::{anyhow, Context, Result};
+    use std::{env, process::Command};
+
+    // Wayland compositors take the layout from the environment; X11 reports
+    // it through setxkbmap. Only the first of several layouts counts.
+    pub fn detect() -> Result<Layout> {
+        let layouts = match env::var("XKB_DEFAULT_LAYOUT") {
+            Ok(layouts) => layouts,
+            Err(_) => {
+                let output = Command::new("setxkbmap")
+                    .arg("-query")
+                    .output()
+                    .context("Failed to run setxkbmap")?;
+                String::from_utf8_lossy(&output.stdout)
+                    .lines()
+                    .find_map(|line| line.strip_prefix("layout:"))
+                    .map(|layouts| layouts.trim().to_string())
+                    .ok_or_else(|| anyhow!("setxkbmap reported no layout"))?
+            }
+        };
+        let layout = layouts.split(',').next().unwrap_or_default();
+        Ok(match layout {
+            "fr" | "be" => Layout::Azerty,
+            "de" | "at" | "ch" | "cz" | "hu" | "sk" | "si" | "hr" => Layout::Qwertz,
+            _ => Layout::Qwerty,
+        })
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use super::Layout;
+    use anyhow::{bail, Context, Result};
+    use std::process::Command;
+
+    pub fn detect() -> Result<Layout> {
+        let output = Command::new("defaults")
+            .args([
+                "read",
+                "com.apple.HIToolbox",
+                "AppleCurrentKeyboardLayoutInputSourceID",
+            ])
+            .output()
+            .context("Failed to run defaults")?;
+        if !output.status.success() {
+            bail!("{}", String::from_utf8_lossy(&output.stderr).trim());
+        }
+        // com.apple.keylayout.French, com.apple.keylayout.German, ...
+        let source = String::from_utf8_lossy(&output.stdout);
+        let name = source.trim().rsplit('.').next().unwrap_or_default();
+        Ok(
+            if name.starts_with("French") || name.starts_with("Belgian") {
+                Layout::Azerty
+            } else if [
+                "German",
+                "Austrian",
+                "Swiss",
+                "Czech",
+                "Hungarian",
+                "Slovak",
+                "Slovenian",
+                "Croatian",
+            ]
+            .iter()
+            .any(|prefix| name.starts_with(prefix))
+            {
+                Layout::Qwertz
+            } else {
+                Layout::Qwerty
+            },
+        )
+    }
+}
+
+#[cfg(target_os = "windows")]
+mod platform {
+    use super::Layout;
+    use anyhow::{Context, Result};
+    use winreg::{enums::HKEY_CURRENT_USER, RegKey};
+
+    // The first preloaded layout is the default one; its low word is the
+    // language, which decides the letter arrangement.
+    pub fn detect() -> Result<Layout> {
+        let preload = RegKey::predef(HKEY_CURRENT_USER)
+            .open_subkey("Keyboard Layout\\Preload")
+            .context("No keyboard layouts configured")?;
+        let id: String = preload.get_value("1")?;
+        let language = u32::from_str_radix(&id, 16).context("Unexpected layout id")? & 0xffff;
+        Ok(match language {
+            0x040c | 0x080c => Layout::Azerty,
+            0x0407 | 0x0c07 | 0x0807 | 0x100c | 0x0405 | 0x040e | 0x041b | 0x0424 | 0x041a => {
+                Layout::Qwertz
+            }
+            _ => Layout::Qwerty,
+        })
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+mod platform {
+    use super::Layout;
+    use anyhow::{bail, Result};
+
+    pub fn detect() -> Result<Layout> {
+        bail!("Keyboard layout detection is not supported on this platform")
+    }
+}
+
+static CONFIGURED: RwLock<Layout> = RwLock::new(Layout::Auto);
+// `None` when asking the system failed.
+static DETECTED: OnceLock<Option<Layout>> = OnceLock::new();
+
+// Set from the config the engine runs with, so key names parse the same
+// everywhere in it.
+pub fn configure(layout: Layout) {
+    *CONFIGURED.write().unwrap() = layout;
+}
+
+fn detected() -> Option<Layout> {
+    *DETECTED.get_or_init(|| match platform::detect() {
+        Ok(layout) => Some(layout),
+        Err(err) => {
+            eprintln!(
+                "Cannot detect the keyboard layout, assuming QWERTY: {:?}",
+                err
+            );
+            None
+        }
+    })
+}
+
+// The layout in use, never `Auto`. A layout that cannot be detected is taken
+// to be QWERTY, which is what unprefixed names assume anyway.
+pub fn active() -> Layout {
+    match *CONFIGURED.read().unwrap() {
+        Layout::Auto => detected().unwrap_or(Layout::Qwerty),
+        layout => layout,
+    }
+}
+
+// Whether the layout in use was asked of the system and it answered.
+pub fn is_detected() -> bool {
+    *CONFIGURED.read().unwrap() == Layout::Auto && detected().is_some()
+}
+
+// device_query reports where a key sits on Linux and macOS, but names
+// letters after the character they type on Windows (virtual key codes).
+// Other keys are taken as positions everywhere.
+const REPORTS_CHARACTERS: bool = cfg!(target_os = "windows");
+
+fn is_letter(key: Keycode) -> bool {
+    matches!(key.to_string().as_bytes(), [c] if c.is_ascii_uppercase())
+}
+
+fn letter(c: char) -> Option<Keycode> {
+    Keycode::from_str(&c.to_ascii_uppercase().to_string())
+        .ok()
+        .filter(|key| c.is_ascii_alphabetic() && is_letter(*key))
+}
+
+// The position of a key as device_query reported it.
+pub fn position(key: Keycode) -> Keycode {
+    if REPORTS_CHARACTERS && is_letter(key) {
+        let c = key.to_string().to_ascii_lowercase().chars().next();
+        if let Some(position) = c.and_then(|c| active().position(c)) {
+            return position;
+        }
+    }
+    key
+}
+
+// What device_query reports for the key at `position`.
+fn reported(position: Keycode) -> Keycode {
+    if REPORTS_CHARACTERS && is_letter(position) {
+        if let Some(key) = active().character(position).and_then(letter) {
+            return key;
+        }
+    }
+    position
+}
+
+// The character a reported key types, if it types one.
+pub fn character(key: Keycode) -> Option<char> {
+    active().character(position(key))
+}
+
+// "physical:KeyQ" for a letter, like web key codes; other keys keep their
+// name, digits already being Key0..Key9.
+pub fn physical_name(key: Keycode) -> String {
+    let position = position(key);
+    if is_letter(position) {
+        format!("physical:Key{}", position)
+    } else {
+        format!("physical:{}", position)
+    }
+}
+
+// Key names with a prefix, resolved to what device_query reports for them:
+// `physical:KeyQ` (or `physical:Q`) is the key where Q sits on a US layout,
+// `char:a` the key that types a on the active one. `None` for names without
+// a prefix, which name the reported key directly.
+pub fn resolve(name: &str) -> Result<Option<Keycode>> {
+    if let Some(position) = name.strip_prefix("physical:") {
+        let position = match position.strip_prefix("Key") {
+            Some(rest) if rest.len() == 1 && rest.as_bytes()[0].is_ascii_uppercase() => rest,
+            _ => position,
+        };
+        let position =
+            Keycode::from_str(position).map_err(|_| anyhow!("Unknown key position: {}", name))?;
+        return Ok(Some(reported(position)));
+    }
+    if let Some(c) = name.strip_prefix("char:") {
+        let mut chars = c.chars();
+        let (Some(c), None) = (chars.next(), chars.next()) else {
+            bail!("{} names more than one character", name);
+        };
+        let layout = active();
+        let position = layout
+            .position(c)
+            .ok_or_else(|| anyhow!("No key types {} on a {} keyboard", c, layout))?;
+        return Ok(Some(reported(position)));
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layouts_swap_letters_between_positions() {
+        assert_eq!(Layout::Azerty.character(Keycode::Q), Some('a'));
+        assert_eq!(Layout::Azerty.position('a'), Some(Keycode::Q));
+        assert_eq!(Layout::Azerty.position('m'), Some(Keycode::Semicolon));
+        assert_eq!(Layout::Qwertz.position('Z'), Some(Keycode::Y));
+        assert_eq!(Layout::Qwerty.character(Keycode::Q), Some('q'));
+        // Keys the other layouts leave alone type the same characters.
+        assert_eq!(Layout::Qwertz.position('q'), Some(Keycode::Q));
+    }
+}
@@ -0,0 +1,174 @@
+use crate::{
+    sample::{Format, Pcm},
+    KeyboardButtonSound, SoundType,
+};
+use std::fmt;
+
+// The level every theme is brought to, about that of the default theme, so
+// auto gain leaves it alone.
+const REFERENCE_DB: f32 = -25.5;
+// Quiet themes are raised and loud ones lowered by at most this much.
+const MAX_GAIN_DB: f32 = 12.0;
+// Clicks are short, so loudness is that of a sample's loudest stretch of
+// this length rather than an average over its silent tail.
+const WINDOW_SECONDS: f32 = 0.01;
+// Samples measured per sound type, to keep loading large themes quick.
+const PER_TYPE: usize = 8;
+
+// Letters are most of typing and the space bar most of the rest.
+fn weight(sound_type: &SoundType) -> f32 {
+    match sound_type {
+        SoundType::Generic => 4.0,
+        SoundType::Space => 2.0,
+        SoundType::Enter | SoundType::Backspace => 1.0,
+    }
+}
+
+// Mean power of the loudest window of `pcm`.
+fn peak_power(pcm: &Pcm) -> f32 {
+    let window = ((pcm.sample_rate as f32 * WINDOW_SECONDS) as usize * usize::from(pcm.channels))
+        .clamp(1, pcm.samples.len().max(1));
+    pcm.samples
+        .chunks(window)
+        .map(|chunk| chunk.iter().map(|sample| sample * sample).sum::<f32>() / chunk.len() as f32)
+        .fold(0.0, f32::max)
+}
+
+fn db(power: f32) -> f32 {
+    10.0 * power.max(1e-12).log10()
+}
+
+// The loudness of a theme's press samples in dBFS, weighted by how often
+// each sound type plays. The velocity and hold variants are left out.
+pub fn measure(sounds: &[KeyboardButtonSound], format: Format) -> Option<f32> {
+    let mut total = 0.0;
+    let mut weights = 0.0;
+    for sound_type in [
+        SoundType::Generic,
+        SoundType::Space,
+        SoundType::Enter,
+        SoundType::Backspace,
+    ] {
+        let powers: Vec<f32> = sounds
+            .iter()
+            .filter(|sound| sound.sound_type == sound_type && sound.velocity.is_none())
+            .take(PER_TYPE)
+            .filter_map(|sound| match &sound.clip.pcm {
+                Some(pcm) => Some(peak_power(pcm)),
+                None => Pcm::decode(&sound.clip.bytes, format)
+                    .ok()
+                    .map(|pcm| peak_power(&pcm)),
+            })
+            .collect();
+        if powers.is_empty() {
+            continue;
+        }
+        let mean = powers.iter().sum::<f32>() / powers.len() as f32;
+        total += mean * weight(&sound_type);
+        weights += weight(&sound_type);
+    }
+    (weights > 0.0).then(|| db(total / weights))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GainSource {
+    // Measured, at this loudness.
+    Auto(f32),
+    // The theme's `default_volume`.
+    Manifest,
+    // `auto_gain = false`, or nothing to measure.
+    Off,
+}
+
+// What a theme's samples are scaled by, under the user's volume.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Gain {
+    pub value: f32,
+    pub source: GainSource,
+}
+
+impl Default for Gain {
+    fn default() -> Self {
+        Self {
+            value: 1.0,
+            source: GainSource::Off,
+        }
+    }
+}
+
+impl Gain {
+    // A theme's own `default_volume` wins over the measured gain.
+    pub fn new(
+        auto_gain: bool,
+        default_volume: Option<f32>,
+        sounds: &[KeyboardButtonSound],
+        format: Format,
+    ) -> Self {
+        if let Some(volume) = default_volume {
+            return Self {
+                value: volume,
+                source: GainSource::Manifest,
+            };
+        }
+        match measure(sounds, format).filter(|_| auto_gain) {
+            Some(loudness) => Self {
+                value: 10f32
+                    .powf((REFERENCE_DB - loudness).clamp(-MAX_GAIN_DB, MAX_GAIN_DB) / 20.0),
+                source: GainSource::Auto(loudness),
+            },
+            None => Self::default(),
+        }
+    }
+}
+
+impl fmt::Display for Gain {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Rounded first so a gain just under 0 dB prints as 0.0, not -0.0.
+        let gain_db = (200.0 * self.value.max(1e-6).log10()).round() / 10.0 + 0.0;
+        match self.source {
+            GainSource::Auto(loudness) => {
+                write!(
+                    f,
+                    "{:+.1} dB (auto, measured {:.1} dBFS)",
+                    gain_db, loudness
+                )
+            }
+            GainSource::Manifest => write!(f, "{:+.1} dB (default_volume)", gain_db),
+            GainSource::Off => write!(f, "{:+.1} dB (off)", gain_db),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sample::Clip;
+    use std::sync::Arc;
+
+    fn theme(level: f32) -> Vec<KeyboardButtonSound> {
+        let pcm = Pcm {
+            channels: 1,
+            sample_rate: 1000,
+            samples: Arc::from(vec![level; 100]),
+        };
+        let clip = Clip::new(Vec::new().into(), Some(pcm));
+        vec![KeyboardButtonSound::new(SoundType::Generic, clip, 1.0)]
+    }
+
+    #[test]
+    fn quiet_themes_are_raised_unless_the_manifest_says_otherwise() {
+        let format = Format {
+            channels: 1,
+            sample_rate: 1000,
+        };
+        let quiet = Gain::new(true, None, &theme(0.01), format);
+        let loud = Gain::new(true, None, &theme(0.5), format);
+        assert!(quiet.value > 1.0 && loud.value < 1.0);
+        assert_eq!(quiet.value, 10f32.powf(MAX_GAIN_DB / 20.0));
+        assert_eq!(Gain::new(true, Some(0.8), &theme(0.01), format).value, 0.8);
+        assert_eq!(
+            Gain::new(false, None, &theme(0.01), format),
+            Gain::default()
+        );
+    }
+}
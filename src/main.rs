@@ -1,17 +1,95 @@
-use anyhow::Result;
+mod archive;
+mod assets;
+mod audio;
+mod automute;
+mod autostart;
+mod bench;
+mod bundle;
+mod cache;
+mod clean;
+mod cli;
+mod config;
+mod control;
+mod convert;
+mod crash;
+#[cfg(all(feature = "dbus", target_os = "linux"))]
+mod dbus;
+mod dnd;
+mod fsutil;
+mod fullscreen;
+#[cfg(feature = "gui")]
+mod gui;
+mod heatmap;
+mod hold;
+mod install;
+mod instance;
+mod ipc;
+mod keys;
+mod layout;
+mod loudness;
+mod manifest;
+mod metrics;
+mod mic;
+mod mixer;
+mod musical;
+mod notify;
+#[cfg(feature = "osc")]
+mod osc;
+mod overrides;
+mod pack;
+mod play;
+mod profile;
+mod registry;
+mod reload;
+mod sample;
+mod schedule;
+mod screenshare;
+mod session;
+mod sha256;
+mod simulate;
+mod state;
+mod stats;
+mod stress;
+mod supervise;
+mod themes;
+#[cfg(feature = "tray")]
+mod tray;
+#[cfg(feature = "self-update")]
+mod update;
+mod velocity;
+
+use anyhow::{Context, Result};
+use clap::Parser;
+use cli::{AutostartAction, CacheAction, Cli, Command};
+use config::Config;
+use control::Controller;
 use device_query::{DeviceEvents, DeviceState, Keycode};
+use heatmap::Heatmap;
+use hold::Held;
+use instance::InstanceLock;
+use keys::KeySet;
+use loudness::Gain;
+use manifest::Manifest;
+use metrics::Metrics;
+use musical::Note;
+use profile::Profile;
 use rand::seq::SliceRandom;
-use rodio::{source::Source, Decoder, OutputStream, OutputStreamHandle};
+use sample::{Bytes, Clip};
+use serde::{Deserialize, Serialize};
+use session::Session;
+use state::{SharedState, Silencer};
+use stats::Stats;
 use std::{
-    fs::File,
-    io::{BufReader, Cursor, Read},
-    path::PathBuf,
+    collections::HashMap,
+    path::{Path, PathBuf},
     sync::{
-        mpsc::{self, Receiver, Sender},
-        Arc, Mutex,
+        mpsc::{self, SendError, Sender, SyncSender, TrySendError},
+        Arc,
     },
     thread,
+    time::{Duration, Instant},
 };
+use velocity::Velocity;
 
 const ASSETS: &str = "assets";
 const AUDIOFILE: [(&str, SoundType); 5] = [
@@ -22,15 +100,16 @@ const AUDIOFILE: [(&str, SoundType); 5] = [
     ("SPACE.mp3", SoundType::Space),
 ];
 
-const CHERRYMXBROWN: &str = "cherrymxbrown";
-
-#[derive(Default)]
-enum Theme {
-    #[default]
-    CherryMXBrown,
-}
+const DEFAULT_THEME: &str = "cherrymxbrown";
+// Key events waiting for the audio thread. Past this many, new ones are
+// dropped instead of piling up behind a slow output.
+const AUDIO_QUEUE: usize = 256;
+// Loading a large theme holds up the audio thread for a while; only this
+// long without a beat means it hung.
+const AUDIO_STALL: Duration = Duration::from_secs(30);
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 enum SoundType {
     Backspace,
     Enter,
@@ -38,133 +117,740 @@ enum SoundType {
     Space,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 enum KeyPressType {
     Press,
     Release,
+    // Repeats while a key stays down, see `hold`.
+    Hold,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug)]
+struct KeyEvent {
+    // Missing for events that did not come from a real keyboard, e.g. replays.
+    key: Option<Keycode>,
+    keypress: KeyPressType,
+    sound_type: SoundType,
+    at: Instant,
+}
+
+impl KeyEvent {
+    fn new(key: Keycode, keypress: KeyPressType) -> Self {
+        Self {
+            key: Some(key),
+            keypress,
+            sound_type: map_key_to_sound(&key),
+            at: Instant::now(),
+        }
+    }
+}
+
+#[derive(Clone)]
 struct KeyboardButtonSound {
     sound_type: SoundType,
-    data: Vec<u8>,
+    velocity: Option<Velocity>,
+    // Only for the short and long release variants.
+    held: Option<Held>,
+    clip: Clip,
+    weight: f32,
+    // The file it was read from, for `watch_theme`.
+    path: Option<PathBuf>,
 }
 
 impl KeyboardButtonSound {
-    fn new(sound_type: SoundType, data: Vec<u8>) -> Self {
-        Self { sound_type, data }
+    fn new(sound_type: SoundType, clip: Clip, weight: f32) -> Self {
+        Self {
+            sound_type,
+            velocity: None,
+            held: None,
+            clip,
+            weight,
+            path: None,
+        }
     }
 }
 
+// `v1.2.3` and `1.2.3-rc1` both compare as 1.2.3.
+fn version(text: &str) -> Option<(u64, u64, u64)> {
+    let text = text.trim_start_matches('v');
+    let text = text.split(['-', '+']).next()?;
+    let mut parts = text.split('.');
+    let mut next = || parts.next().map_or(Some(0), |part| part.parse().ok());
+    Some((next()?, next()?, next()?))
+}
+
+fn available_themes() -> Vec<String> {
+    let mut themes = assets::themes();
+    if !themes.iter().any(|theme| theme == musical::THEME) {
+        themes.push(musical::THEME.to_string());
+    }
+    themes.sort();
+    themes
+}
+
 struct AppState {
-    theme: Theme,
+    theme: String,
     audio_press: Vec<KeyboardButtonSound>,
     audio_release: Vec<KeyboardButtonSound>,
+    audio_hold: Vec<KeyboardButtonSound>,
+    // Played after a streak of keystrokes, see `ComboConfig`.
+    combo: Option<Clip>,
+    // Played once for keys pressed together, see `ChordConfig`.
+    chord: Option<Clip>,
+    // Sounds of single keys, for themes that play one per key.
+    notes: HashMap<Keycode, Note>,
+    silent: KeySet,
+    // How the hot samples were found in the decoded cache.
+    cache: cache::Counts,
+    // Scales every sample of the theme, see `auto_gain`.
+    gain: Gain,
 }
 
 impl AppState {
-    fn new(theme: Theme) -> Self {
+    fn new(theme: &str) -> Self {
         Self {
-            theme,
+            theme: theme.to_string(),
             audio_press: Vec::new(),
             audio_release: Vec::new(),
+            audio_hold: Vec::new(),
+            combo: None,
+            chord: None,
+            notes: HashMap::new(),
+            silent: KeySet::default(),
+            cache: cache::Counts::default(),
+            gain: Gain::default(),
+        }
+    }
+
+    // The built-in musical theme renders its notes from the config; any other
+    // theme is read from its directory.
+    fn load(&mut self, config: &Config) -> Result<()> {
+        if self.theme == musical::THEME {
+            musical::load(self, &config.musical)
+        } else {
+            self.load_audio_samples(config)
         }
     }
 
-    fn load_audio_samples(&mut self) -> Result<()> {
-        match self.theme {
-            Theme::CherryMXBrown => {
-                for audio in AUDIOFILE {
-                    self.load_audio_on_memory(&audio, KeyPressType::Release)?;
-                    self.load_audio_on_memory(&audio, KeyPressType::Press)?;
+    fn load_audio_samples(&mut self, config: &Config) -> Result<()> {
+        let memory = &config.memory;
+        let dir = assets::theme_dir(&self.theme);
+        if !dir.is_dir() {
+            anyhow::bail!("Theme {} not found in {}", self.theme, dir.display());
+        }
+        let manifest = Manifest::load(&dir)?;
+        self.silent = manifest.silent()?;
+        for keypress in [
+            KeyPressType::Release,
+            KeyPressType::Press,
+            KeyPressType::Hold,
+        ] {
+            for sample in manifest.samples(&dir, &keypress) {
+                let bytes = match Bytes::read(&sample.path, memory.mmap) {
+                    Ok(bytes) => bytes,
+                    Err(_) if !sample.required => continue,
+                    Err(err) => {
+                        return Err(err)
+                            .with_context(|| format!("Failed to read {}", sample.path.display()))
+                    }
+                };
+                let mut clip = Clip::from(bytes);
+                if memory.hot.contains(&sample.sound_type) {
+                    let pcm = cache::decode(&clip.bytes, config.engine.format(), &mut self.cache)
+                        .with_context(|| {
+                        format!("Failed to decode {}", sample.path.display())
+                    })?;
+                    clip.pcm = Some(pcm);
+                }
+                let mut sound = KeyboardButtonSound::new(sample.sound_type, clip, sample.weight);
+                sound.velocity = sample.velocity;
+                sound.held = sample.held;
+                sound.path = Some(sample.path);
+                match keypress {
+                    KeyPressType::Press => self.audio_press.push(sound),
+                    KeyPressType::Release => self.audio_release.push(sound),
+                    KeyPressType::Hold => self.audio_hold.push(sound),
                 }
-                Ok(())
             }
         }
+        if let Ok(bytes) = Bytes::read(&manifest.combo(&dir).path, memory.mmap) {
+            self.combo = Some(bytes.into());
+        }
+        if let Ok(bytes) = Bytes::read(&manifest.chord(&dir).path, memory.mmap) {
+            self.chord = Some(bytes.into());
+        }
+        self.gain = Gain::new(
+            config.auto_gain,
+            manifest.default_volume,
+            &self.audio_press,
+            config.engine.format(),
+        );
+        Ok(())
     }
 
-    fn load_audio_on_memory(
-        &mut self,
-        audio: &(&str, SoundType),
-        keypress: KeyPressType,
-    ) -> Result<()> {
-        let dir = match keypress {
-            KeyPressType::Press => "press",
-            KeyPressType::Release => "release",
-        };
-
-        let filepath = format!("{}/{}/{}/{}", ASSETS, CHERRYMXBROWN, dir, audio.0);
-        let path_buf = PathBuf::from(filepath);
-
-        if let Ok(f) = File::open(&path_buf) {
-            let mut buffer = Vec::new();
-            let mut file = BufReader::new(f);
-            file.read_to_end(&mut buffer)?;
-            let sound = KeyboardButtonSound::new(audio.1.clone(), buffer);
-            if dir == "press" {
-                self.audio_press.push(sound);
-            } else {
-                self.audio_release.push(sound);
+    // The note of the key when the theme has one, a sample from the pools
+    // otherwise. A release after a tap or a hold plays the theme's short or
+    // long variant when it has one.
+    fn sound(
+        &self,
+        event: &KeyEvent,
+        velocity: Option<Velocity>,
+        held: Option<Held>,
+    ) -> Option<Clip> {
+        let note = event.key.and_then(|key| self.notes.get(&key));
+        match (note, &event.keypress) {
+            (Some(note), KeyPressType::Press) => Some(note.press.clone()),
+            (Some(note), KeyPressType::Release) => note.release.clone(),
+            (None, KeyPressType::Release)
+                if held.is_some() && self.audio_release.iter().any(|item| item.held == held) =>
+            {
+                let variants: Vec<_> = self
+                    .audio_release
+                    .iter()
+                    .filter(|item| item.held == held)
+                    .collect();
+                variants
+                    .choose_weighted(&mut rand::thread_rng(), |item| item.weight)
+                    .ok()
+                    .map(|item| item.clip.clone())
             }
+            _ => self.get_audio_data(
+                &(event.keypress.clone(), event.sound_type.clone()),
+                velocity,
+            ),
         }
-        Ok(())
     }
 
-    fn get_audio_data(&self, keypress: &(KeyPressType, SoundType)) -> Option<Vec<u8>> {
+    // Picks from the variants for `velocity` when the theme has them, and from
+    // the plain pool otherwise.
+    fn get_audio_data(
+        &self,
+        keypress: &(KeyPressType, SoundType),
+        velocity: Option<Velocity>,
+    ) -> Option<Clip> {
         let sounds = match keypress.0 {
             KeyPressType::Press => &self.audio_press,
             KeyPressType::Release => &self.audio_release,
+            KeyPressType::Hold => &self.audio_hold,
         };
-        let filtered_sound: Vec<_> = sounds
+        // A theme's hold sound is shared by every kind of key.
+        let matching: Vec<_> = sounds
             .iter()
-            .filter(|item| item.sound_type == keypress.1)
+            .filter(|item| item.held.is_none())
+            .filter(|item| item.sound_type == keypress.1 || keypress.0 == KeyPressType::Hold)
             .collect();
+        let with_velocity = |velocity: Option<Velocity>| -> Vec<_> {
+            matching
+                .iter()
+                .copied()
+                .filter(|item| item.velocity == velocity)
+                .collect()
+        };
+        let mut filtered_sound = velocity
+            .map(|velocity| with_velocity(Some(velocity)))
+            .unwrap_or_default();
+        if filtered_sound.is_empty() {
+            filtered_sound = with_velocity(None);
+        }
+        if filtered_sound.is_empty() {
+            filtered_sound = matching;
+        }
         let mut rng = rand::thread_rng();
         filtered_sound
-            .choose(&mut rng)
-            .map(|item| item.data.clone())
+            .choose_weighted(&mut rng, |item| item.weight)
+            .ok()
+            .map(|item| item.clip.clone())
     }
 }
 
 fn main() -> Result<()> {
-    let (tx, rx) = mpsc::channel::<(KeyPressType, SoundType)>();
-    let (_stream, stream_handle) = OutputStream::try_default()?;
-    let app = Arc::new(Mutex::new(AppState::new(Theme::CherryMXBrown)));
-    let cloned_app = Arc::clone(&app);
-    let audio_load_handler = thread::spawn(move || {
-        load_and_handle_audio(cloned_app, stream_handle, rx);
-    });
-    let keyboard_thread_handler = thread::spawn(move || {
-        handle_keyboard(tx);
-    });
-    keyboard_thread_handler.join().unwrap();
-    audio_load_handler.join().unwrap();
-    Ok(())
+    crash::install_hook();
+    let cli = Cli::parse();
+    match &cli.command {
+        None => run(&cli),
+        #[cfg(feature = "gui")]
+        Some(Command::Settings) => gui::run(Config::path()?),
+        #[cfg(not(feature = "gui"))]
+        Some(Command::Settings) => anyhow::bail!("keydio was built without the `gui` feature"),
+        Some(Command::Record {
+            out,
+            duration,
+            with_keys,
+        }) => record(&cli, out, Duration::from_secs(*duration), *with_keys),
+        Some(Command::Replay {
+            file,
+            theme,
+            speed,
+            looped,
+        }) => replay(&cli, file, theme.as_deref(), *speed, *looped),
+        Some(Command::Stress {
+            rate,
+            duration,
+            max_voices,
+            theme,
+        }) => stress(
+            &cli,
+            *rate,
+            Duration::from_secs(*duration),
+            *max_voices,
+            theme.as_deref(),
+        ),
+        Some(Command::Simulate { script, theme }) => {
+            simulate(&cli, script.as_deref(), theme.as_deref())
+        }
+        Some(Command::Install { source, sha256 }) => install::run(source, sha256.as_deref()),
+        Some(Command::Search { query }) => registry::search(query.as_deref()),
+        Some(Command::Info { name }) => registry::info(name),
+        Some(Command::Uninstall { theme }) => install::uninstall(theme),
+        Some(Command::Clean {
+            cache,
+            state,
+            all,
+            yes,
+        }) => clean::run(*cache, *state, *all, *yes),
+        Some(Command::ExportProfile { file }) => bundle::export(file),
+        Some(Command::ImportProfile { file, yes }) => bundle::import(file, *yes),
+        #[cfg(feature = "self-update")]
+        Some(Command::SelfUpdate { check }) => update::run(*check),
+        #[cfg(not(feature = "self-update"))]
+        Some(Command::SelfUpdate { .. }) => {
+            anyhow::bail!("keydio was built without the `self-update` feature")
+        }
+        Some(Command::Autostart { action }) => match action {
+            AutostartAction::Enable { args } => autostart::enable(args),
+            AutostartAction::Disable => autostart::disable(),
+            AutostartAction::Status => autostart::status(),
+        },
+        Some(Command::Cache { action }) => match action {
+            CacheAction::Clear => cache::clear(),
+        },
+        Some(Command::Ctl { command }) => ipc::send(command),
+        Some(Command::Themes) => list_themes(),
+        Some(Command::Validate { themes }) => validate(themes),
+        Some(Command::Keys) => keys(),
+        Some(Command::Convert { from, src, out }) => convert::run(*from, src, out),
+        Some(Command::Pack {
+            theme,
+            name,
+            author,
+            out,
+        }) => pack::run(theme, name.as_deref(), author.as_deref(), out.as_deref()),
+        Some(Command::Play {
+            sound,
+            theme,
+            release,
+            variant,
+            volume,
+        }) => play::run(
+            cli.output,
+            theme.as_deref(),
+            sound,
+            *release,
+            *variant,
+            *volume,
+        ),
+        Some(Command::Bench {
+            theme,
+            events,
+            json,
+        }) => bench::run(theme.as_deref().unwrap_or(DEFAULT_THEME), *events, *json),
+    }
 }
 
-fn load_and_handle_audio(
-    app: Arc<Mutex<AppState>>,
-    stream_handle: OutputStreamHandle,
-    rx: Receiver<(KeyPressType, SoundType)>,
-) {
-    let mut app = app.lock().unwrap();
-    app.load_audio_samples().unwrap();
+struct Engine {
+    state: Arc<SharedState>,
+    stats: Option<Arc<Stats>>,
+    senders: Vec<KeySender>,
+    // Missing with `--no-audio`.
+    audio_thread: Option<thread::JoinHandle<()>>,
+    keyboard_thread: Option<thread::JoinHandle<()>>,
+    // Threads that pass events on elsewhere and end once the senders are gone.
+    forwarders: Vec<thread::JoinHandle<()>>,
+}
 
+impl Engine {
+    // Starts playback. Every sender in `taps` gets a copy of each key event next
+    // to the audio thread. A `theme` override is used for this run only, so the
+    // config file is neither watched nor written back in that case.
+    fn start(cli: &Cli, theme: Option<&str>, taps: Vec<Sender<KeyEvent>>) -> Result<Self> {
+        let config_path = Config::path()?;
+        let mut config = Config::load(&config_path)?;
+        if let Some(theme) = theme {
+            config.theme = theme.to_string();
+        }
+        assets::check(
+            &config
+                .themes()
+                .into_iter()
+                .map(|(theme, _)| theme)
+                .collect::<Vec<_>>(),
+            cli.verbose,
+        )?;
+        let profile = cli.profile().unwrap_or(config.profile);
+        let state = Arc::new(SharedState::new(config, profile));
+        state.metrics().set_debug_latency(cli.debug_latency);
+        crash::set_state(&state);
+        let mut senders = Vec::new();
+        let audio_thread = if cli.no_audio {
+            state.metrics().set_device("none (--no-audio)".to_string());
+            None
+        } else {
+            let (tx, rx) = mpsc::sync_channel::<KeyEvent>(AUDIO_QUEUE);
+            senders.push(KeySender::Audio(tx, Arc::clone(state.metrics())));
+            Some(audio::spawn(Arc::clone(&state), cli.output, rx)?)
+        };
+        if theme.is_none() {
+            config::watch(Arc::clone(&state), config_path);
+        }
+        if state.config().watch_theme {
+            reload::spawn(Arc::clone(&state));
+        }
+        senders.extend(taps.into_iter().map(KeySender::Tap));
+        let stats = (cli.stats || cli.stats_file.is_some())
+            .then(|| Arc::new(Stats::new(Arc::clone(&state))));
+        if let Some(stats) = &stats {
+            let (stats_tx, stats_rx) = mpsc::channel();
+            stats::spawn(Arc::clone(stats), stats_rx, cli.stats_file.clone());
+            senders.push(KeySender::Tap(stats_tx));
+            let stats = Arc::clone(stats);
+            let stats_file = cli.stats_file.clone();
+            state.on_shutdown(move || {
+                let snapshot = stats.snapshot();
+                eprintln!("stats: {}", snapshot.summary());
+                if let Some(file) = stats_file {
+                    if let Err(err) = stats.write(&file) {
+                        eprintln!("{:?}", err);
+                    }
+                }
+            });
+        }
+        if cli.verbose {
+            metrics::spawn_log(Arc::clone(&state));
+        }
+        if cli.stats || cli.verbose {
+            let hook_state = Arc::clone(&state);
+            state.on_shutdown(move || {
+                eprintln!("runtime: {}", hook_state.metrics().snapshot().summary());
+            });
+        }
+        if let Some(path) = &cli.heatmap {
+            let (heatmap_tx, heatmap_rx) = mpsc::channel();
+            let heatmap = Arc::new(Heatmap::open(path.clone())?);
+            let interval = Duration::from_secs(cli.heatmap_interval.max(1) * 60);
+            heatmap::spawn(Arc::clone(&heatmap), heatmap_rx, interval);
+            senders.push(KeySender::Tap(heatmap_tx));
+            state.on_shutdown(move || {
+                if let Err(err) = heatmap.write() {
+                    eprintln!("{:?}", err);
+                }
+            });
+        }
+        #[cfg(feature = "osc")]
+        let forwarders = match &cli.osc_target {
+            Some(target) => {
+                let (osc_tx, osc_rx) = mpsc::channel();
+                senders.push(KeySender::Tap(osc_tx));
+                vec![osc::spawn(target, osc_rx)?]
+            }
+            None => Vec::new(),
+        };
+        #[cfg(not(feature = "osc"))]
+        let forwarders = Vec::new();
+        let handler_state = Arc::clone(&state);
+        ctrlc::set_handler(move || {
+            handler_state.shutdown();
+            std::process::exit(0);
+        })?;
+        Ok(Self {
+            state,
+            stats,
+            senders,
+            audio_thread,
+            keyboard_thread: None,
+            forwarders,
+        })
+    }
+
+    // The keyboard thread supervises the listener, and the audio thread
+    // alongside it.
+    fn listen_keyboard(&mut self) {
+        let state = Arc::clone(&self.state);
+        let senders = self.senders.clone();
+        let audio_thread = self.audio_thread.take();
+        let keyboard_thread = thread::Builder::new()
+            .name("keyboard".to_string())
+            .spawn(move || handle_keyboard(state, senders, audio_thread))
+            .expect("Failed to start the keyboard thread");
+        self.keyboard_thread = Some(keyboard_thread);
+    }
+
+    fn wait(self) -> Result<()> {
+        #[cfg(feature = "tray")]
+        match tray::run(Arc::clone(&self.state)) {
+            Ok(()) => {
+                self.state.shutdown();
+                return Ok(());
+            }
+            Err(err) => eprintln!("Tray icon unavailable, running headless: {:?}", err),
+        }
+        let mut joined = Ok(());
+        if let Some(keyboard_thread) = self.keyboard_thread {
+            joined = crash::join("keyboard", keyboard_thread);
+        }
+        if let Some(audio_thread) = self.audio_thread {
+            joined = joined.and(crash::join("audio", audio_thread));
+        }
+        self.state.shutdown();
+        joined
+    }
+
+    // Lets the audio thread drain whatever is still queued, then shuts down.
+    // Only meaningful without a keyboard listener, which never lets go of its senders.
+    fn finish(self) -> Result<()> {
+        drop(self.senders);
+        let mut joined = Ok(());
+        if let Some(audio_thread) = self.audio_thread {
+            joined = crash::join("audio", audio_thread);
+        }
+        for forwarder in self.forwarders {
+            joined = joined.and(crash::join("forwarder", forwarder));
+        }
+        self.state.shutdown();
+        joined
+    }
+}
+
+fn run(cli: &Cli) -> Result<()> {
+    let _instance = InstanceLock::acquire(cli.force)?;
+    let mut engine = Engine::start(cli, None, Vec::new())?;
+    let schedule = schedule::Schedule::parse(&engine.state.config())?;
+    schedule::spawn(Arc::clone(&engine.state), schedule);
+    automute::spawn(
+        Arc::clone(&engine.state),
+        vec![
+            dnd::condition(),
+            mic::condition(),
+            fullscreen::condition(),
+            screenshare::condition(),
+        ],
+    );
+    let controller = Arc::new(Controller::new(
+        Arc::clone(&engine.state),
+        engine.stats.clone(),
+    ));
+    if let Err(err) = ipc::serve(&engine.state, Arc::clone(&controller)) {
+        eprintln!("Control socket unavailable: {:?}", err);
+    }
+    #[cfg(all(feature = "dbus", target_os = "linux"))]
+    dbus::serve(&engine.state, Arc::clone(&controller));
+    notify::spawn(Arc::clone(&engine.state));
+    engine.listen_keyboard();
+    engine.wait()
+}
+
+fn record(cli: &Cli, out: &Path, duration: Duration, with_keys: bool) -> Result<()> {
+    let _instance = InstanceLock::acquire(cli.force)?;
+    let (tx, rx) = mpsc::channel();
+    let mut engine = Engine::start(cli, None, vec![tx])?;
+    engine.listen_keyboard();
+    let theme = engine.state.config().theme;
+    eprintln!("Waiting for the first keystroke...");
+    let session = Session::record(&rx, &theme, duration, with_keys)?;
+    session.save(out)?;
+    eprintln!(
+        "Recorded {} events to {}",
+        session.events.len(),
+        out.display()
+    );
+    engine.state.shutdown();
+    Ok(())
+}
+
+fn replay(cli: &Cli, file: &Path, theme: Option<&str>, speed: f64, looped: bool) -> Result<()> {
+    if speed <= 0.0 {
+        anyhow::bail!("--speed must be greater than zero");
+    }
+    let session = Session::load(file)?;
+    let engine = Engine::start(cli, theme, Vec::new())?;
+    let theme = engine.state.config().theme;
+    if session.keydio_version != env!("CARGO_PKG_VERSION") {
+        eprintln!(
+            "Warning: session was recorded with keydio {}, this is {}",
+            session.keydio_version,
+            env!("CARGO_PKG_VERSION")
+        );
+    }
+    if session.theme != theme {
+        eprintln!(
+            "Session was recorded with theme {}, replaying with {}",
+            session.theme, theme
+        );
+    }
     loop {
-        match rx.recv() {
-            Ok((key_press, sound_type)) => {
-                if let Some(audio) = app.get_audio_data(&(key_press, sound_type)) {
-                    if let Ok(source) = Decoder::new(Cursor::new(audio)) {
-                        stream_handle.play_raw(source.convert_samples()).unwrap();
+        session.play(speed, |event| dispatch(&engine.senders, event));
+        if !looped {
+            break;
+        }
+    }
+    engine.finish()
+}
+
+fn simulate(cli: &Cli, script: Option<&Path>, theme: Option<&str>) -> Result<()> {
+    let steps = simulate::load(script)?;
+    let engine = Engine::start(cli, theme, Vec::new())?;
+    for step in steps {
+        match step {
+            simulate::Step::Key(key, keypress) => {
+                if engine.state.silenced_by(&key).is_none() {
+                    dispatch(&engine.senders, KeyEvent::new(key, keypress));
+                }
+            }
+            simulate::Step::Sleep(duration) => thread::sleep(duration),
+        }
+    }
+    engine.finish()
+}
+
+fn stress(
+    cli: &Cli,
+    rate: u32,
+    duration: Duration,
+    max_voices: Option<u64>,
+    theme: Option<&str>,
+) -> Result<()> {
+    if rate == 0 {
+        anyhow::bail!("--rate must be greater than zero");
+    }
+    let engine = Engine::start(cli, theme, Vec::new())?;
+    let state = Arc::clone(&engine.state);
+    let storm = stress::generate(&state, rate, duration, |event| {
+        dispatch(&engine.senders, event)
+    });
+    engine.finish()?;
+    let max_voices = max_voices.unwrap_or(state.config().max_voices as u64);
+    stress::report(
+        &storm,
+        &state.metrics().snapshot(),
+        max_voices,
+        AUDIO_QUEUE as u64,
+    )
+}
+
+fn validate(themes: &[String]) -> Result<()> {
+    let themes = if themes.is_empty() {
+        // The musical theme is built in, there is nothing to validate.
+        available_themes()
+            .into_iter()
+            .filter(|theme| theme != musical::THEME)
+            .collect()
+    } else {
+        themes.to_vec()
+    };
+    // Samples are converted at load; list each one's format so a theme
+    // mixing rates or channel counts is easy to spot.
+    let config = Config::load(&Config::path()?)?;
+    let engine = config.engine.format();
+    let mut failed = 0;
+    for theme in &themes {
+        let dir = assets::theme_dir(theme);
+        match manifest::validate(&dir) {
+            Ok(samples) => {
+                println!("{}: ok ({} samples)", theme, samples.len());
+                for (path, format) in samples {
+                    let path = path.strip_prefix(&dir).unwrap_or(&path).display();
+                    if format == engine {
+                        println!("  {}: {}", path, format);
+                    } else {
+                        println!("  {}: {}, converted to {}", path, format, engine);
                     }
                 }
+                let mut app = AppState::new(theme);
+                match app.load(&config) {
+                    Ok(()) => println!("  gain: {}", app.gain),
+                    Err(err) => println!("  gain: {:?}", err),
+                }
             }
             Err(err) => {
-                eprintln!("Error receiving message: {:?}", err);
-                break;
+                println!("{}: {:?}", theme, err);
+                failed += 1;
             }
         }
     }
+    if failed > 0 {
+        anyhow::bail!("{} of {} themes failed validation", failed, themes.len());
+    }
+    Ok(())
+}
+
+// `keydio themes`: every theme keydio can find, with the gain `auto_gain`
+// or its `default_volume` gives it. The configured theme is starred.
+fn list_themes() -> Result<()> {
+    let config = Config::load(&Config::path()?)?;
+    for theme in available_themes() {
+        let current = if theme == config.theme { "*" } else { " " };
+        let mut app = AppState::new(&theme);
+        match app.load(&config) {
+            Ok(()) if theme == musical::THEME => println!("{} {:<20} built in", current, theme),
+            Ok(()) => println!("{} {:<20} {}", current, theme, app.gain),
+            Err(err) => println!("{} {:<20} failed to load: {:?}", current, theme, err),
+        }
+    }
+    Ok(())
+}
+
+// Prints key events as keydio sees them, without playing anything.
+fn keys() -> Result<()> {
+    let state = Arc::new(SharedState::new(
+        Config::load(&Config::path()?)?,
+        Profile::default(),
+    ));
+    let config = state.config();
+    let mut theme = AppState::new(&config.theme);
+    theme.load(&config)?;
+    state.set_theme_silent(theme.silent);
+    eprintln!(
+        "Keyboard layout: {}{}",
+        layout::active(),
+        if layout::is_detected() {
+            " (detected)"
+        } else {
+            ""
+        }
+    );
+    eprintln!("Printing key events, press Ctrl+C to stop");
+    // Each key under the name device_query gives it and the position and
+    // character it resolves to, the names a theme can use for it.
+    let print = move |key: &Keycode, keypress: KeyPressType| {
+        let event = KeyEvent::new(*key, keypress);
+        let note = match state.silenced_by(key) {
+            Some(Silencer::User) => "  silenced by the ignore list",
+            Some(Silencer::Theme) => "  silenced by the theme",
+            None => "",
+        };
+        let character = layout::character(*key)
+            .map(|c| format!("char:{}", c))
+            .unwrap_or_default();
+        println!(
+            "{:<8} {:<14} {:<22} {:<8} {:?}{}",
+            format!("{:?}", event.keypress),
+            key.to_string(),
+            layout::physical_name(*key),
+            character,
+            event.sound_type,
+            note
+        );
+    };
+    let print = Arc::new(print);
+    let device_state = DeviceState::new();
+    let _guard_release = device_state.on_key_up({
+        let print = Arc::clone(&print);
+        move |key| print(key, KeyPressType::Release)
+    });
+    let _guard_down = device_state.on_key_down(move |key| print(key, KeyPressType::Press));
+    loop {
+        thread::park();
+    }
 }
 
 fn map_key_to_sound(key: &Keycode) -> SoundType {
@@ -176,22 +862,75 @@ fn map_key_to_sound(key: &Keycode) -> SoundType {
     }
 }
 
-fn handle_keyboard(tx: Sender<(KeyPressType, SoundType)>) {
-    let device_state = DeviceState::new();
-    let _guard_release = device_state.on_key_up({
-        let tx = tx.clone();
-        move |key| {
-            let sound_type = map_key_to_sound(key);
-            if let Err(err) = tx.send((KeyPressType::Release, sound_type)) {
-                eprintln!("Failed to send key release event: {:?}", err.to_string());
+// Where `dispatch` sends key events: the audio thread's bounded queue, which
+// counts what it has no room for, or a tap that takes everything.
+#[derive(Clone)]
+enum KeySender {
+    Audio(SyncSender<KeyEvent>, Arc<Metrics>),
+    Tap(Sender<KeyEvent>),
+}
+
+fn dispatch(senders: &[KeySender], event: KeyEvent) {
+    for sender in senders {
+        let sent = match sender {
+            KeySender::Audio(tx, metrics) => match tx.try_send(event.clone()) {
+                Ok(()) => Ok(()),
+                Err(TrySendError::Full(_)) => {
+                    metrics.overflowed();
+                    Ok(())
+                }
+                Err(TrySendError::Disconnected(event)) => Err(SendError(event)),
+            },
+            KeySender::Tap(tx) => tx.send(event.clone()),
+        };
+        if let Err(err) = sent {
+            eprintln!("Failed to send key event: {:?}", err);
+        }
+    }
+}
+
+// Only returns by ending keydio: when the listener keeps dying, or the audio
+// thread is gone, there is no point running on in silence.
+fn handle_keyboard(
+    state: Arc<SharedState>,
+    senders: Vec<KeySender>,
+    audio_thread: Option<thread::JoinHandle<()>>,
+) {
+    let senders = Arc::new(senders);
+    let hold = hold::spawn(Arc::clone(&state), Arc::clone(&senders));
+    let on_key: supervise::OnKey = Arc::new({
+        let state = Arc::clone(&state);
+        move |key, keypress| {
+            if keypress == KeyPressType::Release {
+                let _ = hold.send(hold::Edge::Up(key));
+            }
+            if state.silenced_by(&key).is_some() {
+                return;
+            }
+            dispatch(&senders, KeyEvent::new(key, keypress.clone()));
+            if keypress == KeyPressType::Press {
+                let _ = hold.send(hold::Edge::Down(key));
             }
         }
     });
-    let _guard_down = device_state.on_key_down(move |key| {
-        let sound_type = map_key_to_sound(key);
-        if let Err(err) = tx.send((KeyPressType::Press, sound_type)) {
-            eprintln!("Failed to send key press event: {:?}", err);
+    let mut audio_pulse = supervise::Pulse::new(state.audio_heartbeat());
+    let companion = || match &audio_thread {
+        Some(audio_thread) if audio_thread.is_finished() => {
+            anyhow::bail!("The audio thread stopped")
         }
-    });
-    loop {}
+        Some(_) if audio_pulse.stalled(Instant::now(), AUDIO_STALL) => {
+            anyhow::bail!("The audio thread stopped responding")
+        }
+        _ => Ok(()),
+    };
+    if let Err(err) = supervise::run(
+        supervise::Settings::default(),
+        Arc::new(|| Box::new(supervise::Keyboard::new())),
+        on_key,
+        companion,
+    ) {
+        eprintln!("{:?}", err);
+    }
+    state.shutdown();
+    std::process::exit(1);
 }
@@ -0,0 +1,397 @@
+use crate::{
+    hold::Held,
+    keys::KeySet,
+    sample::{Bytes, Format},
+    velocity::Velocity,
+    KeyPressType, SoundType, AUDIOFILE,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use rodio::Decoder;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    io::Cursor,
+    path::{Path, PathBuf},
+};
+
+pub const MANIFEST_FILE: &str = "theme.toml";
+// Optional, in the press directory; played by `KeyPressType::Hold`.
+const HOLD_FILE: &str = "HOLD.mp3";
+// Optional, in the press directory; played after a streak of keystrokes.
+const COMBO_FILE: &str = "COMBO.mp3";
+// Optional, in the press directory; played once for keys pressed together.
+const CHORD_FILE: &str = "CHORD.mp3";
+// Optional, in the release directory; played for releases after a quick tap
+// or a long hold.
+const RELEASE_SHORT_FILE: &str = "RELEASE_SHORT.mp3";
+const RELEASE_LONG_FILE: &str = "RELEASE_LONG.mp3";
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum PoolEntry {
+    File(String),
+    Weighted { file: String, weight: f32 },
+}
+
+impl PoolEntry {
+    fn file(&self) -> &str {
+        match self {
+            PoolEntry::File(file) | PoolEntry::Weighted { file, .. } => file,
+        }
+    }
+
+    fn weight(&self) -> f32 {
+        match self {
+            PoolEntry::File(_) => 1.0,
+            PoolEntry::Weighted { weight, .. } => *weight,
+        }
+    }
+}
+
+// `<type>_soft` and `<type>_hard` are the velocity variants of a sound type,
+// picked by typing speed when velocity is on (see `velocity`). `short` and
+// `long`, only for releases, replace every sound type's release after a tap
+// or a hold (see `ReleaseConfig`).
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Pools {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backspace: Option<Vec<PoolEntry>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backspace_soft: Option<Vec<PoolEntry>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backspace_hard: Option<Vec<PoolEntry>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub enter: Option<Vec<PoolEntry>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub enter_soft: Option<Vec<PoolEntry>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub enter_hard: Option<Vec<PoolEntry>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub generic: Option<Vec<PoolEntry>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub generic_soft: Option<Vec<PoolEntry>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub generic_hard: Option<Vec<PoolEntry>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub space: Option<Vec<PoolEntry>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub space_soft: Option<Vec<PoolEntry>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub space_hard: Option<Vec<PoolEntry>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub short: Option<Vec<PoolEntry>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub long: Option<Vec<PoolEntry>>,
+}
+
+pub(crate) const SOUND_TYPES: [(&str, SoundType); 4] = [
+    ("backspace", SoundType::Backspace),
+    ("enter", SoundType::Enter),
+    ("generic", SoundType::Generic),
+    ("space", SoundType::Space),
+];
+const VELOCITIES: [(&str, Option<Velocity>); 3] = [
+    ("", None),
+    ("_soft", Some(Velocity::Soft)),
+    ("_hard", Some(Velocity::Hard)),
+];
+
+impl Pools {
+    fn get(&self, sound_type: &SoundType, velocity: Option<Velocity>) -> &Option<Vec<PoolEntry>> {
+        match (sound_type, velocity) {
+            (SoundType::Backspace, None) => &self.backspace,
+            (SoundType::Backspace, Some(Velocity::Soft)) => &self.backspace_soft,
+            (SoundType::Backspace, Some(Velocity::Hard)) => &self.backspace_hard,
+            (SoundType::Enter, None) => &self.enter,
+            (SoundType::Enter, Some(Velocity::Soft)) => &self.enter_soft,
+            (SoundType::Enter, Some(Velocity::Hard)) => &self.enter_hard,
+            (SoundType::Generic, None) => &self.generic,
+            (SoundType::Generic, Some(Velocity::Soft)) => &self.generic_soft,
+            (SoundType::Generic, Some(Velocity::Hard)) => &self.generic_hard,
+            (SoundType::Space, None) => &self.space,
+            (SoundType::Space, Some(Velocity::Soft)) => &self.space_soft,
+            (SoundType::Space, Some(Velocity::Hard)) => &self.space_hard,
+        }
+    }
+
+    fn held(&self) -> [(&str, Held, &Option<Vec<PoolEntry>>, &str); 2] {
+        [
+            ("short", Held::Short, &self.short, RELEASE_SHORT_FILE),
+            ("long", Held::Long, &self.long, RELEASE_LONG_FILE),
+        ]
+    }
+
+    fn is_empty(&self) -> bool {
+        SOUND_TYPES.iter().all(|(_, sound_type)| {
+            VELOCITIES
+                .iter()
+                .all(|(_, velocity)| self.get(sound_type, *velocity).is_none())
+        }) && self.short.is_none()
+            && self.long.is_none()
+    }
+
+    pub fn get_mut(&mut self, sound_type: &SoundType) -> &mut Option<Vec<PoolEntry>> {
+        match sound_type {
+            SoundType::Backspace => &mut self.backspace,
+            SoundType::Enter => &mut self.enter,
+            SoundType::Generic => &mut self.generic,
+            SoundType::Space => &mut self.space,
+        }
+    }
+}
+
+pub struct Sample {
+    pub path: PathBuf,
+    pub sound_type: SoundType,
+    // Only for the velocity variants.
+    pub velocity: Option<Velocity>,
+    // Only for the short and long release variants.
+    pub held: Option<Held>,
+    pub weight: f32,
+    // Declared in the manifest, so it has to exist. The built-in file names
+    // are only used when they are there.
+    pub required: bool,
+}
+
+// A theme's optional `theme.toml`. It lists the files each sound type picks
+// from at random, per press/release directory, e.g.
+// `generic = ["g1.mp3", { file = "g2.mp3", weight = 2.0 }]`. Sound types
+// without a pool use the built-in file names. `silent` lists keys the theme
+// never plays, in the same syntax as the user's ignore list.
+// `default_volume` scales the theme's samples instead of the gain measured
+// for `auto_gain`. `name` and `author` are informational.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Manifest {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default_volume: Option<f32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub silent: Vec<String>,
+    #[serde(skip_serializing_if = "Pools::is_empty")]
+    pub press: Pools,
+    #[serde(skip_serializing_if = "Pools::is_empty")]
+    pub release: Pools,
+}
+
+impl Manifest {
+    pub fn load(theme_dir: &Path) -> Result<Self> {
+        let path = theme_dir.join(MANIFEST_FILE);
+        let manifest: Self = match fs::read_to_string(&path) {
+            Ok(contents) => toml::from_str(&contents)
+                .with_context(|| format!("Invalid theme manifest {}", path.display()))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => {
+                return Err(err).with_context(|| format!("Failed to read {}", path.display()))
+            }
+        };
+        manifest
+            .check()
+            .with_context(|| format!("Invalid theme manifest {}", path.display()))?;
+        Ok(manifest)
+    }
+
+    fn check(&self) -> Result<()> {
+        KeySet::parse(&self.silent).context("Invalid silent list")?;
+        if let Some(volume) = self.default_volume {
+            if !(volume.is_finite() && volume > 0.0) {
+                bail!("default_volume has to be above zero");
+            }
+        }
+        if let Some((name, ..)) = self
+            .press
+            .held()
+            .iter()
+            .find(|(_, _, pool, _)| pool.is_some())
+        {
+            bail!("press.{}: only releases have short and long variants", name);
+        }
+        for (dir, pools) in [("press", &self.press), ("release", &self.release)] {
+            for (name, sound_type) in &SOUND_TYPES {
+                for (suffix, velocity) in &VELOCITIES {
+                    if let Some(pool) = pools.get(sound_type, *velocity) {
+                        check_pool(&format!("{}.{}{}", dir, name, suffix), pool)?;
+                    }
+                }
+            }
+            for (name, _, pool, _) in pools.held() {
+                if let Some(pool) = pool {
+                    check_pool(&format!("{}.{}", dir, name), pool)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn silent(&self) -> Result<KeySet> {
+        KeySet::parse(&self.silent)
+    }
+
+    pub fn samples(&self, theme_dir: &Path, keypress: &KeyPressType) -> Vec<Sample> {
+        let (dir, pools) = match keypress {
+            KeyPressType::Press => (theme_dir.join("press"), &self.press),
+            KeyPressType::Release => (theme_dir.join("release"), &self.release),
+            KeyPressType::Hold => {
+                return vec![Sample {
+                    path: theme_dir.join("press").join(HOLD_FILE),
+                    sound_type: SoundType::Generic,
+                    velocity: None,
+                    held: None,
+                    weight: 1.0,
+                    required: false,
+                }]
+            }
+        };
+        let mut samples = Vec::new();
+        if *keypress == KeyPressType::Release {
+            for (_, held, pool, file) in pools.held() {
+                let Some(pool) = pool else {
+                    samples.push(Sample {
+                        path: dir.join(file),
+                        sound_type: SoundType::Generic,
+                        velocity: None,
+                        held: Some(held),
+                        weight: 1.0,
+                        required: false,
+                    });
+                    continue;
+                };
+                samples.extend(pool.iter().map(|entry| Sample {
+                    path: dir.join(entry.file()),
+                    sound_type: SoundType::Generic,
+                    velocity: None,
+                    held: Some(held),
+                    weight: entry.weight(),
+                    required: true,
+                }));
+            }
+        }
+        for (_, sound_type) in &SOUND_TYPES {
+            match pools.get(sound_type, None) {
+                Some(pool) => samples.extend(pool.iter().map(|entry| Sample {
+                    path: dir.join(entry.file()),
+                    sound_type: sound_type.clone(),
+                    velocity: None,
+                    held: None,
+                    weight: entry.weight(),
+                    required: true,
+                })),
+                None => {
+                    samples.extend(AUDIOFILE.iter().filter(|(_, kind)| kind == sound_type).map(
+                        |(file, _)| Sample {
+                            path: dir.join(file),
+                            sound_type: sound_type.clone(),
+                            velocity: None,
+                            held: None,
+                            weight: 1.0,
+                            required: false,
+                        },
+                    ))
+                }
+            }
+            for velocity in [Velocity::Soft, Velocity::Hard] {
+                if let Some(pool) = pools.get(sound_type, Some(velocity)) {
+                    samples.extend(pool.iter().map(|entry| Sample {
+                        path: dir.join(entry.file()),
+                        sound_type: sound_type.clone(),
+                        velocity: Some(velocity),
+                        held: None,
+                        weight: entry.weight(),
+                        required: true,
+                    }));
+                }
+            }
+        }
+        samples
+    }
+
+    // The combo sound, when the theme has one.
+    pub fn combo(&self, theme_dir: &Path) -> Sample {
+        Sample {
+            path: theme_dir.join("press").join(COMBO_FILE),
+            sound_type: SoundType::Generic,
+            velocity: None,
+            held: None,
+            weight: 1.0,
+            required: false,
+        }
+    }
+
+    // The chord sound, when the theme has one.
+    pub fn chord(&self, theme_dir: &Path) -> Sample {
+        Sample {
+            path: theme_dir.join("press").join(CHORD_FILE),
+            sound_type: SoundType::Generic,
+            velocity: None,
+            held: None,
+            weight: 1.0,
+            required: false,
+        }
+    }
+}
+
+fn check_pool(name: &str, pool: &[PoolEntry]) -> Result<()> {
+    if pool.is_empty() {
+        bail!("{} is an empty pool", name);
+    }
+    if let Some(entry) = pool
+        .iter()
+        .find(|entry| entry.weight().is_nan() || entry.weight() <= 0.0)
+    {
+        bail!("{}: {} needs a weight above zero", name, entry.file());
+    }
+    Ok(())
+}
+
+// A sample is usable once it decodes all the way through. Returns the format
+// it is stored in.
+pub fn check_decodes(data: Vec<u8>) -> Result<Format> {
+    let bytes = Bytes::from(data);
+    let format = Format::probe(&bytes)?;
+    Decoder::new(Cursor::new(bytes))?.for_each(drop);
+    Ok(format)
+}
+
+// Checks that the manifest parses and that every sample the theme would play
+// decodes. Returns the samples checked, with the format each is stored in.
+pub fn validate(theme_dir: &Path) -> Result<Vec<(PathBuf, Format)>> {
+    if !theme_dir.is_dir() {
+        bail!("{} is not a theme directory", theme_dir.display());
+    }
+    let manifest = Manifest::load(theme_dir)?;
+    let mut checked = Vec::new();
+    let mut problems = Vec::new();
+    for keypress in [
+        KeyPressType::Press,
+        KeyPressType::Release,
+        KeyPressType::Hold,
+    ] {
+        let mut samples = manifest.samples(theme_dir, &keypress);
+        if keypress == KeyPressType::Hold {
+            samples.push(manifest.combo(theme_dir));
+            samples.push(manifest.chord(theme_dir));
+        }
+        for sample in samples {
+            let data = match fs::read(&sample.path) {
+                Ok(data) => data,
+                Err(_) if !sample.required => continue,
+                Err(err) => {
+                    problems.push(format!("{}: {}", sample.path.display(), err));
+                    continue;
+                }
+            };
+            match check_decodes(data) {
+                Ok(format) => checked.push((sample.path, format)),
+                Err(err) => problems.push(format!("{}: {}", sample.path.display(), err)),
+            }
+        }
+    }
+    if !problems.is_empty() {
+        return Err(anyhow!(problems.join("\n")));
+    }
+    Ok(checked)
+}
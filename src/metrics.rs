@@ -0,0 +1,490 @@
+use crate::{cache, sample::Clip, state::SharedState, AppState, KeyboardButtonSound};
+use rodio::Source;
+use serde::Serialize;
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+const LOG_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct SampleCounts {
+    pub press: u64,
+    pub release: u64,
+    pub hold: u64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct OutputStats {
+    pub device: String,
+    pub open: bool,
+    pub played: u64,
+    // Failed opens, failed plays and disconnects.
+    pub errors: u64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Runtime {
+    pub uptime_seconds: u64,
+    pub device: String,
+    pub samples: SampleCounts,
+    // Encoded samples of the loaded theme, read onto the heap or mapped.
+    pub heap_bytes: u64,
+    pub mapped_bytes: u64,
+    // Samples of the hot sound types, decoded at load.
+    pub pcm_bytes: u64,
+    // How the hot samples were found in the on-disk cache, and its size.
+    pub pcm_cache_hits: u64,
+    pub pcm_cache_misses: u64,
+    pub pcm_cache_bytes: u64,
+    // Samples of the other sound types, decoded as they first played.
+    pub decoded_bytes: u64,
+    pub events_received: u64,
+    pub events_played: u64,
+    // Received but not played: muted, filtered by the mode, every voice
+    // over the cap or failed.
+    pub events_dropped: u64,
+    pub events_muted: u64,
+    pub events_filtered: u64,
+    pub events_capped: u64,
+    // Never received: the audio queue was full.
+    pub events_overflowed: u64,
+    pub voices_active: u64,
+    pub voices_peak: u64,
+    // Voices not started because `max_voices` were playing.
+    pub voices_dropped: u64,
+    // From a keystroke to the first sample of its sound leaving for the
+    // output, over every voice played.
+    pub latency_avg_ms: f64,
+    pub latency_max_ms: f64,
+    pub outputs: Vec<OutputStats>,
+}
+
+impl Runtime {
+    pub fn summary(&self) -> String {
+        let mut summary = format!(
+            "up {}s on {}, samples {} press / {} release / {} hold, \
+             {} heap / {} mapped / {} pcm / {} decoded bytes, \
+             pcm cache {} hits / {} misses / {} bytes, \
+             events {} received / {} played / {} dropped, \
+             {} voices / {} over the cap",
+            self.uptime_seconds,
+            self.device,
+            self.samples.press,
+            self.samples.release,
+            self.samples.hold,
+            self.heap_bytes,
+            self.mapped_bytes,
+            self.pcm_bytes,
+            self.decoded_bytes,
+            self.pcm_cache_hits,
+            self.pcm_cache_misses,
+            self.pcm_cache_bytes,
+            self.events_received,
+            self.events_played,
+            self.events_dropped,
+            self.voices_active,
+            self.voices_dropped
+        );
+        if self.latency_max_ms > 0.0 {
+            summary.push_str(&format!(
+                ", latency {:.1} ms avg / {:.1} ms max",
+                self.latency_avg_ms, self.latency_max_ms
+            ));
+        }
+        // With more than one output, how each of them fares.
+        if self.outputs.len() > 1 {
+            let outputs: Vec<String> = self
+                .outputs
+                .iter()
+                .map(|output| {
+                    format!(
+                        "{} {} played / {} errors{}",
+                        output.device,
+                        output.played,
+                        output.errors,
+                        if output.open { "" } else { " (closed)" }
+                    )
+                })
+                .collect();
+            summary.push_str(&format!("; outputs {}", outputs.join(", ")));
+        }
+        summary
+    }
+}
+
+// The counters of one configured output. The audio thread keeps them across
+// reopening the output, so they count from startup.
+pub struct OutputCounters {
+    device: String,
+    open: AtomicBool,
+    played: AtomicU64,
+    errors: AtomicU64,
+}
+
+impl OutputCounters {
+    pub fn set_open(&self, open: bool) {
+        self.open.store(open, Ordering::Relaxed);
+    }
+
+    pub fn played(&self) {
+        self.played.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// What the engine holds and does. The counters on the playback path are
+// plain atomics, so updating them never waits.
+pub struct Metrics {
+    started: Instant,
+    device: Mutex<String>,
+    samples_press: AtomicU64,
+    samples_release: AtomicU64,
+    samples_hold: AtomicU64,
+    heap_bytes: AtomicU64,
+    mapped_bytes: AtomicU64,
+    pcm_bytes: AtomicU64,
+    pcm_cache_hits: AtomicU64,
+    pcm_cache_misses: AtomicU64,
+    pcm_cache_bytes: AtomicU64,
+    decoded_bytes: AtomicU64,
+    events_received: AtomicU64,
+    events_played: AtomicU64,
+    events_muted: AtomicU64,
+    events_filtered: AtomicU64,
+    events_capped: AtomicU64,
+    events_overflowed: AtomicU64,
+    voices_active: AtomicU64,
+    voices_peak: AtomicU64,
+    voices_dropped: AtomicU64,
+    latency_count: AtomicU64,
+    latency_total_us: AtomicU64,
+    latency_max_us: AtomicU64,
+    // Set by the cpal backend's callback: how long samples sit in the
+    // device buffer before they are heard.
+    output_buffered_us: AtomicU64,
+    debug_latency: AtomicBool,
+    // Outputs that came back after their device went away.
+    reconnects: AtomicU64,
+    outputs: Mutex<Vec<Arc<OutputCounters>>>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            device: Mutex::new("no device".to_string()),
+            samples_press: AtomicU64::new(0),
+            samples_release: AtomicU64::new(0),
+            samples_hold: AtomicU64::new(0),
+            heap_bytes: AtomicU64::new(0),
+            mapped_bytes: AtomicU64::new(0),
+            pcm_bytes: AtomicU64::new(0),
+            pcm_cache_hits: AtomicU64::new(0),
+            pcm_cache_misses: AtomicU64::new(0),
+            pcm_cache_bytes: AtomicU64::new(0),
+            decoded_bytes: AtomicU64::new(0),
+            events_received: AtomicU64::new(0),
+            events_played: AtomicU64::new(0),
+            events_muted: AtomicU64::new(0),
+            events_filtered: AtomicU64::new(0),
+            events_capped: AtomicU64::new(0),
+            events_overflowed: AtomicU64::new(0),
+            voices_active: AtomicU64::new(0),
+            voices_peak: AtomicU64::new(0),
+            voices_dropped: AtomicU64::new(0),
+            latency_count: AtomicU64::new(0),
+            latency_total_us: AtomicU64::new(0),
+            latency_max_us: AtomicU64::new(0),
+            output_buffered_us: AtomicU64::new(0),
+            debug_latency: AtomicBool::new(false),
+            reconnects: AtomicU64::new(0),
+            outputs: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn set_device(&self, device: String) {
+        *self.device.lock().unwrap() = device;
+    }
+
+    pub fn device(&self) -> String {
+        self.device.lock().unwrap().clone()
+    }
+
+    pub fn reconnected(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn reconnects(&self) -> u64 {
+        self.reconnects.load(Ordering::Relaxed)
+    }
+
+    // The counters of the output on `device`, created the first time it is
+    // opened.
+    pub fn output(&self, device: &str) -> Arc<OutputCounters> {
+        let mut outputs = self.outputs.lock().unwrap();
+        if let Some(counters) = outputs.iter().find(|counters| counters.device == device) {
+            return Arc::clone(counters);
+        }
+        let counters = Arc::new(OutputCounters {
+            device: device.to_string(),
+            open: AtomicBool::new(false),
+            played: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+        });
+        outputs.push(Arc::clone(&counters));
+        counters
+    }
+
+    // Counts over every loaded theme layer; key notes count as samples.
+    pub fn theme_loaded(&self, themes: &[AppState]) {
+        let count = |sounds: fn(&AppState) -> &Vec<KeyboardButtonSound>| {
+            themes
+                .iter()
+                .map(|app| sounds(app).len() as u64)
+                .sum::<u64>()
+        };
+        let notes = themes.iter().flat_map(|app| app.notes.values());
+        let release_notes = notes.clone().filter(|note| note.release.is_some()).count() as u64;
+        self.samples_press.store(
+            count(|app| &app.audio_press) + notes.clone().count() as u64,
+            Ordering::Relaxed,
+        );
+        self.samples_release.store(
+            count(|app| &app.audio_release) + release_notes,
+            Ordering::Relaxed,
+        );
+        self.samples_hold
+            .store(count(|app| &app.audio_hold), Ordering::Relaxed);
+        let clips: Vec<&Clip> = themes
+            .iter()
+            .flat_map(|app| [&app.audio_press, &app.audio_release, &app.audio_hold])
+            .flat_map(|sounds| sounds.iter().map(|sound| &sound.clip))
+            .chain(notes.flat_map(|note| std::iter::once(&note.press).chain(&note.release)))
+            .collect();
+        let (mapped, heap): (Vec<&Clip>, Vec<&Clip>) =
+            clips.iter().partition(|clip| clip.bytes.is_mapped());
+        let total = |clips: &[&Clip]| clips.iter().map(|clip| clip.bytes.len() as u64).sum();
+        self.heap_bytes.store(total(&heap), Ordering::Relaxed);
+        self.mapped_bytes.store(total(&mapped), Ordering::Relaxed);
+        let pcm_bytes = clips
+            .iter()
+            .filter_map(|clip| clip.pcm.as_ref())
+            .map(|pcm| pcm.bytes() as u64)
+            .sum();
+        self.pcm_bytes.store(pcm_bytes, Ordering::Relaxed);
+        let decoded_bytes = clips.iter().map(|clip| clip.decoded_bytes()).sum();
+        self.decoded_bytes.store(decoded_bytes, Ordering::Relaxed);
+        let hits = themes.iter().map(|app| app.cache.hits).sum();
+        let misses = themes.iter().map(|app| app.cache.misses).sum();
+        self.pcm_cache_hits.store(hits, Ordering::Relaxed);
+        self.pcm_cache_misses.store(misses, Ordering::Relaxed);
+        self.pcm_cache_bytes.store(cache::size(), Ordering::Relaxed);
+    }
+
+    pub fn received(&self) {
+        self.events_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn played(&self) {
+        self.events_played.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn muted(&self) {
+        self.events_muted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Dropped because the mode does not play this kind of event.
+    pub fn filtered(&self) {
+        self.events_filtered.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Dropped because every voice it would have started was over the cap.
+    pub fn capped(&self) {
+        self.events_capped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Dropped before the audio thread saw it, its queue being full.
+    pub fn overflowed(&self) {
+        self.events_overflowed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn voices_dropped(&self) -> u64 {
+        self.voices_dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn voices_active(&self) -> u64 {
+        self.voices_active.load(Ordering::Relaxed)
+    }
+
+    pub fn decoded(&self, bytes: u64) {
+        self.decoded_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn voice_dropped(&self) {
+        self.voices_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // For `--debug-latency`: log the latency of every voice as it starts.
+    pub fn set_debug_latency(&self, debug: bool) {
+        self.debug_latency.store(debug, Ordering::Relaxed);
+    }
+
+    pub fn set_output_buffered(&self, buffered: Duration) {
+        self.output_buffered_us
+            .store(buffered.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn latency(&self, latency: Duration) {
+        let micros = latency.as_micros() as u64;
+        self.latency_count.fetch_add(1, Ordering::Relaxed);
+        self.latency_total_us.fetch_add(micros, Ordering::Relaxed);
+        self.latency_max_us.fetch_max(micros, Ordering::Relaxed);
+        if self.debug_latency.load(Ordering::Relaxed) {
+            // Only the cpal backend knows how full the device buffer is.
+            let buffered = match self.output_buffered_us.load(Ordering::Relaxed) {
+                0 => String::new(),
+                buffered => format!(" + {:.2} ms buffered", buffered as f64 / 1000.0),
+            };
+            eprintln!(
+                "latency: {:.2} ms to output{}",
+                micros as f64 / 1000.0,
+                buffered
+            );
+        }
+    }
+
+    pub fn snapshot(&self) -> Runtime {
+        let received = self.events_received.load(Ordering::Relaxed);
+        let played = self.events_played.load(Ordering::Relaxed);
+        Runtime {
+            uptime_seconds: self.started.elapsed().as_secs(),
+            device: self.device.lock().unwrap().clone(),
+            samples: SampleCounts {
+                press: self.samples_press.load(Ordering::Relaxed),
+                release: self.samples_release.load(Ordering::Relaxed),
+                hold: self.samples_hold.load(Ordering::Relaxed),
+            },
+            heap_bytes: self.heap_bytes.load(Ordering::Relaxed),
+            mapped_bytes: self.mapped_bytes.load(Ordering::Relaxed),
+            pcm_bytes: self.pcm_bytes.load(Ordering::Relaxed),
+            pcm_cache_hits: self.pcm_cache_hits.load(Ordering::Relaxed),
+            pcm_cache_misses: self.pcm_cache_misses.load(Ordering::Relaxed),
+            pcm_cache_bytes: self.pcm_cache_bytes.load(Ordering::Relaxed),
+            decoded_bytes: self.decoded_bytes.load(Ordering::Relaxed),
+            events_received: received,
+            events_played: played,
+            events_dropped: received.saturating_sub(played),
+            events_muted: self.events_muted.load(Ordering::Relaxed),
+            events_filtered: self.events_filtered.load(Ordering::Relaxed),
+            events_capped: self.events_capped.load(Ordering::Relaxed),
+            events_overflowed: self.events_overflowed.load(Ordering::Relaxed),
+            voices_active: self.voices_active.load(Ordering::Relaxed),
+            voices_peak: self.voices_peak.load(Ordering::Relaxed),
+            voices_dropped: self.voices_dropped.load(Ordering::Relaxed),
+            latency_avg_ms: match self.latency_count.load(Ordering::Relaxed) {
+                0 => 0.0,
+                count => {
+                    self.latency_total_us.load(Ordering::Relaxed) as f64 / count as f64 / 1000.0
+                }
+            },
+            latency_max_ms: self.latency_max_us.load(Ordering::Relaxed) as f64 / 1000.0,
+            outputs: self
+                .outputs
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|counters| OutputStats {
+                    device: counters.device.clone(),
+                    open: counters.open.load(Ordering::Relaxed),
+                    played: counters.played.load(Ordering::Relaxed),
+                    errors: counters.errors.load(Ordering::Relaxed),
+                })
+                .collect(),
+        }
+    }
+}
+
+// A playing source, counted as an active voice until the mixer drops it. A
+// timed voice records its latency when the mixer first pulls a sample from
+// it.
+pub struct Voice<S> {
+    source: S,
+    metrics: Arc<Metrics>,
+    at: Option<Instant>,
+}
+
+impl<S> Voice<S> {
+    pub fn new(source: S, metrics: &Arc<Metrics>) -> Self {
+        let voices = metrics.voices_active.fetch_add(1, Ordering::Relaxed) + 1;
+        metrics.voices_peak.fetch_max(voices, Ordering::Relaxed);
+        Self {
+            source,
+            metrics: Arc::clone(metrics),
+            at: None,
+        }
+    }
+
+    // `at` is when the keystroke happened.
+    pub fn timed(mut self, at: Instant) -> Self {
+        self.at = Some(at);
+        self
+    }
+}
+
+impl<S> Drop for Voice<S> {
+    fn drop(&mut self) {
+        self.metrics.voices_active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl<S: Source<Item = f32>> Iterator for Voice<S> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if let Some(at) = self.at.take() {
+            self.metrics.latency(at.elapsed());
+        }
+        self.source.next()
+    }
+}
+
+impl<S: Source<Item = f32>> Source for Voice<S> {
+    fn current_frame_len(&self) -> Option<usize> {
+        self.source.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.source.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.source.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.source.total_duration()
+    }
+}
+
+// Logs the runtime counters once a minute, for `--verbose`.
+pub fn spawn_log(state: Arc<SharedState>) {
+    thread::spawn(move || loop {
+        thread::sleep(LOG_INTERVAL);
+        eprintln!("runtime: {}", state.metrics().snapshot().summary());
+    });
+}
@@ -0,0 +1,207 @@
+use crate::{
+    automute::{Condition, POLL_INTERVAL},
+    state::MuteReason,
+};
+use std::time::Duration;
+
+// Mute as soon as a capture stream shows up, but wait a little before
+// unmuting so an app that reopens the microphone does not let sound through.
+const SETTLE_ON: Duration = Duration::ZERO;
+const SETTLE_OFF: Duration = Duration::from_secs(4);
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use crate::config::{Config, DEFAULT_OUTPUT};
+    use anyhow::{bail, Context, Result};
+    use std::{collections::HashMap, process::Command};
+
+    fn pactl(list: &str) -> Result<String> {
+        let output = Command::new("pactl")
+            .args(["list", "short", list])
+            .output()
+            .context("Failed to run pactl")?;
+        if !output.status.success() {
+            bail!("{}", String::from_utf8_lossy(&output.stderr).trim());
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    // Recording a sink's monitor captures what plays, not a voice, and that
+    // includes whatever records keydio's own virtual output.
+    fn is_own(config: &Config, source: &str) -> bool {
+        source.ends_with(".monitor")
+            || config.virtual_output.device.as_deref() == Some(source)
+            || config
+                .outputs
+                .iter()
+                .any(|output| output.device != DEFAULT_OUTPUT && output.device == source)
+    }
+
+    // Every application recording from a source has a source output, on
+    // PulseAudio and on PipeWire's pulse compatibility layer alike. Lines
+    // start with the index, then the source's index or name.
+    pub fn in_use(config: &Config) -> Result<bool> {
+        let sources = pactl("sources")?;
+        let names: HashMap<&str, &str> = sources
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.split('\t');
+                Some((fields.next()?, fields.next()?))
+            })
+            .collect();
+        let outputs = pactl("source-outputs")?;
+        Ok(outputs
+            .lines()
+            .filter_map(|line| line.split('\t').nth(1))
+            .any(|source| !is_own(config, names.get(source).unwrap_or(&source))))
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use anyhow::{bail, Result};
+    use coreaudio_sys::{
+        kAudioDevicePropertyDeviceIsRunningSomewhere, kAudioHardwareNoError,
+        kAudioHardwarePropertyDefaultInputDevice, kAudioObjectPropertyElementMaster,
+        kAudioObjectPropertyScopeGlobal, kAudioObjectSystemObject, AudioDeviceID,
+        AudioObjectGetPropertyData, AudioObjectID, AudioObjectPropertyAddress,
+        AudioObjectPropertySelector,
+    };
+    use std::{mem, ptr};
+
+    fn property(object: AudioObjectID, selector: AudioObjectPropertySelector) -> Result<u32> {
+        let address = AudioObjectPropertyAddress {
+            mSelector: selector,
+            mScope: kAudioObjectPropertyScopeGlobal,
+            mElement: kAudioObjectPropertyElementMaster,
+        };
+        let mut value: u32 = 0;
+        let mut size = mem::size_of::<u32>() as u32;
+        let status = unsafe {
+            AudioObjectGetPropertyData(
+                object,
+                &address,
+                0,
+                ptr::null(),
+                &mut size,
+                &mut value as *mut u32 as *mut _,
+            )
+        };
+        if status != kAudioHardwareNoError as i32 {
+            bail!("CoreAudio query failed with status {}", status);
+        }
+        Ok(value)
+    }
+
+    // The default input device reports whether any process is running it.
+    pub fn in_use(_config: &crate::config::Config) -> Result<bool> {
+        let device: AudioDeviceID = property(
+            kAudioObjectSystemObject,
+            kAudioHardwarePropertyDefaultInputDevice,
+        )?;
+        Ok(property(device, kAudioDevicePropertyDeviceIsRunningSomewhere)? != 0)
+    }
+}
+
+// Windows keeps a per-app record of sessions for each privacy capability,
+// e.g. screen capture; one that has started but not stopped is open right
+// now. Desktop apps are grouped under NonPackaged.
+#[cfg(target_os = "windows")]
+pub mod consent {
+    use anyhow::Result;
+    use winreg::{enums::HKEY_CURRENT_USER, RegKey};
+
+    const CONSENT_STORE: &str =
+        r"Software\Microsoft\Windows\CurrentVersion\CapabilityAccessManager\ConsentStore";
+
+    pub fn in_use(capability: &str) -> Result<bool> {
+        let store = RegKey::predef(HKEY_CURRENT_USER)
+            .open_subkey(format!(r"{}\{}", CONSENT_STORE, capability))?;
+        let mut apps = Vec::new();
+        for name in store.enum_keys().flatten() {
+            let key = store.open_subkey(&name)?;
+            if name == "NonPackaged" {
+                for name in key.enum_keys().flatten() {
+                    apps.push(key.open_subkey(&name)?);
+                }
+            } else {
+                apps.push(key);
+            }
+        }
+        Ok(apps
+            .iter()
+            .any(|app| matches!(app.get_value::<u64, _>("LastUsedTimeStop"), Ok(0))))
+    }
+}
+
+#[cfg(target_os = "windows")]
+mod platform {
+    use anyhow::Result;
+    use windows::Win32::{
+        Media::Audio::{
+            eCapture, eCommunications, eConsole, AudioSessionStateActive, IAudioSessionManager2,
+            IMMDeviceEnumerator, MMDeviceEnumerator,
+        },
+        System::Com::{
+            CoCreateInstance, CoInitializeEx, CoUninitialize, CLSCTX_ALL, COINIT_MULTITHREADED,
+        },
+    };
+
+    // Balances a successful CoInitializeEx, including one that found COM
+    // already set up on the thread.
+    struct Com;
+
+    impl Drop for Com {
+        fn drop(&mut self) {
+            unsafe { CoUninitialize() };
+        }
+    }
+
+    // Every stream recording from an endpoint is an audio session on it, and
+    // an active one is capturing right now. Calls often record from the
+    // default communications device, so that counts next to the default one.
+    pub fn in_use(_config: &crate::config::Config) -> Result<bool> {
+        unsafe {
+            let _com = CoInitializeEx(None, COINIT_MULTITHREADED)
+                .is_ok()
+                .then_some(Com);
+            let devices: IMMDeviceEnumerator =
+                CoCreateInstance(&MMDeviceEnumerator, None, CLSCTX_ALL)?;
+            for role in [eConsole, eCommunications] {
+                let device = devices.GetDefaultAudioEndpoint(eCapture, role)?;
+                let manager: IAudioSessionManager2 = device.Activate(CLSCTX_ALL, None)?;
+                let sessions = manager.GetSessionEnumerator()?;
+                for index in 0..sessions.GetCount()? {
+                    if sessions.GetSession(index)?.GetState()? == AudioSessionStateActive {
+                        return Ok(true);
+                    }
+                }
+            }
+            Ok(false)
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+mod platform {
+    use anyhow::{bail, Result};
+
+    pub fn in_use(_config: &crate::config::Config) -> Result<bool> {
+        bail!("Microphone detection is not supported on this platform")
+    }
+}
+
+// Keeps keystrokes out of calls: mutes while any application records from
+// the microphone, when `mute_on_mic` is set. On Linux, recordings of sink
+// monitors and of keydio's own outputs do not count.
+pub fn condition() -> Condition {
+    Condition {
+        name: "Microphone use",
+        reason: MuteReason::Microphone,
+        enabled: |config| config.mute_on_mic,
+        detect: platform::in_use,
+        interval: POLL_INTERVAL,
+        settle_on: SETTLE_ON,
+        settle_off: SETTLE_OFF,
+    }
+}
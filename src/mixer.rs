@@ -0,0 +1,254 @@
+use crate::{metrics::Metrics, sample::Format};
+use anyhow::{anyhow, bail, Result};
+use rodio::{
+    cpal::{
+        self,
+        traits::{DeviceTrait, StreamTrait},
+        BufferSize, Device, SampleRate, StreamConfig, SupportedBufferSize,
+    },
+    OutputStreamHandle, Source,
+};
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+type Voices = Vec<Box<dyn Iterator<Item = f32> + Send>>;
+
+// The limiter keeps the sum of the voices under this.
+const THRESHOLD: f32 = 0.95;
+// How long the limiter takes to go back to full gain once the sum is quiet
+// again.
+const RELEASE: Duration = Duration::from_millis(100);
+// Frames a bus played through rodio mixes at a time.
+const BUS_FRAMES: usize = 64;
+
+// The voices playing on one output, all in the engine format. Whoever pulls
+// the output sums them, so the limiter sees every voice of every layer
+// together.
+#[derive(Clone, Default)]
+pub struct Bus {
+    voices: Arc<Mutex<Voices>>,
+}
+
+impl Bus {
+    // Plays the bus through rodio as one endless source; rodio only converts
+    // the sum to the device format.
+    pub fn rodio(handle: &OutputStreamHandle, format: Format) -> Result<Self> {
+        let bus = Self::default();
+        handle.play_raw(BusSource {
+            bus: bus.clone(),
+            limiter: Limiter::new(format),
+            format,
+            buffer: Vec::new(),
+            at: 0,
+        })?;
+        Ok(bus)
+    }
+
+    pub fn play<S>(&self, source: S)
+    where
+        S: Source<Item = f32> + Send + 'static,
+    {
+        self.voices.lock().unwrap().push(Box::new(source));
+    }
+
+    // Fills `data`, whole frames, with the limited sum of the voices.
+    fn mix(&self, data: &mut [f32], limiter: &mut Limiter) {
+        data.fill(0.0);
+        // Every voice fills whole buffers, so it stays aligned to the frames;
+        // one that runs out is done.
+        self.voices.lock().unwrap().retain_mut(|voice| {
+            for sample in data.iter_mut() {
+                match voice.next() {
+                    Some(value) => *sample += value,
+                    None => return false,
+                }
+            }
+            true
+        });
+        limiter.apply(data);
+    }
+}
+
+// Turns the sum down at once to what its loudest frame needs to stay under
+// `THRESHOLD`, and back up over `RELEASE`, so layered voices that add up
+// past full scale get quieter together instead of clipping.
+struct Limiter {
+    channels: usize,
+    gain: f32,
+    // How much of the way back to full gain each frame goes.
+    recover: f32,
+}
+
+impl Limiter {
+    fn new(format: Format) -> Self {
+        let frames = RELEASE.as_secs_f32() * format.sample_rate as f32;
+        Self {
+            channels: usize::from(format.channels.max(1)),
+            gain: 1.0,
+            recover: 1.0 - (-1.0 / frames.max(1.0)).exp(),
+        }
+    }
+
+    fn apply(&mut self, data: &mut [f32]) {
+        for frame in data.chunks_mut(self.channels) {
+            let peak = frame
+                .iter()
+                .fold(0.0f32, |peak, sample| peak.max(sample.abs()));
+            let needed = if peak > THRESHOLD {
+                THRESHOLD / peak
+            } else {
+                1.0
+            };
+            self.gain = if needed < self.gain {
+                needed
+            } else {
+                self.gain + (needed - self.gain) * self.recover
+            };
+            for sample in frame {
+                *sample = (*sample * self.gain).clamp(-1.0, 1.0);
+            }
+        }
+    }
+}
+
+struct BusSource {
+    bus: Bus,
+    limiter: Limiter,
+    format: Format,
+    buffer: Vec<f32>,
+    at: usize,
+}
+
+impl Iterator for BusSource {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.at == self.buffer.len() {
+            self.buffer
+                .resize(BUS_FRAMES * usize::from(self.format.channels), 0.0);
+            self.bus.mix(&mut self.buffer, &mut self.limiter);
+            self.at = 0;
+        }
+        self.at += 1;
+        Some(self.buffer[self.at - 1])
+    }
+}
+
+impl Source for BusSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.format.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.format.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+// The cpal backend: a stream opened straight on the device with a buffer of
+// `frames` frames, mixing the playing voices itself. Voices come in the
+// engine format, which is what the stream is opened with, so mixing is a
+// plain sum.
+#[derive(Clone)]
+pub struct Mixer {
+    bus: Bus,
+}
+
+impl Mixer {
+    // Fails for a device that cannot take 32-bit float samples in the engine
+    // format or refuses the buffer size, so the caller can fall back to rodio.
+    pub fn open(
+        device: &Device,
+        format: Format,
+        frames: Option<u32>,
+        metrics: &Arc<Metrics>,
+    ) -> Result<(cpal::Stream, Self)> {
+        let supported = device
+            .supported_output_configs()?
+            .filter(|config| config.sample_format() == cpal::SampleFormat::F32)
+            .filter(|config| config.channels() == format.channels)
+            .find(|config| {
+                (config.min_sample_rate().0..=config.max_sample_rate().0)
+                    .contains(&format.sample_rate)
+            })
+            .ok_or_else(|| anyhow!("The device does not play {} float samples", format))?;
+        if let (Some(frames), SupportedBufferSize::Range { min, max }) =
+            (frames, supported.buffer_size())
+        {
+            if !(*min..=*max).contains(&frames) {
+                bail!(
+                    "A buffer of {} frames is outside the device's {}..{}",
+                    frames,
+                    min,
+                    max
+                );
+            }
+        }
+        let config = StreamConfig {
+            channels: format.channels,
+            sample_rate: SampleRate(format.sample_rate),
+            buffer_size: frames.map_or(BufferSize::Default, BufferSize::Fixed),
+        };
+        let bus = Bus::default();
+        let mixing = bus.clone();
+        let mut limiter = Limiter::new(format);
+        let metrics = Arc::clone(metrics);
+        let stream = device.build_output_stream(
+            &config,
+            move |data: &mut [f32], info: &cpal::OutputCallbackInfo| {
+                let timestamp = info.timestamp();
+                if let Some(buffered) = timestamp.playback.duration_since(&timestamp.callback) {
+                    metrics.set_output_buffered(buffered);
+                }
+                mixing.mix(data, &mut limiter);
+            },
+            |err| eprintln!("cpal stream error: {:?}", err),
+            None,
+        )?;
+        stream.play()?;
+        Ok((stream, Self { bus }))
+    }
+
+    pub fn play<S>(&self, source: S)
+    where
+        S: Source<Item = f32> + Send + 'static,
+    {
+        self.bus.play(source);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rodio::buffer::SamplesBuffer;
+
+    const FORMAT: Format = Format {
+        channels: 2,
+        sample_rate: 48_000,
+    };
+
+    #[test]
+    fn layered_voices_are_limited_instead_of_clipping() {
+        let bus = Bus::default();
+        let mut limiter = Limiter::new(FORMAT);
+        let mut data = vec![0.0; 256];
+        bus.play(SamplesBuffer::new(2, 48_000, vec![0.4; 256]));
+        bus.mix(&mut data, &mut limiter);
+        // Alone, a voice passes untouched.
+        assert!(data.iter().all(|sample| *sample == 0.4));
+        for _ in 0..3 {
+            bus.play(SamplesBuffer::new(2, 48_000, vec![0.6; 256]));
+        }
+        bus.mix(&mut data, &mut limiter);
+        assert!(data.iter().all(|sample| (*sample - THRESHOLD).abs() < 1e-6));
+    }
+}
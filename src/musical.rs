@@ -0,0 +1,273 @@
+use crate::{
+    config::{MusicalConfig, Scale, Waveform},
+    keys,
+    sample::Clip,
+    AppState,
+};
+use anyhow::{Context, Result};
+use device_query::Keycode;
+use rodio::{Decoder, Source};
+use std::{
+    f32::consts::TAU,
+    fs,
+    io::{BufReader, Cursor},
+};
+
+// Selectable like any installed theme; its sounds are generated on load.
+pub const THEME: &str = "musical";
+
+const SAMPLE_RATE: u32 = 44100;
+// Ramps at both ends of a note, so it starts and stops without a click.
+const RAMP_MS: f32 = 5.0;
+
+// The rows of a US keyboard, each an octave above the one under it. The home
+// row starts at the root.
+const ROWS: [(i32, &[Keycode]); 4] = [
+    (
+        -1,
+        &[
+            Keycode::Z,
+            Keycode::X,
+            Keycode::C,
+            Keycode::V,
+            Keycode::B,
+            Keycode::N,
+            Keycode::M,
+            Keycode::Comma,
+            Keycode::Dot,
+            Keycode::Slash,
+        ],
+    ),
+    (
+        0,
+        &[
+            Keycode::A,
+            Keycode::S,
+            Keycode::D,
+            Keycode::F,
+            Keycode::G,
+            Keycode::H,
+            Keycode::J,
+            Keycode::K,
+            Keycode::L,
+            Keycode::Semicolon,
+            Keycode::Apostrophe,
+        ],
+    ),
+    (
+        1,
+        &[
+            Keycode::Q,
+            Keycode::W,
+            Keycode::E,
+            Keycode::R,
+            Keycode::T,
+            Keycode::Y,
+            Keycode::U,
+            Keycode::I,
+            Keycode::O,
+            Keycode::P,
+            Keycode::LeftBracket,
+            Keycode::RightBracket,
+        ],
+    ),
+    (
+        2,
+        &[
+            Keycode::Key1,
+            Keycode::Key2,
+            Keycode::Key3,
+            Keycode::Key4,
+            Keycode::Key5,
+            Keycode::Key6,
+            Keycode::Key7,
+            Keycode::Key8,
+            Keycode::Key9,
+            Keycode::Key0,
+            Keycode::Minus,
+            Keycode::Equal,
+        ],
+    ),
+];
+
+// What one key plays; releases only sound with `musical.release` on.
+pub struct Note {
+    pub press: Clip,
+    pub release: Option<Clip>,
+}
+
+impl Scale {
+    fn steps(&self) -> &'static [i32] {
+        match self {
+            Scale::Major => &[0, 2, 4, 5, 7, 9, 11],
+            Scale::Minor => &[0, 2, 3, 5, 7, 8, 10],
+            Scale::Pentatonic => &[0, 2, 4, 7, 9],
+            Scale::Chromatic => &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11],
+        }
+    }
+}
+
+// Semitones from the root for every key that plays: `musical.keys` when it
+// is set, the row layout otherwise.
+fn layout(config: &MusicalConfig) -> Result<Vec<(Keycode, i32)>> {
+    if !config.keys.is_empty() {
+        return config
+            .keys
+            .iter()
+            .map(|(name, semitone)| Ok((keys::named(name)?, *semitone)))
+            .collect();
+    }
+    let steps = config.scale.steps();
+    let length = steps.len();
+    Ok(ROWS
+        .iter()
+        .flat_map(|(octave, row)| {
+            row.iter().enumerate().map(move |(degree, key)| {
+                let semitone = 12 * (octave + (degree / length) as i32) + steps[degree % length];
+                (*key, semitone)
+            })
+        })
+        .collect())
+}
+
+struct Audio {
+    channels: u16,
+    sample_rate: u32,
+    samples: Vec<f32>,
+}
+
+impl Audio {
+    fn frames(&self) -> usize {
+        self.samples.len() / self.channels as usize
+    }
+
+    fn encode(&self) -> Result<Vec<u8>> {
+        let spec = hound::WavSpec {
+            channels: self.channels,
+            sample_rate: self.sample_rate,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut data = Vec::new();
+        let mut writer = hound::WavWriter::new(Cursor::new(&mut data), spec)?;
+        for sample in &self.samples {
+            writer.write_sample((sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16)?;
+        }
+        writer.finalize()?;
+        Ok(data)
+    }
+
+    // Fades in and out at the ends; `damping` adds an exponential decay over
+    // the whole note, 0 keeps the level.
+    fn shape(&mut self, gain: f32, damping: f32) {
+        let frames = self.frames();
+        let ramp = (self.sample_rate as f32 * RAMP_MS / 1000.0).max(1.0);
+        let channels = self.channels as usize;
+        for (frame, samples) in self.samples.chunks_mut(channels).enumerate() {
+            let position = frame as f32 / frames.max(1) as f32;
+            let edge = (frame as f32 / ramp)
+                .min((frames - frame) as f32 / ramp)
+                .min(1.0);
+            let level = gain * edge * (-damping * position).exp();
+            for sample in samples {
+                *sample *= level;
+            }
+        }
+    }
+}
+
+fn decode(path: &std::path::Path) -> Result<Audio> {
+    let file =
+        fs::File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let decoder = Decoder::new(BufReader::new(file))
+        .with_context(|| format!("Failed to decode {}", path.display()))?;
+    Ok(Audio {
+        channels: decoder.channels(),
+        sample_rate: decoder.sample_rate(),
+        samples: decoder.convert_samples().collect(),
+    })
+}
+
+// `base` played `ratio` times as fast, which raises its pitch by as much.
+fn pitch(base: &Audio, ratio: f32, max_frames: Option<usize>) -> Audio {
+    let channels = base.channels as usize;
+    let last = base.frames().saturating_sub(1);
+    let mut frames = (last as f32 / ratio) as usize;
+    if let Some(max_frames) = max_frames {
+        frames = frames.min(max_frames);
+    }
+    let mut samples = Vec::with_capacity(frames * channels);
+    for frame in 0..frames {
+        let position = frame as f32 * ratio;
+        let index = position as usize;
+        let fraction = position - index as f32;
+        for channel in 0..channels {
+            let at = |index: usize| base.samples[index.min(last) * channels + channel];
+            samples.push(at(index) + (at(index + 1) - at(index)) * fraction);
+        }
+    }
+    Audio {
+        channels: base.channels,
+        sample_rate: base.sample_rate,
+        samples,
+    }
+}
+
+fn tone(waveform: Waveform, frequency: f32, duration_ms: u64) -> Audio {
+    let frames = (SAMPLE_RATE as u64 * duration_ms / 1000) as usize;
+    let samples = (0..frames)
+        .map(|frame| {
+            let phase = (frame as f32 * frequency / SAMPLE_RATE as f32).fract();
+            match waveform {
+                Waveform::Sine => (phase * TAU).sin(),
+                // Quieter, since a square wave sounds much louder at the same peak.
+                Waveform::Square => {
+                    if phase < 0.5 {
+                        0.4
+                    } else {
+                        -0.4
+                    }
+                }
+            }
+        })
+        .collect();
+    Audio {
+        channels: 1,
+        sample_rate: SAMPLE_RATE,
+        samples,
+    }
+}
+
+// Renders the note of every mapped key, pitching `musical.sample` when set
+// and generating a tone otherwise. Releases play a short damped version.
+pub fn load(app: &mut AppState, config: &MusicalConfig) -> Result<()> {
+    let base = config.sample.as_deref().map(decode).transpose()?;
+    let release_ms = (config.note_ms / 4).max(1);
+    for (key, semitone) in layout(config)? {
+        let ratio = 2f32.powf(semitone as f32 / 12.0);
+        let render = |duration_ms: u64, gain: f32, damping: f32| -> Result<Vec<u8>> {
+            let mut audio = match &base {
+                Some(base) => {
+                    let frames = base.sample_rate as u64 * duration_ms / 1000;
+                    pitch(base, ratio, Some(frames as usize))
+                }
+                None => tone(config.waveform, config.root_hz * ratio, duration_ms),
+            };
+            audio.shape(gain, damping);
+            audio.encode()
+        };
+        let press = render(config.note_ms, 0.8, 4.0)?;
+        let release = match config.release {
+            true => Some(render(release_ms, 0.4, 8.0)?),
+            false => None,
+        };
+        app.notes.insert(
+            key,
+            Note {
+                press: press.into(),
+                release: release.map(Clip::from),
+            },
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,83 @@
+use crate::state::SharedState;
+use notify_rust::Notification;
+use std::{sync::Arc, thread, time::Duration};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+struct Notifier {
+    // Id of the notification currently on screen, so the next one replaces it.
+    #[cfg(all(unix, not(target_os = "macos")))]
+    last_id: Option<u32>,
+}
+
+impl Notifier {
+    fn show(&mut self, summary: &str) {
+        let mut notification = Notification::new();
+        notification.appname("keydio").summary(summary);
+        #[cfg(all(unix, not(target_os = "macos")))]
+        {
+            if let Some(id) = self.last_id {
+                notification.id(id);
+            }
+            match notification.show() {
+                Ok(handle) => self.last_id = Some(handle.id()),
+                Err(err) => eprintln!("Failed to show notification: {:?}", err),
+            }
+        }
+        #[cfg(not(all(unix, not(target_os = "macos"))))]
+        if let Err(err) = notification.show() {
+            eprintln!("Failed to show notification: {:?}", err);
+        }
+    }
+}
+
+// Watches for theme, mute and output changes, whatever made them (tray,
+// config file, IPC, a device coming back), and announces them. Runs on its own
+// thread so a slow or missing notification daemon never holds up playback.
+// Changes made within one poll interval collapse into a single notification.
+pub fn spawn(state: Arc<SharedState>) {
+    let interval = state.poll_interval("notifications", POLL_INTERVAL);
+    thread::spawn(move || {
+        let mut notifier = Notifier {
+            #[cfg(all(unix, not(target_os = "macos")))]
+            last_id: None,
+        };
+        let mut theme = state.config().theme;
+        let mut muted = state.is_muted();
+        let mut reconnects = state.metrics().reconnects();
+        loop {
+            thread::sleep(interval);
+            let config = state.config();
+            let now_muted = state.is_muted();
+            let now_reconnects = state.metrics().reconnects();
+            if config.notifications {
+                let mut changes = Vec::new();
+                if config.theme != theme {
+                    changes.push(format!("Theme: {}", config.theme));
+                }
+                if now_muted != muted {
+                    changes.push(
+                        if now_muted {
+                            "keydio muted"
+                        } else {
+                            "keydio unmuted"
+                        }
+                        .to_string(),
+                    );
+                }
+                if now_reconnects != reconnects {
+                    changes.push(format!(
+                        "Audio device reconnected: {}",
+                        state.metrics().device()
+                    ));
+                }
+                if !changes.is_empty() {
+                    notifier.show(&changes.join("\n"));
+                }
+            }
+            theme = config.theme;
+            muted = now_muted;
+            reconnects = now_reconnects;
+        }
+    });
+}
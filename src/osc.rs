@@ -0,0 +1,65 @@
+use crate::{KeyEvent, KeyPressType, SoundType};
+use anyhow::{Context, Result};
+use std::{
+    net::{ToSocketAddrs, UdpSocket},
+    sync::mpsc::Receiver,
+    thread::{self, JoinHandle},
+};
+
+// OSC strings end in a NUL and are padded to a multiple of four bytes.
+fn push_string(packet: &mut Vec<u8>, value: &str) {
+    packet.extend_from_slice(value.as_bytes());
+    packet.push(0);
+    while !packet.len().is_multiple_of(4) {
+        packet.push(0);
+    }
+}
+
+// `/keydio/press`, `/keydio/release` or `/keydio/hold` with the key name and
+// the sound type as string arguments. Events without a key send an empty name.
+fn message(event: &KeyEvent) -> Vec<u8> {
+    let address = match event.keypress {
+        KeyPressType::Press => "/keydio/press",
+        KeyPressType::Release => "/keydio/release",
+        KeyPressType::Hold => "/keydio/hold",
+    };
+    let sound_type = match event.sound_type {
+        SoundType::Backspace => "backspace",
+        SoundType::Enter => "enter",
+        SoundType::Generic => "generic",
+        SoundType::Space => "space",
+    };
+    let key = event.key.map(|key| key.to_string()).unwrap_or_default();
+    let mut packet = Vec::with_capacity(64);
+    push_string(&mut packet, address);
+    push_string(&mut packet, ",ss");
+    push_string(&mut packet, &key);
+    push_string(&mut packet, sound_type);
+    packet
+}
+
+// Forwards every event to `target` on a thread of its own. The socket never
+// blocks and failed sends are dropped, so a missing listener costs nothing.
+pub fn spawn(target: &str, rx: Receiver<KeyEvent>) -> Result<JoinHandle<()>> {
+    let address = target
+        .to_socket_addrs()
+        .with_context(|| format!("Invalid OSC target {}", target))?
+        .next()
+        .with_context(|| format!("OSC target {} did not resolve", target))?;
+    let local = if address.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let socket = UdpSocket::bind(local).context("Failed to open the OSC socket")?;
+    socket.set_nonblocking(true)?;
+    socket.connect(address)?;
+    let handle = thread::Builder::new()
+        .name("osc".to_string())
+        .spawn(move || {
+            for event in rx {
+                let _ = socket.send(&message(&event));
+            }
+        })?;
+    Ok(handle)
+}
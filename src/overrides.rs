@@ -0,0 +1,74 @@
+use crate::{fsutil, keys, manifest, sample::Clip, KeyEvent, KeyPressType};
+use anyhow::{anyhow, bail, Context, Result};
+use device_query::Keycode;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+};
+
+// The files `key_sounds` plays for one key, instead of the theme's sound.
+#[derive(Default)]
+pub struct KeySound {
+    pub press: Option<Clip>,
+    pub release: Option<Clip>,
+}
+
+pub type KeySounds = HashMap<Keycode, KeySound>;
+
+// `CapsLock` overrides both directions, `CapsLock:press` and
+// `CapsLock:release` just one. Prefixed names work the same:
+// `physical:KeyQ`, `char:a:press`.
+fn parse_entry(entry: &str) -> Result<(Keycode, bool, bool)> {
+    let (name, press, release) = match entry.rsplit_once(':') {
+        Some((name, "press")) => (name, true, false),
+        Some((name, "release")) => (name, false, true),
+        Some(("physical" | "char", _)) => (entry, true, true),
+        Some((_, direction)) => bail!("Unknown direction {}, use press or release", direction),
+        None => (entry, true, true),
+    };
+    Ok((keys::named(name)?, press, release))
+}
+
+fn read(path: &str) -> Result<Vec<u8>> {
+    let path = fsutil::expand_home(path);
+    let data = fs::read(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+    manifest::check_decodes(data.clone())
+        .map_err(|err| anyhow!("Failed to decode {}: {}", path.display(), err))?;
+    Ok(data)
+}
+
+// Reads and decodes every file in `key_sounds` once. An entry that does not
+// work is reported and skipped, so that key keeps the theme's sound.
+pub fn load(entries: &BTreeMap<String, String>) -> KeySounds {
+    let mut sounds = KeySounds::new();
+    for (entry, path) in entries {
+        let loaded = parse_entry(entry).and_then(|key| Ok((key, read(path)?)));
+        let ((key, press, release), data) = match loaded {
+            Ok(loaded) => loaded,
+            Err(err) => {
+                eprintln!("Ignoring key sound {}: {:?}", entry, err);
+                continue;
+            }
+        };
+        let data = Clip::from(data);
+        let sound = sounds.entry(key).or_default();
+        // A direction-specific entry wins over the one for both.
+        let specific = press != release;
+        if press && (specific || sound.press.is_none()) {
+            sound.press = Some(data.clone());
+        }
+        if release && (specific || sound.release.is_none()) {
+            sound.release = Some(data);
+        }
+    }
+    sounds
+}
+
+pub fn get(sounds: &KeySounds, event: &KeyEvent) -> Option<Clip> {
+    let sound = sounds.get(&event.key?)?;
+    match event.keypress {
+        KeyPressType::Press => sound.press.clone(),
+        KeyPressType::Release => sound.release.clone(),
+        KeyPressType::Hold => None,
+    }
+}
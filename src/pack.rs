@@ -0,0 +1,186 @@
+use crate::{
+    archive, assets, fsutil,
+    manifest::{self, Manifest, MANIFEST_FILE},
+    sha256, KeyPressType,
+};
+use anyhow::{bail, Context, Result};
+use std::{
+    collections::BTreeSet,
+    fs,
+    io::{self, BufRead, IsTerminal, Write},
+    path::{Path, PathBuf},
+};
+
+pub const EXTENSION: &str = "keydio";
+
+// Files that operating systems drop next to anything a user browses.
+pub fn is_junk(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    name == ".ds_store" || name == "thumbs.db" || name == "desktop.ini" || name.starts_with("._")
+}
+
+// A theme name from the assets directory, or a path to a theme directory.
+fn theme_dir(theme: &str) -> PathBuf {
+    let installed = assets::theme_dir(theme);
+    if installed.is_dir() {
+        installed
+    } else {
+        PathBuf::from(theme)
+    }
+}
+
+// Archive entry names use `/` on every platform.
+pub fn entry_name(relative: &Path) -> String {
+    relative
+        .components()
+        .map(|part| part.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+pub fn walk(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir).with_context(|| format!("Failed to read {}", dir.display()))? {
+        let path = entry?.path();
+        if path.is_dir() {
+            files.extend(walk(&path)?);
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+fn prompt(label: &str, default: Option<&str>) -> Result<String> {
+    match default {
+        Some(default) => eprint!("{} [{}]: ", label, default),
+        None => eprint!("{}: ", label),
+    }
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    match (answer.trim(), default) {
+        ("", Some(default)) => Ok(default.to_string()),
+        ("", None) => bail!("{} is required", label),
+        (answer, _) => Ok(answer.to_string()),
+    }
+}
+
+// Fills in missing metadata from the flags, or by asking when run
+// interactively; the name defaults to the directory's. Returns whether
+// anything changed.
+fn complete_metadata(
+    manifest: &mut Manifest,
+    dir_name: &str,
+    name: Option<&str>,
+    author: Option<&str>,
+) -> Result<bool> {
+    let interactive = io::stdin().is_terminal();
+    let mut changed = false;
+    for (field, flag, option, label, default) in [
+        (
+            &mut manifest.name,
+            name,
+            "name",
+            "Theme name",
+            Some(dir_name),
+        ),
+        (&mut manifest.author, author, "author", "Author", None),
+    ] {
+        let value = match (flag, field.as_deref()) {
+            (Some(flag), _) => flag.to_string(),
+            (None, Some(_)) => continue,
+            (None, None) if interactive => prompt(label, default)?,
+            (None, None) => match default {
+                Some(default) => default.to_string(),
+                None => bail!("The theme has no {} yet, pass --{}", option, option),
+            },
+        };
+        if field.as_deref() != Some(value.as_str()) {
+            *field = Some(value);
+            changed = true;
+        }
+    }
+    Ok(changed)
+}
+
+// Validates a theme and zips its manifest and the samples it plays into a
+// `.keydio` archive. Files the theme does not use are left out. Entries are
+// sorted and carry fixed timestamps, so packing the same content again gives
+// the same bytes.
+pub fn run(
+    theme: &str,
+    name: Option<&str>,
+    author: Option<&str>,
+    out: Option<&Path>,
+) -> Result<()> {
+    let dir = theme_dir(theme);
+    let samples =
+        manifest::validate(&dir).with_context(|| format!("{} failed validation", dir.display()))?;
+    let dir_name = dir
+        .canonicalize()?
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .context("The theme directory has no name")?;
+
+    let mut manifest = Manifest::load(&dir)?;
+    let contents = if complete_metadata(&mut manifest, &dir_name, name, author)? {
+        let contents = toml::to_string(&manifest)?;
+        fsutil::write_atomic(&dir.join(MANIFEST_FILE), contents.as_bytes())?;
+        eprintln!("Updated {}", dir.join(MANIFEST_FILE).display());
+        contents
+    } else {
+        toml::to_string(&manifest)?
+    };
+
+    let mut files = BTreeSet::new();
+    for keypress in [
+        KeyPressType::Press,
+        KeyPressType::Release,
+        KeyPressType::Hold,
+    ] {
+        for sample in manifest.samples(&dir, &keypress) {
+            if sample.path.is_file() {
+                files.insert(entry_name(sample.path.strip_prefix(&dir)?));
+            }
+        }
+    }
+    for extra in [manifest.combo(&dir).path, manifest.chord(&dir).path] {
+        if extra.is_file() {
+            files.insert(entry_name(extra.strip_prefix(&dir)?));
+        }
+    }
+    let mut unused: Vec<String> = walk(&dir)?
+        .iter()
+        .map(|path| entry_name(path.strip_prefix(&dir).unwrap_or(path)))
+        .filter(|file| file != MANIFEST_FILE && !files.contains(file))
+        .filter(|file| !is_junk(file.rsplit('/').next().unwrap_or(file)))
+        .collect();
+    unused.sort();
+    if !unused.is_empty() {
+        eprintln!(
+            "Leaving out files the theme does not use: {}",
+            unused.join(", ")
+        );
+    }
+    let mut entries = vec![(MANIFEST_FILE.to_string(), contents.into_bytes())];
+    for file in &files {
+        let data = fs::read(dir.join(file)).with_context(|| format!("Failed to read {}", file))?;
+        entries.push((file.clone(), data));
+    }
+
+    let archive = archive::zip(&entries)?;
+    let out = match out {
+        Some(out) => out.to_path_buf(),
+        None => PathBuf::from(format!("{}.{}", dir_name, EXTENSION)),
+    };
+    fsutil::write_atomic(&out, &archive)?;
+    println!(
+        "Wrote {} ({} samples, {} bytes)",
+        out.display(),
+        samples.len(),
+        archive.len()
+    );
+    println!("sha256 {}", sha256::hex(&archive));
+    Ok(())
+}
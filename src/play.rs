@@ -0,0 +1,190 @@
+use crate::{
+    assets,
+    audio::{self, OutputKind, Outputs, Shaping},
+    config::Config,
+    manifest::{Manifest, Sample, SOUND_TYPES},
+    metrics::Metrics,
+    themes::ThemeSet,
+    AppState, KeyEvent, KeyPressType, KeyboardButtonSound, SoundType,
+};
+use anyhow::{bail, Context, Result};
+use rand::seq::SliceRandom;
+use rodio::{Decoder, Source};
+use std::{
+    fs,
+    io::Cursor,
+    path::Path,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+// The sound IDs the theme has at least one sample for, e.g. `enter` or
+// `hold`, with `--release` ones marked.
+fn defined(manifest: &Manifest, dir: &Path) -> Vec<String> {
+    let mut ids = Vec::new();
+    for (keypress, suffix) in [
+        (KeyPressType::Press, ""),
+        (KeyPressType::Release, " (--release)"),
+    ] {
+        let samples = existing(manifest.samples(dir, &keypress));
+        for (name, sound_type) in &SOUND_TYPES {
+            if samples
+                .iter()
+                .any(|sample| sample.sound_type == *sound_type)
+            {
+                ids.push(format!("{}{}", name, suffix));
+            }
+        }
+    }
+    if !existing(manifest.samples(dir, &KeyPressType::Hold)).is_empty() {
+        ids.push("hold".to_string());
+    }
+    ids
+}
+
+// The samples whose files are there, required or not: a theme is listed by
+// what it can actually play.
+fn existing(samples: Vec<Sample>) -> Vec<Sample> {
+    samples
+        .into_iter()
+        .filter(|sample| sample.path.is_file())
+        .collect()
+}
+
+// Length of a decoded sample, to know how long to keep the output open.
+fn duration(data: &[u8]) -> Result<Duration> {
+    let decoder = Decoder::new(Cursor::new(data.to_vec()))?;
+    let samples_per_second = decoder.sample_rate() as f64 * decoder.channels() as f64;
+    let samples = decoder.count();
+    Ok(Duration::from_secs_f64(samples as f64 / samples_per_second))
+}
+
+// Plays one sample of the theme through the normal playback path, so the
+// configured volumes and theme layers apply, and waits until it has
+// finished. `variant` counts pool members from 1 and picks from the main
+// theme; without it a member is picked like a keystroke would.
+pub fn run(
+    kind: OutputKind,
+    theme: Option<&str>,
+    id: &str,
+    release: bool,
+    variant: Option<usize>,
+    volume: Option<u8>,
+) -> Result<()> {
+    let mut config = Config::load(&Config::path()?)?;
+    if let Some(theme) = theme {
+        config.theme = theme.to_string();
+    }
+    if let Some(volume) = volume {
+        config.volume = volume as f32 / 100.0;
+    }
+    let dir = assets::theme_dir(&config.theme);
+    if !dir.is_dir() {
+        bail!("Unknown theme: {}", config.theme);
+    }
+    let manifest = Manifest::load(&dir)?;
+
+    let (keypress, sound_type) = match (id, release) {
+        ("hold", true) => bail!("Hold sounds have no release variant"),
+        ("hold", false) => (KeyPressType::Hold, SoundType::Generic),
+        (id, release) => {
+            let keypress = if release {
+                KeyPressType::Release
+            } else {
+                KeyPressType::Press
+            };
+            let sound_type = SOUND_TYPES
+                .iter()
+                .find(|(name, _)| *name == id)
+                .map(|(_, sound_type)| sound_type.clone());
+            match sound_type {
+                Some(sound_type) => (keypress, sound_type),
+                None => bail!(
+                    "Unknown sound {}; {} defines: {}",
+                    id,
+                    config.theme,
+                    defined(&manifest, &dir).join(", ")
+                ),
+            }
+        }
+    };
+    let pool: Vec<Sample> = existing(manifest.samples(&dir, &keypress))
+        .into_iter()
+        .filter(|sample| sample.sound_type == sound_type || keypress == KeyPressType::Hold)
+        .collect();
+    if pool.is_empty() {
+        bail!(
+            "{} has no {}{} sound; it defines: {}",
+            config.theme,
+            id,
+            if release { " release" } else { "" },
+            defined(&manifest, &dir).join(", ")
+        );
+    }
+    let sample = match variant {
+        Some(n) if n >= 1 && n <= pool.len() => &pool[n - 1],
+        Some(n) => bail!(
+            "No variant {} of {}, the theme has {} (1-{})",
+            n,
+            id,
+            pool.len(),
+            pool.len()
+        ),
+        None => pool.choose_weighted(&mut rand::thread_rng(), |sample| sample.weight)?,
+    };
+    let data = fs::read(&sample.path)
+        .with_context(|| format!("Failed to read {}", sample.path.display()))?;
+    let length =
+        duration(&data).with_context(|| format!("Failed to decode {}", sample.path.display()))?;
+    eprintln!("Playing {}", sample.path.display());
+
+    let mut app = AppState::new(&config.theme);
+    let sound = KeyboardButtonSound::new(sound_type.clone(), data.into(), 1.0);
+    match keypress {
+        KeyPressType::Press => app.audio_press.push(sound),
+        KeyPressType::Release => app.audio_release.push(sound),
+        KeyPressType::Hold => app.audio_hold.push(sound),
+    }
+    let mut themes = vec![app];
+    let mut length = length;
+    for (theme, _) in config.themes().into_iter().skip(1) {
+        let mut layer = AppState::new(&theme);
+        if let Err(err) = layer.load(&config) {
+            eprintln!("Failed to load theme layer {}: {:?}", theme, err);
+            layer = AppState::new(&theme);
+        }
+        let sounds = match keypress {
+            KeyPressType::Press => &layer.audio_press,
+            KeyPressType::Release => &layer.audio_release,
+            KeyPressType::Hold => &layer.audio_hold,
+        };
+        for sound in sounds {
+            if sound.sound_type == sound_type || keypress == KeyPressType::Hold {
+                length = length.max(duration(sound.clip.bytes.as_ref()).unwrap_or_default());
+            }
+        }
+        themes.push(layer);
+    }
+    let metrics = Arc::new(Metrics::new());
+    let outputs = Outputs::open(kind, &config, &metrics)?;
+    let started = Instant::now();
+    audio::play_event(
+        &ThemeSet::new(themes, &config),
+        &config,
+        &outputs,
+        &metrics,
+        KeyEvent {
+            key: None,
+            keypress,
+            sound_type,
+            at: started,
+        },
+        Shaping::default(),
+    );
+    if outputs.is_device() {
+        // The device plays in the background; the stream closes on return.
+        thread::sleep(length.saturating_sub(started.elapsed()) + Duration::from_millis(50));
+    }
+    Ok(())
+}
@@ -0,0 +1,89 @@
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, time::Duration};
+use thread_priority::{set_current_thread_priority, ThreadPriority};
+
+// Low power stretches every background poll by this much.
+const LOW_POWER_POLL_FACTOR: u32 = 4;
+// Low power closes the audio stream after this long without a keystroke and
+// reopens it on the next one.
+const LOW_POWER_IDLE_SUSPEND: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Profile {
+    #[default]
+    Normal,
+    LowPower,
+    LowLatency,
+}
+
+impl Profile {
+    pub fn name(self) -> &'static str {
+        match self {
+            Profile::Normal => "normal",
+            Profile::LowPower => "low-power",
+            Profile::LowLatency => "low-latency",
+        }
+    }
+
+    pub fn poll_interval(self, base: Duration) -> Duration {
+        match self {
+            Profile::LowPower => base * LOW_POWER_POLL_FACTOR,
+            Profile::Normal | Profile::LowLatency => base,
+        }
+    }
+
+    pub fn idle_suspend(self) -> Option<Duration> {
+        (self == Profile::LowPower).then_some(LOW_POWER_IDLE_SUSPEND)
+    }
+
+    // Adjusts the calling thread and describes what actually took effect.
+    pub fn apply_thread_priority(self) -> String {
+        let (priority, name) = match self {
+            Profile::Normal => return "default".to_string(),
+            Profile::LowPower => (ThreadPriority::Min, "min"),
+            Profile::LowLatency => (ThreadPriority::Max, "max"),
+        };
+        match set_current_thread_priority(priority) {
+            Ok(()) => name.to_string(),
+            Err(err) => {
+                eprintln!("Failed to set {} thread priority: {:?}", name, err);
+                format!("default ({} refused)", name)
+            }
+        }
+    }
+}
+
+// What the profile changed in this run, reported alongside the stats.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Tuning {
+    pub profile: Profile,
+    pub audio_thread_priority: String,
+    pub idle_suspend_seconds: Option<u64>,
+    pub poll_intervals_ms: BTreeMap<String, u64>,
+}
+
+impl Tuning {
+    pub fn new(profile: Profile) -> Self {
+        Self {
+            profile,
+            audio_thread_priority: "default".to_string(),
+            idle_suspend_seconds: profile.idle_suspend().map(|idle| idle.as_secs()),
+            poll_intervals_ms: BTreeMap::new(),
+        }
+    }
+
+    pub fn summary(&self) -> String {
+        let polls: Vec<_> = self
+            .poll_intervals_ms
+            .iter()
+            .map(|(name, ms)| format!("{} {}ms", name, ms))
+            .collect();
+        format!(
+            "profile {}, audio thread priority {}, polling {}",
+            self.profile.name(),
+            self.audio_thread_priority,
+            polls.join(", ")
+        )
+    }
+}
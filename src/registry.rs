@@ -0,0 +1,180 @@
+use crate::{assets, config::Config, fsutil, install, sha256};
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Deserialize;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+pub const DEFAULT_REGISTRY: &str =
+    "https://raw.githubusercontent.com/BipulLamsal/keydio/main/registry/index.json";
+// The newest index format this build reads.
+const INDEX_VERSION: u32 = 1;
+// How long a fetched index is used before it is fetched again.
+const FRESH_FOR: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Entry {
+    pub name: String,
+    #[serde(default)]
+    pub author: String,
+    #[serde(default)]
+    pub description: String,
+    // Of the archive, in bytes.
+    #[serde(default)]
+    pub size: u64,
+    pub url: String,
+    pub sha256: String,
+}
+
+impl Entry {
+    fn matches(&self, query: &str) -> bool {
+        let query = query.to_lowercase();
+        [&self.name, &self.author, &self.description]
+            .iter()
+            .any(|field| field.to_lowercase().contains(&query))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Index {
+    version: u32,
+    themes: Vec<Entry>,
+}
+
+fn parse(data: &[u8]) -> Result<Vec<Entry>> {
+    let index: Index = serde_json::from_slice(data).context("Invalid registry index")?;
+    if index.version > INDEX_VERSION {
+        bail!(
+            "The registry index is version {}, this keydio reads up to {}; update keydio",
+            index.version,
+            INDEX_VERSION
+        );
+    }
+    Ok(index.themes)
+}
+
+// One cached index per registry URL.
+fn cache_path(url: &str) -> Result<PathBuf> {
+    let dir = dirs::cache_dir().ok_or_else(|| anyhow!("No cache directory found"))?;
+    let key = &sha256::hex(url.as_bytes())[..16];
+    Ok(dir.join("keydio").join(format!("registry-{}.json", key)))
+}
+
+fn age(path: &Path) -> Option<Duration> {
+    let modified = fs::metadata(path).and_then(|meta| meta.modified()).ok()?;
+    SystemTime::now().duration_since(modified).ok()
+}
+
+// The index of the configured registry: from the cache while it is fresh,
+// fetched otherwise. When the registry cannot be reached, a stale cached
+// index is better than nothing.
+fn index() -> Result<Vec<Entry>> {
+    let url = Config::load(&Config::path()?)?.registry;
+    let cached = cache_path(&url).ok();
+    if let Some(path) = &cached {
+        if age(path).is_some_and(|age| age < FRESH_FOR) {
+            if let Ok(entries) = fs::read(path)
+                .map_err(Into::into)
+                .and_then(|data| parse(&data))
+            {
+                return Ok(entries);
+            }
+        }
+    }
+    let data = match install::download(&url) {
+        Ok(data) => data,
+        Err(err) => {
+            if let Some(data) = cached.as_ref().and_then(|path| fs::read(path).ok()) {
+                eprintln!("Cannot reach the theme registry, using the last index fetched");
+                return parse(&data);
+            }
+            return Err(err.context(format!(
+                "Cannot reach the theme registry at {}; check your connection or the `registry` setting",
+                url
+            )));
+        }
+    };
+    let entries = parse(&data).with_context(|| format!("From {}", url))?;
+    if let Some(path) = &cached {
+        if let Err(err) = fsutil::write_atomic(path, &data) {
+            eprintln!("Failed to cache the registry index: {:?}", err);
+        }
+    }
+    Ok(entries)
+}
+
+// The registry entry for the theme called `name`.
+pub fn find(name: &str) -> Result<Entry> {
+    index()?
+        .into_iter()
+        .find(|entry| entry.name == name)
+        .ok_or_else(|| {
+            anyhow!(
+                "No theme named {} in the registry; try `keydio search`",
+                name
+            )
+        })
+}
+
+fn size(bytes: u64) -> String {
+    match bytes {
+        0 => "?".to_string(),
+        bytes => fsutil::human_size(bytes),
+    }
+}
+
+// `keydio search`: every theme whose name, author or description contains
+// `query`, or all of them.
+pub fn search(query: Option<&str>) -> Result<()> {
+    let entries: Vec<Entry> = index()?
+        .into_iter()
+        .filter(|entry| query.is_none_or(|query| entry.matches(query)))
+        .collect();
+    if entries.is_empty() {
+        println!("No themes found");
+        return Ok(());
+    }
+    for entry in &entries {
+        println!(
+            "{:<20} {:<16} {:>8}  {}",
+            entry.name,
+            entry.author,
+            size(entry.size),
+            entry.description
+        );
+    }
+    Ok(())
+}
+
+// `keydio info`: everything the registry knows about one theme.
+pub fn info(name: &str) -> Result<()> {
+    let entry = find(name)?;
+    println!("Name:        {}", entry.name);
+    println!("Author:      {}", entry.author);
+    println!("Description: {}", entry.description);
+    println!("Size:        {}", size(entry.size));
+    println!("URL:         {}", entry.url);
+    println!("SHA-256:     {}", entry.sha256);
+    let installed = assets::theme_dir(&entry.name);
+    if installed.is_dir() {
+        println!("Installed:   {}", installed.display());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn indexes_from_newer_keydio_are_refused() {
+        let index = br#"{"version": 1, "themes": [{"name": "typewriter", "author": "Ada",
+            "description": "Clacky", "url": "https://example.com/t.keydio", "sha256": "00"}]}"#;
+        let entries = parse(index).unwrap();
+        assert!(entries[0].matches("CLACK") && entries[0].matches("ada"));
+        assert!(!entries[0].matches("cherry"));
+        assert!(parse(br#"{"version": 2, "themes": []}"#).is_err());
+    }
+}
@@ -0,0 +1,154 @@
+use crate::{
+    assets,
+    config::Config,
+    manifest::Manifest,
+    musical,
+    sample::{Bytes, Clip, Pcm},
+    state::SharedState,
+    KeyPressType, SoundType,
+};
+use anyhow::Result;
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{self, RecvTimeoutError},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+// How often the config is looked at for a theme switch or `watch_theme`
+// being turned off, while no file changes.
+const INTERVAL: Duration = Duration::from_millis(250);
+// Editors write in steps (truncate then write, or a temp file renamed into
+// place); a file has to stay unchanged this long before it is read.
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+// The theme directories to watch: the main theme and every layer.
+fn theme_dirs(config: &Config) -> Vec<PathBuf> {
+    if !config.watch_theme {
+        return Vec::new();
+    }
+    config
+        .themes()
+        .into_iter()
+        .filter(|(theme, _)| theme != musical::THEME)
+        .map(|(theme, _)| assets::theme_dir(&theme))
+        .collect()
+}
+
+// The sample files of the watched themes, by path, with whether they are hot.
+fn samples(dirs: &[PathBuf], hot: &[SoundType]) -> HashMap<PathBuf, bool> {
+    dirs.iter()
+        .filter_map(|dir| Some((dir, Manifest::load(dir).ok()?)))
+        .flat_map(|(dir, manifest)| {
+            [
+                KeyPressType::Press,
+                KeyPressType::Release,
+                KeyPressType::Hold,
+            ]
+            .iter()
+            .flat_map(|keypress| manifest.samples(dir, keypress))
+            .collect::<Vec<_>>()
+        })
+        .map(|sample| {
+            let hot = hot.contains(&sample.sound_type);
+            (sample.path, hot)
+        })
+        .collect()
+}
+
+// Reads and decodes a changed file the way loading the theme would.
+fn read(config: &Config, path: &Path, hot: bool) -> Result<Clip> {
+    let bytes = Bytes::read(path, config.memory.mmap)?;
+    let pcm = Pcm::decode(&bytes, config.engine.format())?;
+    Ok(Clip::new(bytes, hot.then_some(pcm)))
+}
+
+// Watches the sample files of the main theme and its layers while
+// `watch_theme` is set and hands every changed one, decoded, to the audio
+// thread. A file that fails to decode keeps playing its old version. Any
+// other file changing in a theme may be its manifest, so that rereads the
+// list of samples.
+pub fn spawn(state: Arc<SharedState>) {
+    thread::spawn(move || {
+        let (tx, rx) = mpsc::channel();
+        let mut watcher: RecommendedWatcher = match notify::recommended_watcher(tx) {
+            Ok(watcher) => watcher,
+            Err(err) => {
+                eprintln!("Cannot watch theme files: {:?}", err);
+                return;
+            }
+        };
+        let mut dirs: Vec<PathBuf> = Vec::new();
+        let mut hot = Vec::new();
+        let mut samples_by_path: HashMap<PathBuf, bool> = HashMap::new();
+        // When each changed file last changed.
+        let mut changed: HashMap<PathBuf, Instant> = HashMap::new();
+        loop {
+            let wait = changed
+                .values()
+                .map(|at| (*at + DEBOUNCE).saturating_duration_since(Instant::now()))
+                .fold(INTERVAL, Duration::min);
+            match rx.recv_timeout(wait) {
+                Ok(Ok(event)) if !matches!(event.kind, EventKind::Access(_)) => {
+                    for path in event.paths {
+                        changed.insert(path, Instant::now());
+                    }
+                }
+                Ok(Ok(_)) | Err(RecvTimeoutError::Timeout) => {}
+                Ok(Err(err)) => eprintln!("Theme watch error: {:?}", err),
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+            let config = state.config();
+            let wanted = theme_dirs(&config);
+            if wanted != dirs || config.memory.hot != hot {
+                // A theme switch loads the whole theme anyway.
+                for dir in &dirs {
+                    let _ = watcher.unwatch(dir);
+                }
+                for dir in &wanted {
+                    if let Err(err) = watcher.watch(dir, RecursiveMode::Recursive) {
+                        eprintln!("Cannot watch {}: {:?}", dir.display(), err);
+                    }
+                }
+                dirs = wanted;
+                hot = config.memory.hot.clone();
+                samples_by_path = samples(&dirs, &hot);
+                changed.clear();
+                continue;
+            }
+            let now = Instant::now();
+            let due: Vec<PathBuf> = changed
+                .iter()
+                .filter(|(_, at)| now.duration_since(**at) >= DEBOUNCE)
+                .map(|(path, _)| path.clone())
+                .collect();
+            let mut rescan = false;
+            for path in due {
+                changed.remove(&path);
+                let Some(hot) = samples_by_path.get(&path) else {
+                    rescan = true;
+                    continue;
+                };
+                // Gone for good, not just renamed into place.
+                if !path.is_file() {
+                    continue;
+                }
+                match read(&config, &path, *hot) {
+                    Ok(clip) => {
+                        eprintln!("Reloaded {}", path.display());
+                        state.reload_sample(path, clip);
+                    }
+                    Err(err) => eprintln!("Keeping the old {}: {:?}", path.display(), err),
+                }
+            }
+            if rescan {
+                samples_by_path = samples(&dirs, &hot);
+            }
+        }
+    });
+}
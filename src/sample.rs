@@ -0,0 +1,427 @@
+use anyhow::{bail, Result};
+use memmap2::Mmap;
+use rodio::{Decoder, Source};
+use std::{
+    fmt,
+    fs::{self, File},
+    io::{self, Cursor},
+    path::Path,
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+// Encoded sample bytes: read onto the heap, or mapped from the theme file so
+// the OS pages them in and out as needed. Cloning never copies them.
+#[derive(Clone)]
+pub enum Bytes {
+    Heap(Arc<[u8]>),
+    Mapped(Arc<Mmap>),
+}
+
+impl Bytes {
+    pub fn read(path: &Path, mmap: bool) -> io::Result<Self> {
+        if !mmap {
+            return Ok(Self::Heap(fs::read(path)?.into()));
+        }
+        let file = File::open(path)?;
+        if file.metadata()?.len() == 0 {
+            // Empty files cannot be mapped everywhere.
+            return Ok(Self::Heap(Arc::from(Vec::new())));
+        }
+        // Safety: the mapping is only ever read. A theme file that is
+        // truncated while keydio has it mapped can still crash it, which is
+        // why mapping is opt-in.
+        let map = unsafe { Mmap::map(&file)? };
+        Ok(Self::Mapped(Arc::new(map)))
+    }
+
+    pub fn len(&self) -> usize {
+        self.as_ref().len()
+    }
+
+    pub fn is_mapped(&self) -> bool {
+        matches!(self, Self::Mapped(_))
+    }
+}
+
+impl AsRef<[u8]> for Bytes {
+    fn as_ref(&self) -> &[u8] {
+        match self {
+            Self::Heap(bytes) => bytes,
+            Self::Mapped(map) => map,
+        }
+    }
+}
+
+impl From<Vec<u8>> for Bytes {
+    fn from(data: Vec<u8>) -> Self {
+        Self::Heap(data.into())
+    }
+}
+
+// The channel count and rate of some audio.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Format {
+    pub channels: u16,
+    pub sample_rate: u32,
+}
+
+impl Format {
+    // The format a sample is stored in, read from its header.
+    pub fn probe(bytes: &Bytes) -> Result<Self> {
+        let decoder = Decoder::new(Cursor::new(bytes.clone()))?;
+        Ok(Self {
+            channels: decoder.channels(),
+            sample_rate: decoder.sample_rate(),
+        })
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.channels {
+            1 => write!(f, "{} Hz mono", self.sample_rate),
+            2 => write!(f, "{} Hz stereo", self.sample_rate),
+            channels => write!(f, "{} Hz {} channels", self.sample_rate, channels),
+        }
+    }
+}
+
+// Interleaved frames of `from`, mapped to the channels of `to`: mono is
+// copied to every channel, everything else is averaged down to mono, and
+// other counts take the source channels in turn.
+fn remix(samples: &[f32], from: u16, to: u16) -> Vec<f32> {
+    let (from, to) = (from as usize, to as usize);
+    if from == to {
+        return samples.to_vec();
+    }
+    let mut out = Vec::with_capacity(samples.len() / from * to);
+    for frame in samples.chunks_exact(from) {
+        if to == 1 {
+            out.push(frame.iter().sum::<f32>() / from as f32);
+        } else {
+            out.extend((0..to).map(|channel| frame[channel % from]));
+        }
+    }
+    out
+}
+
+// Linear interpolation between neighbouring frames; the length scales with
+// the rate.
+fn resample(samples: &[f32], channels: u16, from: u32, to: u32) -> Vec<f32> {
+    let channels = channels as usize;
+    if from == to {
+        return samples.to_vec();
+    }
+    let frames = samples.len() / channels;
+    let out_frames = (frames as u64 * to as u64 / from as u64) as usize;
+    let mut out = Vec::with_capacity(out_frames * channels);
+    for frame in 0..out_frames {
+        let position = frame as f64 * from as f64 / to as f64;
+        let index = position as usize;
+        let next = (index + 1).min(frames - 1);
+        let fraction = (position - index as f64) as f32;
+        for channel in 0..channels {
+            let a = samples[index * channels + channel];
+            let b = samples[next * channels + channel];
+            out.push(a + (b - a) * fraction);
+        }
+    }
+    out
+}
+
+// Decoded samples, interleaved. Cloning shares them.
+#[derive(Clone)]
+pub struct Pcm {
+    pub channels: u16,
+    pub sample_rate: u32,
+    pub samples: Arc<[f32]>,
+}
+
+impl Pcm {
+    // Decodes a sample and converts it to `format`, so every sound reaches
+    // the mixer in the same shape whatever the theme stored.
+    pub fn decode(bytes: &Bytes, format: Format) -> Result<Self> {
+        if format.channels == 0 || format.sample_rate == 0 {
+            bail!("The engine format needs at least one channel and a sample rate");
+        }
+        let decoder = Decoder::new(Cursor::new(bytes.clone()))?;
+        let native = Format {
+            channels: decoder.channels(),
+            sample_rate: decoder.sample_rate(),
+        };
+        if native.channels == 0 || native.sample_rate == 0 {
+            bail!("The sample has no channels or no sample rate");
+        }
+        let samples: Vec<f32> = decoder.convert_samples().collect();
+        let samples = remix(&samples, native.channels, format.channels);
+        let samples = resample(
+            &samples,
+            format.channels,
+            native.sample_rate,
+            format.sample_rate,
+        );
+        assert!(samples.len().is_multiple_of(format.channels as usize));
+        Ok(Self {
+            channels: format.channels,
+            sample_rate: format.sample_rate,
+            samples: samples.into(),
+        })
+    }
+
+    pub fn bytes(&self) -> usize {
+        std::mem::size_of_val(&*self.samples)
+    }
+}
+
+// Plays a `Pcm` without copying it.
+pub struct PcmSource {
+    pcm: Pcm,
+    position: usize,
+}
+
+impl PcmSource {
+    pub fn new(pcm: Pcm) -> Self {
+        Self { pcm, position: 0 }
+    }
+}
+
+impl Iterator for PcmSource {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let sample = self.pcm.samples.get(self.position).copied();
+        self.position += 1;
+        sample
+    }
+}
+
+impl Source for PcmSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        Some(self.pcm.samples.len().saturating_sub(self.position))
+    }
+
+    fn channels(&self) -> u16 {
+        self.pcm.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.pcm.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        let frames = self.pcm.samples.len() as f64 / self.pcm.channels.max(1) as f64;
+        Some(Duration::from_secs_f64(
+            frames / self.pcm.sample_rate.max(1) as f64,
+        ))
+    }
+}
+
+// One playable sound: its encoded bytes, and the decoded samples when the
+// sound is hot and was decoded at load. Any other sound is decoded the first
+// time it plays and kept from then on, shared by every clone.
+#[derive(Clone)]
+pub struct Clip {
+    pub bytes: Bytes,
+    pub pcm: Option<Pcm>,
+    // `None` inside for a file that does not decode, so it is tried once.
+    decoded: Arc<OnceLock<Option<Pcm>>>,
+}
+
+impl Clip {
+    pub fn new(bytes: Bytes, pcm: Option<Pcm>) -> Self {
+        Self {
+            bytes,
+            pcm,
+            decoded: Arc::default(),
+        }
+    }
+
+    // The decoded samples, and how many bytes this call had to decode.
+    pub fn decode(&self, format: Format) -> (Option<Pcm>, u64) {
+        if let Some(pcm) = &self.pcm {
+            return (Some(pcm.clone()), 0);
+        }
+        let mut decoded = 0;
+        let pcm = self.decoded.get_or_init(|| {
+            let pcm = Pcm::decode(&self.bytes, format).ok();
+            decoded = pcm.as_ref().map_or(0, |pcm| pcm.bytes() as u64);
+            pcm
+        });
+        (pcm.clone(), decoded)
+    }
+
+    // What playing it has decoded so far.
+    pub fn decoded_bytes(&self) -> u64 {
+        match self.decoded.get() {
+            Some(Some(pcm)) => pcm.bytes() as u64,
+            _ => 0,
+        }
+    }
+}
+
+impl From<Vec<u8>> for Clip {
+    fn from(data: Vec<u8>) -> Self {
+        Self::new(data.into(), None)
+    }
+}
+
+impl From<Bytes> for Clip {
+    fn from(bytes: Bytes) -> Self {
+        Self::new(bytes, None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATES: [u32; 3] = [22_050, 44_100, 48_000];
+
+    // A WAV of `frames` frames where channel `n` holds a constant level of
+    // `(n + 1) / 4`, so a swapped or dropped channel shows.
+    fn fixture(format: Format, frames: usize) -> Bytes {
+        let spec = hound::WavSpec {
+            channels: format.channels,
+            sample_rate: format.sample_rate,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut data = Vec::new();
+        let mut writer = hound::WavWriter::new(Cursor::new(&mut data), spec).unwrap();
+        for _ in 0..frames {
+            for channel in 0..format.channels {
+                let level = (channel + 1) as f32 / 4.0;
+                writer
+                    .write_sample((level * i16::MAX as f32) as i16)
+                    .unwrap();
+            }
+        }
+        writer.finalize().unwrap();
+        data.into()
+    }
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 0.001
+    }
+
+    #[test]
+    fn every_combination_converts_to_the_engine_format() {
+        for native_channels in [1, 2] {
+            for native_rate in RATES {
+                for channels in [1, 2] {
+                    for sample_rate in RATES {
+                        let native = Format {
+                            channels: native_channels,
+                            sample_rate: native_rate,
+                        };
+                        let engine = Format {
+                            channels,
+                            sample_rate,
+                        };
+                        // A tenth of a second.
+                        let bytes = fixture(native, native_rate as usize / 10);
+                        assert_eq!(Format::probe(&bytes).unwrap(), native);
+                        let pcm = Pcm::decode(&bytes, engine).unwrap();
+                        assert_eq!(
+                            (pcm.channels, pcm.sample_rate),
+                            (channels, sample_rate),
+                            "{} to {}",
+                            native,
+                            engine
+                        );
+
+                        let frames = pcm.samples.len() / channels as usize;
+                        assert_eq!(
+                            frames,
+                            sample_rate as usize / 10,
+                            "{} to {}",
+                            native,
+                            engine
+                        );
+                        let source = PcmSource::new(pcm.clone());
+                        let length = source.total_duration().unwrap().as_secs_f64();
+                        assert!((length - 0.1).abs() < 0.001, "{} to {}", native, engine);
+
+                        let expected: Vec<f32> = match (native_channels, channels) {
+                            (1, _) => vec![0.25; channels as usize],
+                            (_, 1) => vec![0.375],
+                            _ => vec![0.25, 0.5],
+                        };
+                        for frame in pcm.samples.chunks_exact(channels as usize) {
+                            assert!(
+                                frame.iter().zip(&expected).all(|(a, b)| close(*a, *b)),
+                                "{} to {}: {:?}",
+                                native,
+                                engine,
+                                frame
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn resampling_interpolates_between_frames() {
+        let ramp: Vec<f32> = (0..4).map(|n| n as f32).collect();
+        assert_eq!(
+            resample(&ramp, 1, 22_050, 44_100),
+            [0.0, 0.5, 1.0, 1.5, 2.0, 2.5, 3.0, 3.0]
+        );
+        assert_eq!(resample(&ramp, 1, 44_100, 22_050), [0.0, 2.0]);
+    }
+
+    #[test]
+    fn more_channels_are_filled_in_turn() {
+        let stereo = [0.1, 0.2, 0.3, 0.4];
+        assert_eq!(
+            remix(&stereo, 2, 4),
+            [0.1, 0.2, 0.1, 0.2, 0.3, 0.4, 0.3, 0.4]
+        );
+        assert_eq!(remix(&[0.5, -0.5], 1, 3), [0.5, 0.5, 0.5, -0.5, -0.5, -0.5]);
+    }
+
+    #[test]
+    fn an_empty_engine_format_is_refused() {
+        let bytes = fixture(
+            Format {
+                channels: 1,
+                sample_rate: 44_100,
+            },
+            10,
+        );
+        for format in [
+            Format {
+                channels: 0,
+                sample_rate: 44_100,
+            },
+            Format {
+                channels: 2,
+                sample_rate: 0,
+            },
+        ] {
+            assert!(Pcm::decode(&bytes, format).is_err());
+        }
+    }
+
+    #[test]
+    fn a_cold_clip_decodes_once_for_all_its_clones() {
+        let format = Format {
+            channels: 2,
+            sample_rate: 48_000,
+        };
+        let clip = Clip::from(fixture(format, 480));
+        let copy = clip.clone();
+        let (first, decoded) = clip.decode(format);
+        assert_eq!(decoded, first.as_ref().unwrap().bytes() as u64);
+        let (second, decoded) = copy.decode(format);
+        assert_eq!(decoded, 0);
+        assert!(Arc::ptr_eq(
+            &first.unwrap().samples,
+            &second.unwrap().samples
+        ));
+        assert_eq!(copy.decoded_bytes(), clip.decoded_bytes());
+    }
+}
@@ -0,0 +1,291 @@
+use crate::{
+    available_themes,
+    config::{Config, TimeRange},
+    state::{MuteReason, SharedState},
+};
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{Datelike, Local, NaiveDate, NaiveDateTime, NaiveTime, TimeDelta, Weekday};
+use std::{sync::Arc, thread, time::Duration};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(15);
+const MINUTES_PER_WEEK: i64 = 7 * 24 * 60;
+
+struct Range {
+    start: NaiveTime,
+    end: NaiveTime,
+    days: Vec<Weekday>,
+}
+
+impl Range {
+    fn parse(range: &TimeRange) -> Result<Self> {
+        let time = |value: &str| {
+            NaiveTime::parse_from_str(value, "%H:%M")
+                .map_err(|_| anyhow!("`{}` is not a HH:MM time", value))
+        };
+        let start = time(&range.start)?;
+        let end = time(&range.end)?;
+        if start == end {
+            bail!("start and end are the same time");
+        }
+        let days = range
+            .days
+            .iter()
+            .map(|day| {
+                day.parse::<Weekday>()
+                    .map_err(|_| anyhow!("`{}` is not a weekday", day))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { start, end, days })
+    }
+
+    fn starts_on(&self, day: Weekday) -> bool {
+        self.days.is_empty() || self.days.contains(&day)
+    }
+
+    fn contains(&self, now: NaiveDateTime) -> bool {
+        let (time, day) = (now.time(), now.weekday());
+        if self.start < self.end {
+            self.starts_on(day) && time >= self.start && time < self.end
+        } else {
+            (self.starts_on(day) && time >= self.start)
+                || (self.starts_on(day.pred()) && time < self.end)
+        }
+    }
+}
+
+fn describe(range: &TimeRange) -> String {
+    format!("{}-{}", range.start, range.end)
+}
+
+// Times only have minute precision, so walking every minute of one week finds
+// any overlap, including ones that only happen on some weekdays.
+fn overlaps(a: &Range, b: &Range) -> bool {
+    let monday = NaiveDate::from_isoywd_opt(2024, 1, Weekday::Mon)
+        .unwrap()
+        .and_time(NaiveTime::MIN);
+    (0..MINUTES_PER_WEEK)
+        .map(|minute| monday + TimeDelta::minutes(minute))
+        .any(|at| a.contains(at) && b.contains(at))
+}
+
+pub struct Schedule {
+    quiet: Vec<Range>,
+    themes: Vec<(Range, String)>,
+    manual_override: bool,
+}
+
+impl Schedule {
+    pub fn parse(config: &Config) -> Result<Self> {
+        let quiet = config
+            .quiet_hours
+            .ranges
+            .iter()
+            .map(|range| {
+                Range::parse(range)
+                    .with_context(|| format!("Invalid quiet hours range {}", describe(range)))
+            })
+            .collect::<Result<_>>()?;
+
+        let installed = available_themes();
+        let mut themes: Vec<(Range, String)> = Vec::new();
+        for entry in &config.theme_schedule.ranges {
+            let context = || format!("Invalid theme schedule range {}", describe(&entry.range));
+            let range = Range::parse(&entry.range).with_context(context)?;
+            if !installed.contains(&entry.theme) {
+                return Err(anyhow!("Unknown theme: {}", entry.theme)).with_context(context);
+            }
+            if let Some(index) = themes.iter().position(|(other, _)| overlaps(&range, other)) {
+                return Err(anyhow!(
+                    "overlaps {}",
+                    describe(&config.theme_schedule.ranges[index].range)
+                ))
+                .with_context(context);
+            }
+            themes.push((range, entry.theme.clone()));
+        }
+        Ok(Self {
+            quiet,
+            themes,
+            manual_override: config.theme_schedule.manual_override,
+        })
+    }
+
+    fn is_quiet(&self, now: NaiveDateTime) -> bool {
+        self.quiet.iter().any(|range| range.contains(now))
+    }
+
+    fn theme_at(&self, now: NaiveDateTime) -> Option<usize> {
+        self.themes
+            .iter()
+            .position(|(range, _)| range.contains(now))
+    }
+}
+
+// Applies quiet hours and the theme schedule. Only boundaries touch the mute
+// state and the theme, so muting or picking a theme by hand holds until the
+// next boundary (unless the theme schedule turns off `manual_override`). A
+// scheduled theme is never saved as the config's theme.
+pub fn spawn(state: Arc<SharedState>, mut schedule: Schedule) {
+    let interval = state.poll_interval("schedule", CHECK_INTERVAL);
+    thread::spawn(move || {
+        let config = state.config();
+        let mut applied = (config.quiet_hours, config.theme_schedule);
+        let mut quiet = None;
+        let mut theme_range = None;
+        loop {
+            let config = state.config();
+            let current = (config.quiet_hours.clone(), config.theme_schedule.clone());
+            if current != applied {
+                match Schedule::parse(&config) {
+                    Ok(parsed) => {
+                        schedule = parsed;
+                        theme_range = None;
+                    }
+                    Err(err) => eprintln!("Ignoring schedule change: {:?}", err),
+                }
+                applied = current;
+            }
+            let now = Local::now().naive_local();
+
+            let now_quiet = schedule.is_quiet(now);
+            if quiet != Some(now_quiet) {
+                // Outside quiet hours at startup: leave the mute state alone.
+                if quiet.is_some() || now_quiet {
+                    state.set_mute_reason(MuteReason::QuietHours, now_quiet);
+                }
+                quiet = Some(now_quiet);
+            }
+
+            let now_range = schedule.theme_at(now);
+            if theme_range != Some(now_range) || !schedule.manual_override {
+                // Outside every range the config's own theme plays again.
+                let theme = now_range.map(|index| schedule.themes[index].1.as_str());
+                if state.scheduled_theme().as_deref() != theme {
+                    state.set_scheduled_theme(theme);
+                }
+                theme_range = Some(now_range);
+            }
+            thread::sleep(interval);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schedule(contents: &str) -> Result<Schedule> {
+        Schedule::parse(&Config::parse(contents)?)
+    }
+
+    // January 2024 starts on a Monday.
+    fn at(day: u32, time: &str) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2024, 1, day)
+            .unwrap()
+            .and_time(NaiveTime::parse_from_str(time, "%H:%M").unwrap())
+    }
+
+    #[test]
+    fn quiet_hours_run_past_midnight_up_to_their_end() {
+        let schedule = schedule(
+            r#"
+            [[quiet_hours.ranges]]
+            start = "22:00"
+            end = "07:00"
+            "#,
+        )
+        .unwrap();
+        assert!(!schedule.is_quiet(at(1, "21:59")));
+        assert!(schedule.is_quiet(at(1, "22:00")));
+        assert!(schedule.is_quiet(at(1, "23:59")));
+        assert!(schedule.is_quiet(at(2, "00:00")));
+        assert!(schedule.is_quiet(at(2, "06:59")));
+        assert!(!schedule.is_quiet(at(2, "07:00")));
+    }
+
+    #[test]
+    fn a_range_limited_to_some_days_runs_into_the_next() {
+        let schedule = schedule(
+            r#"
+            [[quiet_hours.ranges]]
+            start = "23:00"
+            end = "01:00"
+            days = ["mon"]
+            "#,
+        )
+        .unwrap();
+        assert!(schedule.is_quiet(at(1, "23:00")));
+        assert!(schedule.is_quiet(at(1, "23:59")));
+        assert!(schedule.is_quiet(at(2, "00:00")));
+        assert!(schedule.is_quiet(at(2, "00:59")));
+        assert!(!schedule.is_quiet(at(2, "01:00")));
+        // Tuesday starts no range of its own, and Monday morning belongs to
+        // Sunday night.
+        assert!(!schedule.is_quiet(at(2, "23:30")));
+        assert!(!schedule.is_quiet(at(1, "00:30")));
+    }
+
+    #[test]
+    fn themes_switch_at_the_ends_of_their_ranges() {
+        let schedule = schedule(
+            r#"
+            [[theme_schedule.ranges]]
+            start = "09:00"
+            end = "17:00"
+            theme = "cherrymxbrown"
+
+            [[theme_schedule.ranges]]
+            start = "17:00"
+            end = "09:00"
+            theme = "musical"
+            "#,
+        )
+        .unwrap();
+        for (time, range) in [
+            ("00:00", 1),
+            ("08:59", 1),
+            ("09:00", 0),
+            ("16:59", 0),
+            ("17:00", 1),
+            ("23:59", 1),
+        ] {
+            assert_eq!(schedule.theme_at(at(3, time)), Some(range), "{}", time);
+        }
+    }
+
+    #[test]
+    fn overlapping_theme_ranges_are_rejected_by_name() {
+        let err = schedule(
+            r#"
+            [[theme_schedule.ranges]]
+            start = "09:00"
+            end = "17:00"
+            days = ["mon"]
+            theme = "cherrymxbrown"
+
+            [[theme_schedule.ranges]]
+            start = "22:00"
+            end = "10:00"
+            days = ["sun"]
+            theme = "musical"
+            "#,
+        )
+        .err()
+        .unwrap();
+        // Only Sunday night running into Monday morning overlaps.
+        assert_eq!(
+            format!("{:#}", err),
+            "Invalid theme schedule range 22:00-10:00: overlaps 09:00-17:00"
+        );
+        let err = schedule(
+            r#"
+            [[quiet_hours.ranges]]
+            start = "22:00"
+            end = "7:60"
+            "#,
+        )
+        .err()
+        .unwrap();
+        assert!(format!("{:#}", err).starts_with("Invalid quiet hours range 22:00-7:60"));
+    }
+}
@@ -0,0 +1,92 @@
+use crate::{automute::Condition, state::MuteReason};
+use std::time::Duration;
+
+// Capture rarely starts or stops by the second, so it is polled slowly.
+const INTERVAL: Duration = Duration::from_secs(5);
+// Mute as soon as a capture is seen; a share that restarts (a new window
+// picked, say) should not let a click through in between.
+const SETTLE_ON: Duration = Duration::ZERO;
+const SETTLE_OFF: Duration = Duration::from_secs(6);
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use anyhow::{bail, Context, Result};
+    use serde_json::Value;
+    use std::process::Command;
+
+    // Screen casts go through PipeWire on Wayland (and on X11 for apps that
+    // use the desktop portal): the portal publishes a video source for
+    // the shared screen that, unlike a camera, no device backs. It runs
+    // while something captures it. Recorders that grab X11 directly do not
+    // show up.
+    pub fn capturing() -> Result<bool> {
+        let output = Command::new("pw-dump")
+            .output()
+            .context("Failed to run pw-dump")?;
+        if !output.status.success() {
+            bail!("{}", String::from_utf8_lossy(&output.stderr).trim());
+        }
+        let objects: Vec<Value> =
+            serde_json::from_slice(&output.stdout).context("Unexpected pw-dump output")?;
+        Ok(objects.iter().any(|object| {
+            let info = &object["info"];
+            let props = &info["props"];
+            object["type"] == "PipeWire:Interface:Node"
+                && props["media.class"] == "Video/Source"
+                && props.get("device.id").is_none()
+                && info["state"] == "running"
+        }))
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use anyhow::Result;
+
+    #[link(name = "CoreGraphics", kind = "framework")]
+    extern "C" {
+        // Private, but stable for years: whether any process is watching the
+        // screen, which covers screen recording and sharing alike.
+        fn CGSIsScreenWatcherPresent() -> bool;
+    }
+
+    pub fn capturing() -> Result<bool> {
+        Ok(unsafe { CGSIsScreenWatcherPresent() })
+    }
+}
+
+#[cfg(target_os = "windows")]
+mod platform {
+    use crate::mic::consent;
+    use anyhow::Result;
+
+    // Windows records Graphics Capture sessions (what browsers, Teams and
+    // OBS use) in the privacy consent store, per app like microphone use.
+    pub fn capturing() -> Result<bool> {
+        Ok(consent::in_use("graphicsCaptureProgrammatic")?
+            || consent::in_use("graphicsCaptureWithoutBorder").unwrap_or(false))
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+mod platform {
+    use anyhow::{bail, Result};
+
+    pub fn capturing() -> Result<bool> {
+        bail!("Screen capture detection is not supported on this platform")
+    }
+}
+
+// Keeps keystrokes out of recordings: mutes while the screen is being shared
+// or recorded, when `mute_on_screenshare` is set.
+pub fn condition() -> Condition {
+    Condition {
+        name: "Screen sharing",
+        reason: MuteReason::ScreenShare,
+        enabled: |config| config.mute_on_screenshare,
+        detect: |_| platform::capturing(),
+        interval: INTERVAL,
+        settle_on: SETTLE_ON,
+        settle_off: SETTLE_OFF,
+    }
+}
@@ -0,0 +1,94 @@
+use crate::{fsutil::write_atomic, KeyEvent, KeyPressType, SoundType};
+use anyhow::{anyhow, Context, Result};
+use device_query::Keycode;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::Path,
+    str::FromStr,
+    sync::mpsc::Receiver,
+    thread,
+    time::{Duration, Instant},
+};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RecordedEvent {
+    pub offset_ms: u64,
+    pub keypress: KeyPressType,
+    pub sound_type: SoundType,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+}
+
+// A typing rhythm: which kind of key went down or up, and when. The header
+// lets a replay warn when it runs against a different theme or version.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Session {
+    pub keydio_version: String,
+    pub theme: String,
+    pub events: Vec<RecordedEvent>,
+}
+
+impl Session {
+    // Blocks until the first event arrives, then collects events for `duration`.
+    pub fn record(
+        rx: &Receiver<KeyEvent>,
+        theme: &str,
+        duration: Duration,
+        with_keys: bool,
+    ) -> Result<Self> {
+        let mut session = Self {
+            keydio_version: env!("CARGO_PKG_VERSION").to_string(),
+            theme: theme.to_string(),
+            events: Vec::new(),
+        };
+        let first = rx
+            .recv()
+            .map_err(|_| anyhow!("Keyboard listener stopped before recording started"))?;
+        let started = first.at;
+        let deadline = Instant::now() + duration;
+        let mut event = first;
+        loop {
+            session.events.push(RecordedEvent {
+                offset_ms: event.at.duration_since(started).as_millis() as u64,
+                keypress: event.keypress,
+                sound_type: event.sound_type,
+                key: event.key.filter(|_| with_keys).map(|key| key.to_string()),
+            });
+            match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(next) if next.at <= deadline => event = next,
+                _ => break,
+            }
+        }
+        Ok(session)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        write_atomic(path, serde_json::to_string(self)?.as_bytes())
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents =
+            fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        serde_json::from_slice(&contents)
+            .with_context(|| format!("{} is not a keydio session", path.display()))
+    }
+
+    // Hands every event to `emit` at its recorded offset, scaled by `speed`.
+    pub fn play(&self, speed: f64, mut emit: impl FnMut(KeyEvent)) {
+        let started = Instant::now();
+        for recorded in &self.events {
+            let due = started + Duration::from_secs_f64(recorded.offset_ms as f64 / 1000.0 / speed);
+            thread::sleep(due.saturating_duration_since(Instant::now()));
+            emit(KeyEvent {
+                key: recorded
+                    .key
+                    .as_deref()
+                    .and_then(|key| Keycode::from_str(key).ok()),
+                keypress: recorded.keypress.clone(),
+                sound_type: recorded.sound_type.clone(),
+                at: Instant::now(),
+            });
+        }
+    }
+}
@@ -0,0 +1,57 @@
+use crate::{keys, KeyPressType};
+use anyhow::{bail, Context, Result};
+use device_query::Keycode;
+use std::{
+    fs,
+    io::{self, Read},
+    path::Path,
+    time::Duration,
+};
+
+pub enum Step {
+    Key(Keycode, KeyPressType),
+    Sleep(Duration),
+}
+
+// One step per line: `press A`, `release A` or `sleep 120` (milliseconds).
+// Blank lines and lines starting with `#` are skipped.
+pub fn parse(script: &str) -> Result<Vec<Step>> {
+    let mut steps = Vec::new();
+    for (number, line) in script.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let step = match line.split_whitespace().collect::<Vec<_>>()[..] {
+            ["press", key] => keys::named(key).map(|key| Step::Key(key, KeyPressType::Press)),
+            ["release", key] => keys::named(key).map(|key| Step::Key(key, KeyPressType::Release)),
+            ["sleep", ms] => ms
+                .parse()
+                .map(|ms| Step::Sleep(Duration::from_millis(ms)))
+                .with_context(|| format!("Invalid sleep time {}", ms)),
+            _ => bail!(
+                "Line {}: expected `press <key>`, `release <key>` or `sleep <ms>`, got `{}`",
+                number + 1,
+                line
+            ),
+        };
+        steps.push(step.with_context(|| format!("Line {}", number + 1))?);
+    }
+    Ok(steps)
+}
+
+// The whole script is read and checked before anything plays.
+pub fn load(script: Option<&Path>) -> Result<Vec<Step>> {
+    let contents = match script {
+        Some(path) => fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?,
+        None => {
+            let mut contents = String::new();
+            io::stdin()
+                .read_to_string(&mut contents)
+                .context("Failed to read the script from stdin")?;
+            contents
+        }
+    };
+    parse(&contents)
+}
@@ -0,0 +1,300 @@
+use crate::{
+    config::Config,
+    keys::KeySet,
+    layout,
+    metrics::Metrics,
+    profile::{Profile, Tuning},
+    sample::Clip,
+    supervise::Heartbeat,
+};
+use device_query::Keycode;
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+type ShutdownHook = Box<dyn FnOnce() + Send>;
+
+// Why a key makes no sound.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Silencer {
+    User,
+    Theme,
+}
+
+// Why keydio is muted. Each reason comes and goes on its own, and keydio
+// stays muted while any is left.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MuteReason {
+    Manual,
+    QuietHours,
+    Dnd,
+    Microphone,
+    Fullscreen,
+    ScreenShare,
+}
+
+impl MuteReason {
+    fn bit(self) -> u32 {
+        1 << self as u32
+    }
+}
+
+// Runtime settings shared by the audio thread and everything that can change
+// them while keydio is running (tray menu, config file, hotkeys, IPC).
+pub struct SharedState {
+    config: Mutex<Config>,
+    // The theme the schedule switched to. It plays instead of the config's
+    // own theme for this run only, so it never reaches the config file.
+    scheduled_theme: Mutex<Option<String>>,
+    // Resolved from `config.ignore` whenever the config is replaced.
+    ignored: Mutex<KeySet>,
+    // The `silent` list of the theme the audio thread has loaded.
+    theme_silent: Mutex<KeySet>,
+    // One bit per `MuteReason`.
+    muted: AtomicU32,
+    shutdown_hooks: Mutex<Vec<ShutdownHook>>,
+    profile: Profile,
+    tuning: Mutex<Tuning>,
+    metrics: Arc<Metrics>,
+    // Sample files of the theme that changed on disk, already decoded, for
+    // the audio thread to swap in.
+    reloaded: Mutex<Vec<(PathBuf, Clip)>>,
+    // Beats while the audio thread goes round its loop, idle or not.
+    audio_heartbeat: Heartbeat,
+}
+
+impl SharedState {
+    // The config the engine runs with also decides the keyboard layout key
+    // names resolve with.
+    pub fn new(config: Config, profile: Profile) -> Self {
+        layout::configure(config.layout);
+        Self {
+            ignored: Mutex::new(KeySet::parse_lenient(&config.ignore)),
+            theme_silent: Mutex::new(KeySet::default()),
+            config: Mutex::new(config),
+            scheduled_theme: Mutex::new(None),
+            muted: AtomicU32::new(0),
+            shutdown_hooks: Mutex::new(Vec::new()),
+            profile,
+            tuning: Mutex::new(Tuning::new(profile)),
+            metrics: Arc::new(Metrics::new()),
+            reloaded: Mutex::new(Vec::new()),
+            audio_heartbeat: Heartbeat::default(),
+        }
+    }
+
+    // The config in effect, with the scheduled theme if there is one.
+    pub fn config(&self) -> Config {
+        let mut config = self.stored_config();
+        if let Some(theme) = self.scheduled_theme() {
+            config.theme = theme;
+        }
+        config
+    }
+
+    // The config as the config file should hold it.
+    pub fn stored_config(&self) -> Config {
+        self.config.lock().unwrap().clone()
+    }
+
+    // For crash reports: never blocks, and gives up if the lock is poisoned.
+    pub fn try_theme(&self) -> Option<String> {
+        if let Some(theme) = self.scheduled_theme.try_lock().ok()?.clone() {
+            return Some(theme);
+        }
+        Some(self.config.try_lock().ok()?.theme.clone())
+    }
+
+    pub fn update(&self, config: Config) {
+        layout::configure(config.layout);
+        *self.ignored.lock().unwrap() = KeySet::parse_lenient(&config.ignore);
+        *self.config.lock().unwrap() = config;
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.muted.load(Ordering::Relaxed) != 0
+    }
+
+    pub fn set_mute_reason(&self, reason: MuteReason, active: bool) {
+        if active {
+            self.muted.fetch_or(reason.bit(), Ordering::Relaxed);
+        } else {
+            self.muted.fetch_and(!reason.bit(), Ordering::Relaxed);
+        }
+    }
+
+    // By hand. Unmuting clears every reason, so it brings the sound back
+    // until the next condition starts.
+    pub fn set_muted(&self, muted: bool) {
+        if muted {
+            self.set_mute_reason(MuteReason::Manual, true);
+        } else {
+            self.muted.store(0, Ordering::Relaxed);
+        }
+    }
+
+    pub fn toggle_mute(&self) -> bool {
+        let toggled = |mask| {
+            Some(if mask == 0 {
+                MuteReason::Manual.bit()
+            } else {
+                0
+            })
+        };
+        self.muted
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, toggled)
+            .unwrap()
+            == 0
+    }
+
+    pub fn set_volume(&self, volume: f32) {
+        self.config.lock().unwrap().volume = volume.clamp(0.0, 1.0);
+    }
+
+    // A theme picked by hand replaces any scheduled one.
+    pub fn set_theme(&self, theme: &str) {
+        *self.scheduled_theme.lock().unwrap() = None;
+        self.config.lock().unwrap().theme = theme.to_string();
+    }
+
+    pub fn scheduled_theme(&self) -> Option<String> {
+        self.scheduled_theme.lock().unwrap().clone()
+    }
+
+    // `None` goes back to the config's theme.
+    pub fn set_scheduled_theme(&self, theme: Option<&str>) {
+        *self.scheduled_theme.lock().unwrap() = theme.map(str::to_string);
+    }
+
+    // Goes back to `theme` after the one in effect failed to load, in place
+    // of whichever was in effect.
+    pub fn revert_theme(&self, theme: &str) {
+        let mut scheduled = self.scheduled_theme.lock().unwrap();
+        match scheduled.as_mut() {
+            Some(scheduled) => *scheduled = theme.to_string(),
+            None => self.config.lock().unwrap().theme = theme.to_string(),
+        }
+    }
+
+    pub fn set_theme_silent(&self, silent: KeySet) {
+        *self.theme_silent.lock().unwrap() = silent;
+    }
+
+    pub fn silenced_by(&self, key: &Keycode) -> Option<Silencer> {
+        if self.ignored.lock().unwrap().contains(key) {
+            Some(Silencer::User)
+        } else if self.theme_silent.lock().unwrap().contains(key) {
+            Some(Silencer::Theme)
+        } else {
+            None
+        }
+    }
+
+    pub fn profile(&self) -> Profile {
+        self.profile
+    }
+
+    // The interval a background poller should use under the active profile;
+    // recorded so the stats can show what is actually running.
+    pub fn poll_interval(&self, name: &str, base: Duration) -> Duration {
+        let interval = self.profile.poll_interval(base);
+        self.tuning
+            .lock()
+            .unwrap()
+            .poll_intervals_ms
+            .insert(name.to_string(), interval.as_millis() as u64);
+        interval
+    }
+
+    pub fn set_audio_thread_priority(&self, applied: String) {
+        self.tuning.lock().unwrap().audio_thread_priority = applied;
+    }
+
+    pub fn tuning(&self) -> Tuning {
+        self.tuning.lock().unwrap().clone()
+    }
+
+    pub fn reload_sample(&self, path: PathBuf, clip: Clip) {
+        self.reloaded.lock().unwrap().push((path, clip));
+    }
+
+    pub fn take_reloaded(&self) -> Vec<(PathBuf, Clip)> {
+        std::mem::take(&mut *self.reloaded.lock().unwrap())
+    }
+
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
+    }
+
+    pub fn audio_heartbeat(&self) -> &Heartbeat {
+        &self.audio_heartbeat
+    }
+
+    pub fn on_shutdown(&self, hook: impl FnOnce() + Send + 'static) {
+        self.shutdown_hooks.lock().unwrap().push(Box::new(hook));
+    }
+
+    // Runs every registered hook once; later calls find the list empty.
+    pub fn shutdown(&self) {
+        let hooks = std::mem::take(&mut *self.shutdown_hooks.lock().unwrap());
+        for hook in hooks {
+            hook();
+        }
+    }
+
+    // For the crash handler: runs the hooks unless the list is locked or
+    // poisoned, which would mean the crash happened in the middle of it.
+    pub fn try_shutdown(&self) {
+        let Ok(mut hooks) = self.shutdown_hooks.try_lock() else {
+            return;
+        };
+        let hooks = std::mem::take(&mut *hooks);
+        for hook in hooks {
+            hook();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn muted_while_any_reason_is_left() {
+        let state = SharedState::new(Config::default(), Profile::default());
+        state.set_mute_reason(MuteReason::Microphone, true);
+        state.set_mute_reason(MuteReason::Dnd, true);
+        state.set_mute_reason(MuteReason::Microphone, false);
+        assert!(state.is_muted());
+        state.set_muted(true);
+        state.set_mute_reason(MuteReason::Dnd, false);
+        assert!(state.is_muted());
+        state.set_mute_reason(MuteReason::QuietHours, true);
+        assert!(!state.toggle_mute());
+        assert!(!state.is_muted());
+        assert!(state.toggle_mute());
+        state.set_mute_reason(MuteReason::QuietHours, false);
+        assert!(state.is_muted());
+    }
+
+    #[test]
+    fn a_scheduled_theme_stays_out_of_the_stored_config() {
+        let state = SharedState::new(Config::default(), Profile::default());
+        let chosen = state.config().theme;
+        state.set_scheduled_theme(Some("night"));
+        assert_eq!(state.config().theme, "night");
+        assert_eq!(state.stored_config().theme, chosen);
+        state.set_scheduled_theme(None);
+        assert_eq!(state.config().theme, chosen);
+        // Picking a theme by hand ends the scheduled one.
+        state.set_scheduled_theme(Some("night"));
+        state.set_theme("day");
+        assert_eq!(state.config().theme, "day");
+        assert_eq!(state.scheduled_theme(), None);
+    }
+}
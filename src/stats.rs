@@ -0,0 +1,298 @@
+use crate::{
+    config::{ChordConfig, ComboConfig},
+    fsutil::write_atomic,
+    profile::Tuning,
+    state::SharedState,
+    KeyEvent, KeyPressType, SoundType,
+};
+use anyhow::Result;
+use serde::Serialize;
+use std::{
+    collections::VecDeque,
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{Receiver, RecvTimeoutError},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+const REPORT_INTERVAL: Duration = Duration::from_secs(60);
+const WPM_WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ClassCounts {
+    pub generic: u64,
+    pub space: u64,
+    pub enter: u64,
+    pub backspace: u64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Snapshot {
+    pub session_seconds: u64,
+    pub keystrokes: u64,
+    pub per_class: ClassCounts,
+    pub wpm: f64,
+    pub tuning: Tuning,
+}
+
+impl Snapshot {
+    pub fn summary(&self) -> String {
+        format!(
+            "{} keystrokes (generic {}, space {}, enter {}, backspace {}), {:.0} wpm; {}",
+            self.keystrokes,
+            self.per_class.generic,
+            self.per_class.space,
+            self.per_class.enter,
+            self.per_class.backspace,
+            self.wpm,
+            self.tuning.summary()
+        )
+    }
+}
+
+// Only counts are kept: the key that was pressed is reduced to its sound type
+// before anything is stored.
+struct Counters {
+    started: Instant,
+    keystrokes: u64,
+    per_class: ClassCounts,
+    in_word: bool,
+    word_ends: VecDeque<Instant>,
+}
+
+// Keystrokes in a row, for combo sounds. The audio thread keeps its own, so
+// a combo plays right with the keystroke that completes it.
+#[derive(Default)]
+pub struct Streak {
+    count: u64,
+    last: Option<Instant>,
+}
+
+impl Streak {
+    // Counts a press; returns whether it completes a combo.
+    pub fn press(&mut self, config: &ComboConfig, event: &KeyEvent) -> bool {
+        if event.keypress != KeyPressType::Press {
+            return false;
+        }
+        let pause = Duration::from_millis(config.pause_ms);
+        if self
+            .last
+            .is_some_and(|last| event.at.saturating_duration_since(last) > pause)
+        {
+            self.count = 0;
+        }
+        self.last = Some(event.at);
+        if event.sound_type == SoundType::Backspace && config.reset_on_backspace {
+            self.count = 0;
+            return false;
+        }
+        self.count += 1;
+        let every = config.every.max(1);
+        self.count.is_multiple_of(every) && (config.repeat || self.count == every)
+    }
+}
+
+// Presses within the window of the first press of a chord. Lives on the
+// audio thread next to `Streak`; the first key never waits for the others.
+#[derive(Default)]
+pub struct Chord {
+    first: Option<Instant>,
+    keys: u32,
+}
+
+impl Chord {
+    // Counts a press; returns how many keys of its chord came before it, so
+    // zero for a key pressed on its own.
+    pub fn press(&mut self, config: &ChordConfig, event: &KeyEvent) -> u32 {
+        if event.keypress != KeyPressType::Press {
+            return 0;
+        }
+        let window = Duration::from_millis(config.window_ms);
+        match self.first {
+            Some(first) if event.at.saturating_duration_since(first) <= window => {
+                self.keys += 1;
+            }
+            _ => {
+                self.first = Some(event.at);
+                self.keys = 0;
+            }
+        }
+        self.keys
+    }
+}
+
+pub struct Stats {
+    state: Arc<SharedState>,
+    counters: Mutex<Counters>,
+}
+
+impl Stats {
+    pub fn new(state: Arc<SharedState>) -> Self {
+        Self {
+            state,
+            counters: Mutex::new(Counters {
+                started: Instant::now(),
+                keystrokes: 0,
+                per_class: ClassCounts::default(),
+                in_word: false,
+                word_ends: VecDeque::new(),
+            }),
+        }
+    }
+
+    fn record(&self, event: &KeyEvent) {
+        if event.keypress != KeyPressType::Press {
+            return;
+        }
+        let mut counters = self.counters.lock().unwrap();
+        counters.keystrokes += 1;
+        match event.sound_type {
+            SoundType::Generic => {
+                counters.per_class.generic += 1;
+                counters.in_word = true;
+            }
+            SoundType::Space | SoundType::Enter => {
+                if event.sound_type == SoundType::Space {
+                    counters.per_class.space += 1;
+                } else {
+                    counters.per_class.enter += 1;
+                }
+                if counters.in_word {
+                    counters.in_word = false;
+                    counters.word_ends.push_back(event.at);
+                }
+            }
+            SoundType::Backspace => counters.per_class.backspace += 1,
+        }
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        let mut counters = self.counters.lock().unwrap();
+        let now = Instant::now();
+        while let Some(end) = counters.word_ends.front() {
+            if now.duration_since(*end) <= WPM_WINDOW {
+                break;
+            }
+            counters.word_ends.pop_front();
+        }
+        let elapsed = now.duration_since(counters.started);
+        let window = elapsed.min(WPM_WINDOW).as_secs_f64().max(1.0);
+        Snapshot {
+            session_seconds: elapsed.as_secs(),
+            keystrokes: counters.keystrokes,
+            per_class: counters.per_class.clone(),
+            wpm: counters.word_ends.len() as f64 * 60.0 / window,
+            tuning: self.state.tuning(),
+        }
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_string_pretty(&self.snapshot())?;
+        write_atomic(path, json.as_bytes())
+    }
+}
+
+// Counts events on their own thread so the audio thread never waits on the
+// stats lock, and reports once a minute.
+pub fn spawn(stats: Arc<Stats>, rx: Receiver<KeyEvent>, file: Option<PathBuf>) {
+    thread::spawn(move || {
+        let mut next_report = Instant::now() + REPORT_INTERVAL;
+        loop {
+            let timeout = next_report.saturating_duration_since(Instant::now());
+            match rx.recv_timeout(timeout) {
+                Ok(event) => stats.record(&event),
+                Err(RecvTimeoutError::Timeout) => {
+                    next_report += REPORT_INTERVAL;
+                    eprintln!("stats: {}", stats.snapshot().summary());
+                    if let Some(file) = &file {
+                        if let Err(err) = stats.write(file) {
+                            eprintln!("{:?}", err);
+                        }
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn press(sound_type: SoundType, at: Instant) -> KeyEvent {
+        KeyEvent {
+            key: None,
+            keypress: KeyPressType::Press,
+            sound_type,
+            at,
+        }
+    }
+
+    // Which of `keys` (`b` for Backspace, a digit for a pause of that many
+    // seconds first) complete a combo.
+    fn combos(config: &ComboConfig, keys: &str) -> Vec<usize> {
+        let mut streak = Streak::default();
+        let mut at = Instant::now();
+        let mut hits = Vec::new();
+        let mut index = 0;
+        for key in keys.chars() {
+            if let Some(seconds) = key.to_digit(10) {
+                at += Duration::from_secs(seconds.into());
+                continue;
+            }
+            at += Duration::from_millis(100);
+            let sound_type = match key {
+                'b' => SoundType::Backspace,
+                _ => SoundType::Generic,
+            };
+            if streak.press(config, &press(sound_type, at)) {
+                hits.push(index);
+            }
+            index += 1;
+        }
+        hits
+    }
+
+    #[test]
+    fn combos_play_every_n_keystrokes_in_a_row() {
+        let config = ComboConfig {
+            enabled: true,
+            every: 3,
+            ..ComboConfig::default()
+        };
+        assert_eq!(combos(&config, "aaaaaaa"), [2, 5]);
+        assert_eq!(combos(&config, "aabaaa"), [5]);
+        assert_eq!(combos(&config, "aa3aaa"), [4]);
+        let once = ComboConfig {
+            repeat: false,
+            ..config.clone()
+        };
+        assert_eq!(combos(&once, "aaaaaaa"), [2]);
+        let keep = ComboConfig {
+            reset_on_backspace: false,
+            ..config
+        };
+        assert_eq!(combos(&keep, "aabaaa"), [2, 5]);
+    }
+
+    #[test]
+    fn chords_count_presses_within_the_window_of_the_first() {
+        let config = ChordConfig::default();
+        let mut chord = Chord::default();
+        let at = Instant::now();
+        let keys: Vec<u32> = [0, 4, 9, 30, 45, 200]
+            .iter()
+            .map(|ms| {
+                chord.press(
+                    &config,
+                    &press(SoundType::Generic, at + Duration::from_millis(*ms)),
+                )
+            })
+            .collect();
+        assert_eq!(keys, [0, 1, 2, 0, 0, 0]);
+    }
+}
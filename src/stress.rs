@@ -0,0 +1,133 @@
+use crate::{keys, metrics::Runtime, state::SharedState, KeyEvent, KeyPressType};
+use anyhow::{bail, Result};
+use device_query::Keycode;
+use rand::seq::SliceRandom;
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+pub struct Storm {
+    pub generated: u64,
+    // Most events waiting in the audio queue at once.
+    pub peak_depth: u64,
+    pub elapsed: Duration,
+}
+
+// Hands `rate` random key events a second to `emit` for `duration`: presses
+// of random keys, each followed by its release.
+pub fn generate(
+    state: &SharedState,
+    rate: u32,
+    duration: Duration,
+    mut emit: impl FnMut(KeyEvent),
+) -> Storm {
+    let keys: Vec<Keycode> = keys::NAMES
+        .iter()
+        .filter_map(|name| keys::named(name).ok())
+        .collect();
+    let mut rng = rand::thread_rng();
+    let interval = Duration::from_secs_f64(1.0 / rate.max(1) as f64);
+    let started = Instant::now();
+    let mut storm = Storm {
+        generated: 0,
+        peak_depth: 0,
+        elapsed: Duration::ZERO,
+    };
+    let mut down: Option<Keycode> = None;
+    loop {
+        let due = started + interval.mul_f64(storm.generated as f64);
+        if due.duration_since(started) >= duration {
+            break;
+        }
+        thread::sleep(due.saturating_duration_since(Instant::now()));
+        let event = match down.take() {
+            Some(key) => KeyEvent::new(key, KeyPressType::Release),
+            None => {
+                let key = *keys.choose(&mut rng).unwrap();
+                down = Some(key);
+                KeyEvent::new(key, KeyPressType::Press)
+            }
+        };
+        emit(event);
+        storm.generated += 1;
+        let runtime = state.metrics().snapshot();
+        let taken = runtime.events_received + runtime.events_overflowed;
+        storm.peak_depth = storm.peak_depth.max(storm.generated.saturating_sub(taken));
+    }
+    storm.elapsed = started.elapsed();
+    storm
+}
+
+// Prints what happened to the storm once the engine has drained and shut
+// down, and fails if the counters break an invariant: more voices than
+// `max_voices` at once, a queue deeper than `queue`, or events unaccounted
+// for.
+pub fn report(storm: &Storm, runtime: &Runtime, max_voices: u64, queue: u64) -> Result<()> {
+    let failed = runtime
+        .events_dropped
+        .saturating_sub(runtime.events_muted + runtime.events_filtered + runtime.events_capped);
+    println!(
+        "Generated {} events in {:.1}s ({:.0}/s)",
+        storm.generated,
+        storm.elapsed.as_secs_f64(),
+        storm.generated as f64 / storm.elapsed.as_secs_f64().max(f64::EPSILON)
+    );
+    println!(
+        "Played {}, dropped {} ({} with a full queue, {} muted, {} filtered by the mode, \
+         {} over the voice cap, {} without a playable sample)",
+        runtime.events_played,
+        runtime.events_dropped + runtime.events_overflowed,
+        runtime.events_overflowed,
+        runtime.events_muted,
+        runtime.events_filtered,
+        runtime.events_capped,
+        failed
+    );
+    println!(
+        "Peak voices {} of {} ({} voices over the cap), peak queue depth {} of {}",
+        runtime.voices_peak, max_voices, runtime.voices_dropped, storm.peak_depth, queue
+    );
+
+    let mut violations = Vec::new();
+    if runtime.events_received + runtime.events_overflowed != storm.generated {
+        violations.push(format!(
+            "{} events generated, but the audio thread received {} and dropped {}",
+            storm.generated, runtime.events_received, runtime.events_overflowed
+        ));
+    }
+    if runtime.events_played
+        + runtime.events_muted
+        + runtime.events_filtered
+        + runtime.events_capped
+        > runtime.events_received
+    {
+        violations.push("more events accounted for than were received".to_string());
+    }
+    if runtime.voices_peak > max_voices {
+        violations.push(format!(
+            "{} voices played at once, more than the {} allowed",
+            runtime.voices_peak, max_voices
+        ));
+    }
+    // The audio thread may hold one more it has taken but not yet counted.
+    if storm.peak_depth > queue + 1 {
+        violations.push(format!(
+            "{} events waited in the audio queue, which holds {}",
+            storm.peak_depth, queue
+        ));
+    }
+    if runtime.voices_active != 0 {
+        violations.push(format!(
+            "{} voices outlived the engine",
+            runtime.voices_active
+        ));
+    }
+    for violation in &violations {
+        eprintln!("Invariant violated: {}", violation);
+    }
+    if !violations.is_empty() {
+        bail!("{} invariants violated", violations.len());
+    }
+    Ok(())
+}
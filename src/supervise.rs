@@ -0,0 +1,304 @@
+use crate::{crash, KeyPressType};
+use anyhow::{bail, Result};
+use device_query::{DeviceQuery, DeviceState, Keycode};
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+// As often as device_query's own listener polls.
+const POLL_INTERVAL: Duration = Duration::from_micros(100);
+
+// Bumped by a thread every time round its loop, so another one can tell it
+// is still going.
+#[derive(Clone, Default)]
+pub struct Heartbeat(Arc<AtomicU64>);
+
+impl Heartbeat {
+    pub fn beat(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// Notices a heartbeat that has stopped.
+pub struct Pulse {
+    heartbeat: Heartbeat,
+    beats: u64,
+    beat_at: Instant,
+}
+
+impl Pulse {
+    pub fn new(heartbeat: &Heartbeat) -> Self {
+        Self {
+            heartbeat: heartbeat.clone(),
+            beats: 0,
+            beat_at: Instant::now(),
+        }
+    }
+
+    // Whether no beat came for `stall` up to `now`.
+    pub fn stalled(&mut self, now: Instant, stall: Duration) -> bool {
+        let beats = self.heartbeat.0.load(Ordering::Relaxed);
+        if beats != self.beats {
+            self.beats = beats;
+            self.beat_at = now;
+            return false;
+        }
+        now.duration_since(self.beat_at) >= stall
+    }
+}
+
+// Where key events come from: the keyboard, or a script in tests.
+pub trait Input {
+    // The keys down right now; `None` once the backend has died.
+    fn keys(&mut self) -> Option<Vec<Keycode>>;
+}
+
+// device_query's callbacks all hang off one event loop thread, started once
+// and never again, so a listener that has to be restartable polls a
+// `DeviceState` of its own. A new one reconnects to the display server.
+//
+// Failing to connect is the only error device_query reports. A connection
+// lost later is not: it shows up as a panic, as a poll that never returns,
+// or on X11 as Xlib ending the process, so the supervisor catches just the
+// first two.
+pub struct Keyboard(Option<DeviceState>);
+
+impl Keyboard {
+    pub fn new() -> Self {
+        Self(DeviceState::checked_new())
+    }
+}
+
+impl Input for Keyboard {
+    fn keys(&mut self) -> Option<Vec<Keycode>> {
+        self.0.as_ref().map(DeviceQuery::get_keys)
+    }
+}
+
+// Opens the input on the listener thread, since a `DeviceState` cannot move
+// between threads.
+pub type Open = Arc<dyn Fn() -> Box<dyn Input> + Send + Sync>;
+pub type OnKey = Arc<dyn Fn(Keycode, KeyPressType) + Send + Sync>;
+
+pub struct Settings {
+    // How often the supervisor looks at the listener.
+    pub check: Duration,
+    // A listener that has not polled for this long is taken for hung.
+    pub stall: Duration,
+    // More restarts than this within `window` means input is broken for
+    // good, not just interrupted by a suspend.
+    pub max_restarts: usize,
+    pub window: Duration,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            check: Duration::from_secs(1),
+            stall: Duration::from_secs(5),
+            max_restarts: 5,
+            window: Duration::from_secs(60),
+        }
+    }
+}
+
+struct Listener {
+    handle: JoinHandle<()>,
+    // Beats on every poll.
+    pulse: Pulse,
+    // Set when the listener is replaced; one that was hung and wakes up
+    // later goes away without delivering anything.
+    stop: Arc<AtomicBool>,
+}
+
+impl Listener {
+    // Keys already down when it starts do not sound, so a restart does not
+    // press held keys again.
+    fn start(open: &Open, on_key: &OnKey) -> Result<Self> {
+        let heartbeat = Heartbeat::default();
+        let stop = Arc::new(AtomicBool::new(false));
+        let handle = thread::Builder::new().name("input".to_string()).spawn({
+            let (open, on_key) = (Arc::clone(open), Arc::clone(on_key));
+            let (heartbeat, stop) = (heartbeat.clone(), Arc::clone(&stop));
+            move || {
+                crash::supervised();
+                let mut input = open();
+                let Some(mut down) = input.keys() else {
+                    return;
+                };
+                loop {
+                    heartbeat.beat();
+                    let Some(keys) = input.keys() else {
+                        return;
+                    };
+                    if stop.load(Ordering::Relaxed) {
+                        return;
+                    }
+                    for key in keys.iter().filter(|key| !down.contains(key)) {
+                        on_key(*key, KeyPressType::Press);
+                    }
+                    for key in down.iter().filter(|key| !keys.contains(key)) {
+                        on_key(*key, KeyPressType::Release);
+                    }
+                    down = keys;
+                    thread::sleep(POLL_INTERVAL);
+                }
+            }
+        })?;
+        Ok(Self {
+            handle,
+            pulse: Pulse::new(&heartbeat),
+            stop,
+        })
+    }
+
+    // What is wrong with the listener, if anything.
+    fn failure(&mut self, now: Instant, stall: Duration) -> Option<&'static str> {
+        if self.handle.is_finished() {
+            return Some("stopped");
+        }
+        self.pulse
+            .stalled(now, stall)
+            .then_some("stopped responding")
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+struct Restarts {
+    max: usize,
+    window: Duration,
+    times: VecDeque<Instant>,
+}
+
+impl Restarts {
+    // Counts a restart at `now`, or fails when there were too many lately.
+    fn record(&mut self, now: Instant, failure: &str) -> Result<()> {
+        while self
+            .times
+            .front()
+            .is_some_and(|at| now.duration_since(*at) > self.window)
+        {
+            self.times.pop_front();
+        }
+        if self.times.len() >= self.max {
+            bail!(
+                "The keyboard listener {} again after {} restarts within {}s; giving up",
+                failure,
+                self.max,
+                self.window.as_secs()
+            );
+        }
+        self.times.push_back(now);
+        Ok(())
+    }
+}
+
+// Runs the keyboard listener, and starts a fresh one whenever it ends or
+// hangs. `companion` checks the other threads keydio cannot do without at
+// every look; its error ends the supervision, as do too many restarts.
+pub fn run(
+    settings: Settings,
+    open: Open,
+    on_key: OnKey,
+    mut companion: impl FnMut() -> Result<()>,
+) -> Result<()> {
+    let mut listener = Listener::start(&open, &on_key)?;
+    let mut restarts = Restarts {
+        max: settings.max_restarts,
+        window: settings.window,
+        times: VecDeque::new(),
+    };
+    loop {
+        thread::sleep(settings.check);
+        companion()?;
+        let now = Instant::now();
+        if let Some(failure) = listener.failure(now, settings.stall) {
+            restarts.record(now, failure)?;
+            eprintln!("The keyboard listener {}, restarting it", failure);
+            listener = Listener::start(&open, &on_key)?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulate::{self, Step};
+    use std::sync::Mutex;
+
+    // Plays a simulate script one step per poll, then dies.
+    struct Scripted {
+        steps: VecDeque<Step>,
+        down: Vec<Keycode>,
+    }
+
+    impl Input for Scripted {
+        fn keys(&mut self) -> Option<Vec<Keycode>> {
+            match self.steps.pop_front()? {
+                Step::Key(key, KeyPressType::Press) => self.down.push(key),
+                Step::Key(key, _) => self.down.retain(|down| *down != key),
+                Step::Sleep(duration) => thread::sleep(duration),
+            }
+            Some(self.down.clone())
+        }
+    }
+
+    #[test]
+    fn a_pulse_stalls_once_the_beats_stop() {
+        let heartbeat = Heartbeat::default();
+        let mut pulse = Pulse::new(&heartbeat);
+        let stall = Duration::from_secs(5);
+        let start = Instant::now();
+        heartbeat.beat();
+        assert!(!pulse.stalled(start + Duration::from_secs(4), stall));
+        heartbeat.beat();
+        // Counted from the last beat seen, not from the start.
+        assert!(!pulse.stalled(start + Duration::from_secs(8), stall));
+        assert!(pulse.stalled(start + Duration::from_secs(13), stall));
+    }
+
+    #[test]
+    fn a_dead_listener_is_restarted_until_the_cap() {
+        let opened = Arc::new(AtomicU64::new(0));
+        let open: Open = Arc::new({
+            let opened = Arc::clone(&opened);
+            move || {
+                opened.fetch_add(1, Ordering::Relaxed);
+                let script = "sleep 0\npress A\nrelease A\nsleep 0";
+                Box::new(Scripted {
+                    steps: simulate::parse(script).unwrap().into(),
+                    down: Vec::new(),
+                })
+            }
+        });
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let on_key: OnKey = Arc::new({
+            let events = Arc::clone(&events);
+            move |key, keypress| events.lock().unwrap().push((key, keypress))
+        });
+        let settings = Settings {
+            check: Duration::from_millis(20),
+            stall: Duration::from_secs(60),
+            max_restarts: 3,
+            window: Duration::from_secs(60),
+        };
+        let err = run(settings, open, on_key, || Ok(())).unwrap_err();
+        assert!(err.to_string().contains("after 3 restarts"));
+        // The first listener and three restarts each typed A once.
+        assert_eq!(opened.load(Ordering::Relaxed), 4);
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 8);
+        assert_eq!(events[6], (Keycode::A, KeyPressType::Press));
+    }
+}
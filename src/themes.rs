@@ -0,0 +1,405 @@
+use crate::{
+    config::{Config, EngineConfig, MusicalConfig},
+    hold::Held,
+    loudness::{Gain, GainSource},
+    musical,
+    overrides::{self, KeySounds},
+    sample::Clip,
+    state::SharedState,
+    velocity::Velocity,
+    AppState, KeyEvent,
+};
+use std::{
+    collections::BTreeMap,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+// One sample to play for an event, with the gain of the layer it came from.
+pub struct Pick {
+    pub clip: Clip,
+    pub gain: f32,
+    // Set once the snapshot the sample came from has been replaced; voices
+    // watching it fade out.
+    pub retired: Arc<AtomicBool>,
+}
+
+// The loaded main theme and the layers under it. The audio thread swaps the
+// whole set at once between two events, so every event is played from a
+// single snapshot.
+pub struct ThemeSet {
+    themes: Vec<AppState>,
+    // One per theme, set once that theme is replaced; only its voices fade
+    // out, those of the themes kept play on.
+    retired: Vec<Arc<AtomicBool>>,
+    key_sounds_retired: Arc<AtomicBool>,
+    // What the built-in musical theme was rendered with.
+    musical: MusicalConfig,
+    // The format hot samples were converted to.
+    engine: EngineConfig,
+    auto_gain: bool,
+    // The user's `key_sounds`, kept across theme switches.
+    key_sounds: KeySounds,
+    key_sounds_config: BTreeMap<String, String>,
+}
+
+// Measures a theme again after its samples or `auto_gain` changed. The
+// musical theme and one with its own `default_volume` keep their gain.
+fn regain(app: &mut AppState, config: &Config) {
+    if app.theme != musical::THEME && app.gain.source != GainSource::Manifest {
+        app.gain = Gain::new(
+            config.auto_gain,
+            None,
+            &app.audio_press,
+            config.engine.format(),
+        );
+    }
+}
+
+fn retire(retired: &mut Arc<AtomicBool>) {
+    retired.store(true, Ordering::Relaxed);
+    *retired = Arc::new(AtomicBool::new(false));
+}
+
+impl ThemeSet {
+    // `themes` as loaded with `config`.
+    pub fn new(themes: Vec<AppState>, config: &Config) -> Self {
+        Self {
+            retired: themes
+                .iter()
+                .map(|_| Arc::new(AtomicBool::new(false)))
+                .collect(),
+            themes,
+            key_sounds_retired: Arc::new(AtomicBool::new(false)),
+            musical: config.musical.clone(),
+            engine: config.engine.clone(),
+            auto_gain: config.auto_gain,
+            key_sounds: overrides::load(&config.key_sounds),
+            key_sounds_config: config.key_sounds.clone(),
+        }
+    }
+
+    pub fn main(&self) -> &AppState {
+        &self.themes[0]
+    }
+
+    pub fn themes(&self) -> &[AppState] {
+        &self.themes
+    }
+
+    // Brings the set in line with `config`, reusing themes that are already
+    // loaded, and reloads `key_sounds` when they changed. A new engine format
+    // reloads every theme, and a change of `auto_gain` measures them again. A
+    // main theme that fails to load is switched back in `state`; a layer that
+    // fails stays in place, silent. Returns whether the set changed; voices
+    // of the themes it dropped fade out.
+    pub fn sync(&mut self, state: &SharedState, config: &Config) -> bool {
+        if config.key_sounds != self.key_sounds_config {
+            self.key_sounds = overrides::load(&config.key_sounds);
+            self.key_sounds_config = config.key_sounds.clone();
+            retire(&mut self.key_sounds_retired);
+        }
+        let gain_changed = config.auto_gain != self.auto_gain;
+        if gain_changed {
+            self.auto_gain = config.auto_gain;
+            for app in &mut self.themes {
+                regain(app, config);
+            }
+        }
+        let wanted: Vec<&str> = std::iter::once(config.theme.as_str())
+            .chain(config.layers.iter().map(|layer| layer.theme.as_str()))
+            .collect();
+        let musical_changed = config.musical != self.musical && wanted.contains(&musical::THEME);
+        let engine_changed = config.engine != self.engine;
+        if !musical_changed
+            && !engine_changed
+            && self.themes.len() == wanted.len()
+            && self
+                .themes
+                .iter()
+                .zip(&wanted)
+                .all(|(app, theme)| app.theme == *theme)
+        {
+            return gain_changed;
+        }
+        let mut loaded: Vec<(AppState, Arc<AtomicBool>)> = std::mem::take(&mut self.themes)
+            .into_iter()
+            .zip(std::mem::take(&mut self.retired))
+            .collect();
+        let mut dropped = Vec::new();
+        if musical_changed {
+            let (musical, others) = loaded
+                .into_iter()
+                .partition(|(app, _)| app.theme == musical::THEME);
+            dropped.extend(musical);
+            loaded = others;
+            self.musical = config.musical.clone();
+        }
+        if engine_changed {
+            dropped.append(&mut loaded);
+            self.engine = config.engine.clone();
+        }
+        let mut themes = Vec::with_capacity(wanted.len());
+        for (index, theme) in wanted.iter().enumerate() {
+            if let Some(position) = loaded.iter().position(|(app, _)| app.theme == *theme) {
+                themes.push(loaded.swap_remove(position));
+                continue;
+            }
+            let fresh = Arc::new(AtomicBool::new(false));
+            let mut app = AppState::new(theme);
+            match app.load(config) {
+                Ok(()) => themes.push((app, fresh)),
+                Err(err) if index == 0 && !loaded.is_empty() => {
+                    eprintln!("Failed to load theme {}: {:?}", theme, err);
+                    // Keep playing the previous main theme.
+                    let previous = loaded.swap_remove(0);
+                    state.revert_theme(&previous.0.theme);
+                    themes.push(previous);
+                }
+                Err(err) => {
+                    eprintln!("Failed to load theme layer {}: {:?}", theme, err);
+                    themes.push((AppState::new(theme), fresh));
+                }
+            }
+        }
+        for (_, retired) in dropped.into_iter().chain(loaded) {
+            retired.store(true, Ordering::Relaxed);
+        }
+        (self.themes, self.retired) = themes.into_iter().unzip();
+        true
+    }
+
+    // Swaps a sample file that changed on disk into every pool playing it,
+    // and measures a theme with auto gain again when one of its press samples
+    // changed. Returns whether any did.
+    pub fn replace(&mut self, config: &Config, path: &Path, clip: &Clip) -> bool {
+        let mut replaced = false;
+        for app in &mut self.themes {
+            let mut press_changed = false;
+            for (index, sounds) in [
+                &mut app.audio_press,
+                &mut app.audio_release,
+                &mut app.audio_hold,
+            ]
+            .into_iter()
+            .enumerate()
+            {
+                for sound in sounds.iter_mut() {
+                    if sound.path.as_deref() == Some(path) {
+                        sound.clip = clip.clone();
+                        replaced = true;
+                        press_changed |= index == 0;
+                    }
+                }
+            }
+            if press_changed {
+                regain(app, config);
+            }
+        }
+        replaced
+    }
+
+    // The main theme's chord sound, when it has one.
+    pub fn chord(&self) -> Option<Pick> {
+        Some(Pick {
+            clip: self.main().chord.clone()?,
+            gain: self.main().gain.value,
+            retired: Arc::clone(&self.retired[0]),
+        })
+    }
+
+    // The main theme's combo sound, when it has one.
+    pub fn combo(&self, config: &Config) -> Option<Pick> {
+        Some(Pick {
+            clip: self.main().combo.clone()?,
+            gain: config.combo.volume * self.main().gain.value,
+            retired: Arc::clone(&self.retired[0]),
+        })
+    }
+
+    // The samples for one event, one per layer that has a sound for it. A key
+    // sound from the config replaces them all.
+    pub fn picks(
+        &self,
+        config: &Config,
+        event: &KeyEvent,
+        velocity: Option<Velocity>,
+        held: Option<Held>,
+    ) -> Vec<Pick> {
+        if let Some(clip) = overrides::get(&self.key_sounds, event) {
+            return vec![Pick {
+                clip,
+                gain: 1.0,
+                retired: Arc::clone(&self.key_sounds_retired),
+            }];
+        }
+        let gains = std::iter::once(1.0).chain(config.layers.iter().map(|layer| layer.gain));
+        self.themes
+            .iter()
+            .zip(&self.retired)
+            .zip(gains)
+            .filter_map(|((app, retired), gain)| {
+                let clip = app.sound(event, velocity, held)?;
+                Some(Pick {
+                    clip,
+                    gain: gain * app.gain.value,
+                    retired: Arc::clone(retired),
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Layer, profile::Profile, KeyPressType, SoundType};
+    use std::{fs, path::PathBuf, time::Instant};
+
+    // Theme directories for one test, removed again when it ends.
+    struct Themes(PathBuf);
+
+    impl Themes {
+        fn new(test: &str) -> Self {
+            Self(std::env::temp_dir().join(format!("keydio-test-{}-{}", std::process::id(), test)))
+        }
+
+        // A theme whose only sample is `contents`. Its absolute path works as
+        // a theme name.
+        fn theme(&self, name: &str, contents: &str) -> String {
+            let dir = self.0.join(name);
+            fs::create_dir_all(dir.join("press")).unwrap();
+            fs::write(dir.join("press").join("GENERIC_R0.mp3"), contents).unwrap();
+            dir.to_string_lossy().into_owned()
+        }
+    }
+
+    impl Drop for Themes {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn layered(main: &str, layer: &str) -> Config {
+        Config {
+            theme: main.to_string(),
+            layers: vec![Layer {
+                theme: layer.to_string(),
+                gain: 0.5,
+            }],
+            ..Config::default()
+        }
+    }
+
+    fn press() -> KeyEvent {
+        KeyEvent {
+            key: None,
+            keypress: KeyPressType::Press,
+            sound_type: SoundType::Generic,
+            at: Instant::now(),
+        }
+    }
+
+    #[test]
+    fn switching_never_mixes_old_and_new_samples() {
+        let themes = Themes::new("switching_never_mixes_old_and_new_samples");
+        let old = layered(
+            &themes.theme("old", "old main"),
+            &themes.theme("old-layer", "old layer"),
+        );
+        let new = layered(
+            &themes.theme("new", "new main"),
+            &themes.theme("new-layer", "new layer"),
+        );
+        let state = SharedState::new(old.clone(), Profile::default());
+        let mut set = ThemeSet::new(Vec::new(), &Config::default());
+        assert!(set.sync(&state, &old));
+
+        let mut switched = false;
+        for step in 0..20 {
+            // Switch halfway, between two events, the way the audio thread does.
+            let config = if step < 10 { &old } else { &new };
+            if set.sync(&state, config) {
+                assert_eq!(step, 10);
+                switched = true;
+            }
+            let picks = set.picks(config, &press(), None, None);
+            let played: Vec<String> = picks
+                .iter()
+                .map(|pick| String::from_utf8(pick.clip.bytes.as_ref().to_vec()).unwrap())
+                .collect();
+            let expected = if step < 10 {
+                ["old main", "old layer"]
+            } else {
+                ["new main", "new layer"]
+            };
+            assert_eq!(played, expected, "event {} mixed themes", step);
+            assert!(picks
+                .iter()
+                .all(|pick| !pick.retired.load(Ordering::Relaxed)));
+        }
+        assert!(switched);
+    }
+
+    #[test]
+    fn switching_a_layer_retires_just_that_layer() {
+        let themes = Themes::new("switching_a_layer_retires_just_that_layer");
+        let first = layered(
+            &themes.theme("first", "first"),
+            &themes.theme("first-layer", "first layer"),
+        );
+        let second = layered(&first.theme, &themes.theme("second-layer", "second layer"));
+        let state = SharedState::new(first.clone(), Profile::default());
+        let mut set = ThemeSet::new(Vec::new(), &Config::default());
+        set.sync(&state, &first);
+        let before = set.picks(&first, &press(), None, None);
+        assert!(!set.sync(&state, &first));
+        assert!(!before[0].retired.load(Ordering::Relaxed));
+
+        assert!(set.sync(&state, &second));
+        // The main theme was kept and plays on, only the layer was replaced.
+        assert!(!before[0].retired.load(Ordering::Relaxed));
+        assert!(before[1].retired.load(Ordering::Relaxed));
+        assert_eq!(set.main().theme, first.theme);
+        let after = set.picks(&second, &press(), None, None);
+        assert!(Arc::ptr_eq(&before[0].retired, &after[0].retired));
+    }
+
+    #[test]
+    fn toggling_auto_gain_applies_without_a_theme_change() {
+        let themes = Themes::new("toggling_auto_gain_applies_without_a_theme_change");
+        let config = layered(
+            &themes.theme("gain", "gain"),
+            &themes.theme("gain-layer", "layer"),
+        );
+        let state = SharedState::new(config.clone(), Profile::default());
+        let mut set = ThemeSet::new(Vec::new(), &Config::default());
+        set.sync(&state, &config);
+        set.themes[0].gain.value = 0.25;
+        let off = Config {
+            auto_gain: false,
+            ..config
+        };
+        assert!(set.sync(&state, &off));
+        assert_eq!(set.main().gain.value, 1.0);
+        assert!(!set.sync(&state, &off));
+    }
+
+    #[test]
+    fn a_set_starts_in_sync_with_the_config_it_was_loaded_with() {
+        let themes = Themes::new("a_set_starts_in_sync");
+        let mut config = Config {
+            theme: themes.theme("engine", "engine"),
+            ..Config::default()
+        };
+        config.engine.channels = 1;
+        let state = SharedState::new(config.clone(), Profile::default());
+        let mut app = AppState::new(&config.theme);
+        app.load(&config).unwrap();
+        let mut set = ThemeSet::new(vec![app], &config);
+        assert!(!set.sync(&state, &config));
+    }
+}
@@ -0,0 +1,220 @@
+use crate::{available_themes, config::Config, state::SharedState};
+use anyhow::Result;
+use std::{sync::Arc, time::Duration};
+use tray_icon::{
+    menu::{CheckMenuItem, Menu, MenuEvent, MenuItem, PredefinedMenuItem, Submenu},
+    Icon, TrayIcon, TrayIconBuilder,
+};
+
+const VOLUME_PRESETS: [u8; 4] = [25, 50, 75, 100];
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+const ICON_SIZE: u32 = 32;
+
+struct Tray {
+    icon: TrayIcon,
+    mute: MenuItem,
+    themes: Vec<(String, CheckMenuItem)>,
+    volumes: Vec<(u8, CheckMenuItem)>,
+    #[cfg(feature = "gui")]
+    settings: MenuItem,
+    quit: MenuItem,
+    shown: Option<(Config, bool)>,
+}
+
+impl Tray {
+    fn new() -> Result<Self> {
+        let menu = Menu::new();
+        let mute = MenuItem::new("Mute", true, None);
+        let theme_menu = Submenu::new("Theme", true);
+        let themes: Vec<_> = available_themes()
+            .into_iter()
+            .map(|theme| {
+                let item = CheckMenuItem::new(&theme, true, false, None);
+                (theme, item)
+            })
+            .collect();
+        for (_, item) in &themes {
+            theme_menu.append(item)?;
+        }
+        let volume_menu = Submenu::new("Volume", true);
+        let volumes: Vec<_> = VOLUME_PRESETS
+            .iter()
+            .map(|percent| {
+                let item = CheckMenuItem::new(format!("{}%", percent), true, false, None);
+                (*percent, item)
+            })
+            .collect();
+        for (_, item) in &volumes {
+            volume_menu.append(item)?;
+        }
+        let quit = MenuItem::new("Quit", true, None);
+        menu.append(&mute)?;
+        menu.append(&theme_menu)?;
+        menu.append(&volume_menu)?;
+        #[cfg(feature = "gui")]
+        let settings = MenuItem::new("Settings...", true, None);
+        #[cfg(feature = "gui")]
+        menu.append(&settings)?;
+        menu.append(&PredefinedMenuItem::separator())?;
+        menu.append(&quit)?;
+
+        let icon = TrayIconBuilder::new()
+            .with_menu(Box::new(menu))
+            .with_icon(draw_icon(false)?)
+            .with_tooltip("keydio")
+            .build()?;
+
+        Ok(Self {
+            icon,
+            mute,
+            themes,
+            volumes,
+            #[cfg(feature = "gui")]
+            settings,
+            quit,
+            shown: None,
+        })
+    }
+
+    // Returns true when the user asked to quit.
+    fn handle(&mut self, event: &MenuEvent, state: &SharedState) -> bool {
+        if event.id == *self.quit.id() {
+            return true;
+        }
+        if event.id == *self.mute.id() {
+            state.toggle_mute();
+        }
+        #[cfg(feature = "gui")]
+        if event.id == *self.settings.id() {
+            // The window runs as its own process so closing it never touches playback.
+            let spawned = std::env::current_exe()
+                .and_then(|exe| std::process::Command::new(exe).arg("settings").spawn());
+            if let Err(err) = spawned {
+                eprintln!("Failed to open settings window: {:?}", err);
+            }
+        }
+        if let Some((theme, _)) = self.themes.iter().find(|(_, item)| event.id == *item.id()) {
+            state.set_theme(theme);
+        }
+        if let Some((percent, _)) = self.volumes.iter().find(|(_, item)| event.id == *item.id()) {
+            state.set_volume(f32::from(*percent) / 100.0);
+        }
+        // Check items toggle themselves when clicked, so always resync afterwards.
+        self.shown = None;
+        false
+    }
+
+    fn sync(&mut self, state: &SharedState) {
+        let shown = (state.config(), state.is_muted());
+        if self.shown.as_ref() == Some(&shown) {
+            return;
+        }
+        let (config, muted) = &shown;
+        self.mute.set_text(if *muted { "Unmute" } else { "Mute" });
+        for (theme, item) in &self.themes {
+            item.set_checked(*theme == config.theme);
+        }
+        let percent = (config.volume * 100.0).round() as u8;
+        for (preset, item) in &self.volumes {
+            item.set_checked(*preset == percent);
+        }
+        let tooltip = format!(
+            "keydio - {}, {}%{}",
+            config.theme,
+            percent,
+            if *muted { " (muted)" } else { "" }
+        );
+        if let Err(err) = self.icon.set_tooltip(Some(tooltip)) {
+            eprintln!("Failed to update tray tooltip: {:?}", err);
+        }
+        match draw_icon(*muted) {
+            Ok(icon) => {
+                if let Err(err) = self.icon.set_icon(Some(icon)) {
+                    eprintln!("Failed to update tray icon: {:?}", err);
+                }
+            }
+            Err(err) => eprintln!("Failed to draw tray icon: {:?}", err),
+        }
+        self.shown = Some(shown);
+    }
+}
+
+// A plain rounded keycap, greyed out while muted.
+fn draw_icon(muted: bool) -> Result<Icon> {
+    let color: [u8; 3] = if muted {
+        [0x80, 0x80, 0x80]
+    } else {
+        [0xc8, 0x6b, 0x2a]
+    };
+    let radius = 6;
+    let max = ICON_SIZE as i32 - 1;
+    let mut rgba = Vec::with_capacity((ICON_SIZE * ICON_SIZE * 4) as usize);
+    for y in 0..=max {
+        for x in 0..=max {
+            let dx = (radius - x).max(x - (max - radius)).max(0);
+            let dy = (radius - y).max(y - (max - radius)).max(0);
+            let alpha = if dx * dx + dy * dy <= radius * radius {
+                0xff
+            } else {
+                0x00
+            };
+            rgba.extend_from_slice(&[color[0], color[1], color[2], alpha]);
+        }
+    }
+    Ok(Icon::from_rgba(rgba, ICON_SIZE, ICON_SIZE)?)
+}
+
+// Blocks the calling thread running the tray event loop. Returns Ok(()) when
+// Quit is chosen, or an error if the tray could not be created at all.
+#[cfg(target_os = "linux")]
+pub fn run(state: Arc<SharedState>) -> Result<()> {
+    gtk::init()?;
+    let mut tray = Tray::new()?;
+    loop {
+        while gtk::events_pending() {
+            gtk::main_iteration_do(false);
+        }
+        while let Ok(event) = MenuEvent::receiver().try_recv() {
+            if tray.handle(&event, &state) {
+                return Ok(());
+            }
+        }
+        tray.sync(&state);
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn run(state: Arc<SharedState>) -> Result<()> {
+    use std::time::Instant;
+    use tao::{
+        event::{Event, StartCause},
+        event_loop::{ControlFlow, EventLoopBuilder},
+    };
+
+    let event_loop = EventLoopBuilder::new().build();
+    let mut tray = None;
+    event_loop.run(move |event, _, control_flow| {
+        *control_flow = ControlFlow::WaitUntil(Instant::now() + POLL_INTERVAL);
+        // The tray has to be created once the platform event loop is running.
+        if let Event::NewEvents(StartCause::Init) = event {
+            match Tray::new() {
+                Ok(created) => tray = Some(created),
+                Err(err) => eprintln!("Tray icon unavailable, running headless: {:?}", err),
+            }
+        }
+        let Some(tray) = tray.as_mut() else {
+            return;
+        };
+        while let Ok(event) = MenuEvent::receiver().try_recv() {
+            if tray.handle(&event, &state) {
+                // tao exits the process once the loop ends, so the caller
+                // never gets to shut down.
+                state.shutdown();
+                *control_flow = ControlFlow::Exit;
+                return;
+            }
+        }
+        tray.sync(&state);
+    })
+}
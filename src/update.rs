@@ -0,0 +1,224 @@
+use crate::{archive, install, sha256, version};
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::{env, fs, path::Path};
+
+const RELEASES: &str = "https://api.github.com/repos/BipulLamsal/keydio/releases/latest";
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+#[derive(Debug, Deserialize)]
+struct Asset {
+    name: String,
+    browser_download_url: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Release {
+    tag_name: String,
+    #[serde(default)]
+    body: String,
+    assets: Vec<Asset>,
+}
+
+// The names release assets use for this platform.
+fn os_names() -> &'static [&'static str] {
+    match env::consts::OS {
+        "linux" => &["linux"],
+        "macos" => &["macos", "darwin", "apple"],
+        "windows" => &["windows", "win64"],
+        _ => &[],
+    }
+}
+
+fn arch_names() -> &'static [&'static str] {
+    match env::consts::ARCH {
+        "x86_64" => &["x86_64", "amd64", "x64"],
+        "aarch64" => &["aarch64", "arm64"],
+        _ => &[],
+    }
+}
+
+fn is_checksum(name: &str) -> bool {
+    name.ends_with(".sha256") || name.to_lowercase().contains("sha256sums")
+}
+
+// The release asset built for this OS and architecture.
+fn asset(release: &Release) -> Result<&Asset> {
+    release
+        .assets
+        .iter()
+        .filter(|asset| !is_checksum(&asset.name))
+        .find(|asset| {
+            let name = asset.name.to_lowercase();
+            os_names().iter().any(|os| name.contains(os))
+                && arch_names().iter().any(|arch| name.contains(arch))
+        })
+        .with_context(|| {
+            format!(
+                "Release {} has no build for {} {}",
+                release.tag_name,
+                env::consts::OS,
+                env::consts::ARCH
+            )
+        })
+}
+
+// The first SHA-256 on a line that names `file`, or on its own when the
+// text is a bare `.sha256` file.
+fn find_checksum(text: &str, file: Option<&str>) -> Option<String> {
+    text.lines()
+        .filter(|line| file.is_none_or(|file| line.contains(file)))
+        .flat_map(|line| line.split(|c: char| !c.is_ascii_hexdigit()))
+        .find(|word| word.len() == 64)
+        .map(str::to_ascii_lowercase)
+}
+
+// From `<asset>.sha256`, a `SHA256SUMS` asset or the release notes, in that
+// order. An update without a checksum is refused.
+fn checksum(release: &Release, asset: &Asset) -> Result<String> {
+    let adjacent = format!("{}.sha256", asset.name);
+    if let Some(file) = release.assets.iter().find(|file| file.name == adjacent) {
+        let text = install::download(&file.browser_download_url)?;
+        if let Some(hex) = find_checksum(&String::from_utf8_lossy(&text), None) {
+            return Ok(hex);
+        }
+    }
+    for file in release.assets.iter().filter(|file| is_checksum(&file.name)) {
+        let text = install::download(&file.browser_download_url)?;
+        if let Some(hex) = find_checksum(&String::from_utf8_lossy(&text), Some(&asset.name)) {
+            return Ok(hex);
+        }
+    }
+    find_checksum(&release.body, Some(&asset.name)).with_context(|| {
+        format!(
+            "Release {} publishes no checksum for {}; not updating",
+            release.tag_name, asset.name
+        )
+    })
+}
+
+fn binary_name() -> String {
+    format!("keydio{}", env::consts::EXE_SUFFIX)
+}
+
+fn executable(name: &str, data: &[u8]) -> Result<Vec<u8>> {
+    let entries = if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+        archive::untar(data)?
+    } else if name.ends_with(".zip") {
+        archive::unzip(data)?
+    } else {
+        return Ok(data.to_vec());
+    };
+    // Release archives hold the executable next to the themes.
+    entries
+        .into_iter()
+        .find(|(entry, _)| entry.rsplit('/').next() == Some(&binary_name()))
+        .map(|(_, contents)| contents)
+        .with_context(|| format!("The release archive holds no {}", binary_name()))
+}
+
+#[cfg(not(windows))]
+fn swap(exe: &Path, staged: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(staged, fs::Permissions::from_mode(0o755))?;
+    fs::rename(staged, exe).with_context(|| format!("Failed to replace {}", exe.display()))
+}
+
+// Windows cannot replace a running executable, but it can rename it out of
+// the way; the old one is removed by the next update.
+#[cfg(windows)]
+fn swap(exe: &Path, staged: &Path) -> Result<()> {
+    let old = exe.with_extension("exe.old");
+    let _ = fs::remove_file(&old);
+    fs::rename(exe, &old).context("Failed to move the running keydio aside")?;
+    if let Err(err) = fs::rename(staged, exe) {
+        let _ = fs::rename(&old, exe);
+        return Err(err).context("Failed to put the new keydio in place");
+    }
+    Ok(())
+}
+
+// Writes the new executable beside the running one, so the rename over it
+// stays on one file system and either fully happens or not at all.
+fn replace(exe: &Path, contents: &[u8]) -> Result<()> {
+    let staged = exe.with_extension("new");
+    fs::write(&staged, contents)
+        .with_context(|| format!("Failed to write {}", staged.display()))?;
+    let swapped = swap(exe, &staged);
+    if swapped.is_err() {
+        let _ = fs::remove_file(&staged);
+    }
+    swapped
+}
+
+fn latest() -> Result<Release> {
+    let data = install::download(RELEASES)
+        .context("Cannot reach GitHub to check for updates; check your connection")?;
+    serde_json::from_slice(&data).context("GitHub returned an unexpected release listing")
+}
+
+// `keydio self-update`: installs the latest release over this executable,
+// or with `check` only says whether there is one.
+pub fn run(check: bool) -> Result<()> {
+    let release = latest()?;
+    let current = version(VERSION);
+    let newest = version(&release.tag_name);
+    if newest.is_none() || newest <= current {
+        println!("keydio {} is up to date", VERSION);
+        return Ok(());
+    }
+    if check {
+        println!(
+            "keydio {} is available (this is {}); run `keydio self-update` to install it",
+            release.tag_name.trim_start_matches('v'),
+            VERSION
+        );
+        return Ok(());
+    }
+    let asset = asset(&release)?;
+    let expected = checksum(&release, asset)?;
+    let data = install::download(&asset.browser_download_url)?;
+    let actual = sha256::hex(&data);
+    if actual != expected {
+        bail!(
+            "Checksum mismatch for {}:\n  expected {}\n  actual   {}\nNot updating",
+            asset.name,
+            expected,
+            actual
+        );
+    }
+    let contents = executable(&asset.name, &data)?;
+    let exe = env::current_exe().context("Cannot tell where keydio is installed")?;
+    replace(&exe, &contents)?;
+    println!(
+        "Updated keydio {} to {} in {}",
+        VERSION,
+        release.tag_name.trim_start_matches('v'),
+        exe.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksums_are_found_next_to_the_asset_name() {
+        let hash = "ab".repeat(32);
+        let notes = format!(
+            "Fixes.\n\n{}  keydio-linux-x86_64.tar.gz\n{}  keydio-windows-x86_64.zip\n",
+            "cd".repeat(32),
+            hash
+        );
+        assert_eq!(
+            find_checksum(&notes, Some("keydio-windows-x86_64.zip")),
+            Some(hash)
+        );
+        assert_eq!(
+            find_checksum(&notes, Some("keydio-macos-arm64.tar.gz")),
+            None
+        );
+        assert!(version("v0.10.0") > version("0.9.3-rc1"));
+    }
+}
@@ -0,0 +1,58 @@
+use crate::{config::VelocityConfig, KeyEvent, KeyPressType};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Velocity {
+    Soft,
+    Hard,
+}
+
+// Interval between presses below the threshold is a hard hit, above it a soft
+// one. Inside the crossover band around the threshold the odds of a hard hit
+// fall linearly from one to zero.
+fn pick(config: &VelocityConfig, interval: Duration) -> Velocity {
+    let ms = interval.as_secs_f64() * 1000.0;
+    let half = config.crossover_ms as f64 / 2.0;
+    let (low, high) = (
+        config.threshold_ms as f64 - half,
+        config.threshold_ms as f64 + half,
+    );
+    if ms <= low {
+        Velocity::Hard
+    } else if ms >= high {
+        Velocity::Soft
+    } else if rand::thread_rng().gen_bool((high - ms) / (high - low)) {
+        Velocity::Hard
+    } else {
+        Velocity::Soft
+    }
+}
+
+// Lives on the audio thread and sees every event, muted or not, so the
+// timing follows the actual typing. Releases and holds keep the velocity of
+// the press before them.
+#[derive(Default)]
+pub struct Tracker {
+    last_press: Option<Instant>,
+    current: Option<Velocity>,
+}
+
+impl Tracker {
+    pub fn velocity(&mut self, config: &VelocityConfig, event: &KeyEvent) -> Option<Velocity> {
+        if !config.enabled {
+            return None;
+        }
+        if event.keypress == KeyPressType::Press {
+            let interval = self
+                .last_press
+                .map(|last| event.at.saturating_duration_since(last));
+            self.last_press = Some(event.at);
+            // The first press after a pause counts as soft.
+            self.current = Some(interval.map_or(Velocity::Soft, |interval| pick(config, interval)));
+        }
+        self.current
+    }
+}